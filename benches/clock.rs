@@ -0,0 +1,43 @@
+use std::{hint::black_box, time::Duration};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use gcra::{
+    clock::{Clock, CoarseClock, InstantClock},
+    GcraState, RateLimit,
+};
+
+fn clock_now(c: &mut Criterion) {
+    let mut group = c.benchmark_group("clock_now");
+
+    let instant_clock = InstantClock;
+    group.bench_function("InstantClock", |b| {
+        b.iter(|| black_box(instant_clock.now()))
+    });
+
+    let coarse_clock = CoarseClock::new(Duration::from_millis(1));
+    group.bench_function("CoarseClock", |b| b.iter(|| black_box(coarse_clock.now())));
+
+    group.finish();
+}
+
+fn check_and_modify(c: &mut Criterion) {
+    let mut group = c.benchmark_group("check_and_modify");
+    let rate_limit = RateLimit::per_sec(u32::MAX);
+
+    let instant_clock = InstantClock;
+    let mut state = GcraState::default();
+    group.bench_function("InstantClock", |b| {
+        b.iter(|| black_box(state.check_and_modify_at(&rate_limit, instant_clock.now(), 1)))
+    });
+
+    let coarse_clock = CoarseClock::new(Duration::from_millis(1));
+    let mut state = GcraState::default();
+    group.bench_function("CoarseClock", |b| {
+        b.iter(|| black_box(state.check_and_modify_at(&rate_limit, coarse_clock.now(), 1)))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, clock_now, check_and_modify);
+criterion_main!(benches);
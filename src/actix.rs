@@ -0,0 +1,553 @@
+//! Middleware rate limiting [actix-web](https://docs.rs/actix-web) apps, resources and scopes,
+//! built on the [tower](crate::tower) integration's key extraction.
+//!
+//! Like [GcraHttpLayer](crate::http::GcraHttpLayer), limited responses carry rate limit headers
+//! in the chosen [HeaderFormat], and denied requests are answered with
+//! `429 Too Many Requests` and a `Retry-After` header unless retrying can never succeed.
+//!
+//! ```rust
+//! use std::sync::Arc;
+//! use actix_web::{web, App, HttpResponse};
+//! use gcra::{actix::{GcraMiddleware, KeyBy}, RateLimit, RateLimiter};
+//!
+//! let limiter: Arc<RateLimiter<String>> = Arc::new(RateLimiter::new(1024));
+//! limiter.register_policy("login", RateLimit::new(5, std::time::Duration::from_secs(60)));
+//! let app = App::new()
+//!     .wrap(
+//!         GcraMiddleware::new(limiter, RateLimit::per_sec(10), KeyBy::PeerIp)
+//!             .with_route_policy("/login", "login"),
+//!     )
+//!     .route("/", web::get().to(HttpResponse::Ok))
+//!     .route("/login", web::post().to(HttpResponse::Ok));
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    future::{ready, Future, Ready},
+    hash::Hash,
+    pin::Pin,
+    sync::Arc,
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{HeaderMap, HeaderName},
+        StatusCode,
+    },
+    HttpResponse,
+};
+
+use crate::{
+    clock::{Clock, InstantClock},
+    headers::{HeaderFormat, RateLimitHeaders},
+    ip::{ClientIp, IpPrefix},
+    tower::{KeyExtractor, Limited, MissingKey, RequestCost, UnitCost},
+    DashMapStore, Decision, GcraStore, NoopObserver, Observer, RateLimit, RateLimiter,
+    RateLimiterError,
+};
+
+/// Built-in ways of keying requests.
+///
+/// Requests without a key are answered with `400 Bad Request`, unless [MissingKey::Allow] is
+/// set with [GcraMiddleware::with_missing_key].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyBy {
+    /// The peer's IP address from the connection info.
+    PeerIp,
+    /// The value of a header, e.g. an API key.
+    Header(HeaderName),
+    /// The value of a path parameter, e.g. `id` of `/users/{id}`. Path parameters are only known
+    /// once the request is routed, so the middleware must wrap the resource or scope declaring
+    /// them rather than the app.
+    PathParam(String),
+    /// The method and route pattern of the request, shared by all clients, so `/users/{id}` is
+    /// limited as a whole rather than per user.
+    Route,
+}
+
+impl KeyExtractor<ServiceRequest> for KeyBy {
+    type Key = Option<String>;
+
+    fn extract(&self, request: &ServiceRequest) -> Option<String> {
+        match self {
+            KeyBy::PeerIp => request.peer_addr().map(|addr| addr.ip().to_string()),
+            KeyBy::Header(name) => request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            KeyBy::PathParam(name) => request.match_info().get(name).map(str::to_string),
+            KeyBy::Route => {
+                let route = request
+                    .match_pattern()
+                    .unwrap_or_else(|| request.path().to_string());
+                Some(format!("{} {route}", request.method()))
+            }
+        }
+    }
+}
+
+/// Keys requests by the [IpPrefix] of the client, from the peer address and the headers.
+impl KeyExtractor<ServiceRequest> for ClientIp {
+    type Key = Option<IpPrefix>;
+
+    fn extract(&self, request: &ServiceRequest) -> Option<IpPrefix> {
+        let peer = request.peer_addr()?;
+        let headers = request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)));
+        Some(self.key(peer.ip(), headers))
+    }
+}
+
+fn insert_headers(headers: &mut HeaderMap, decision: &Decision, format: HeaderFormat) {
+    for (name, value) in RateLimitHeaders::from(decision).validated_headers(format) {
+        headers.insert(name, value);
+    }
+}
+
+/// The limit of requests matching a route, along with how their keys are told apart from the
+/// same client's keys on other routes.
+struct RouteLimit<Key: Eq + Hash, C, St, O> {
+    limited: Limited<Key, C, St, O>,
+    scope: fn(&str, Key) -> Key,
+}
+
+impl<Key, C, St, O> Clone for RouteLimit<Key, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    fn clone(&self) -> Self {
+        Self {
+            limited: self.limited.clone(),
+            scope: self.scope,
+        }
+    }
+}
+
+/// Rate limits requests, wrapped around an app, resource or scope.
+pub struct GcraMiddleware<
+    KF,
+    CF = UnitCost,
+    Key: Eq + Hash = String,
+    C = InstantClock,
+    St = DashMapStore<Key>,
+    O = NoopObserver,
+> {
+    limited: Limited<Key, C, St, O>,
+    routes: HashMap<String, RouteLimit<Key, C, St, O>>,
+    key: KF,
+    cost: CF,
+    format: HeaderFormat,
+    missing_key: MissingKey,
+}
+
+impl<KF, CF, Key, C, St, O> Clone for GcraMiddleware<KF, CF, Key, C, St, O>
+where
+    KF: Clone,
+    CF: Clone,
+    Key: Eq + Hash,
+{
+    fn clone(&self) -> Self {
+        Self {
+            limited: self.limited.clone(),
+            routes: self.routes.clone(),
+            key: self.key.clone(),
+            cost: self.cost.clone(),
+            format: self.format,
+            missing_key: self.missing_key,
+        }
+    }
+}
+
+impl<KF, Key, C, St, O> GcraMiddleware<KF, UnitCost, Key, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    /// Limits requests by the key extracted by `key`, e.g. a [KeyBy], charging each a cost of 1.
+    pub fn new(limiter: Arc<RateLimiter<Key, C, St, O>>, rate_limit: RateLimit, key: KF) -> Self {
+        Self::with_limited(Limited::rate_limit(limiter, rate_limit), key)
+    }
+
+    /// Limits requests by the key extracted by `key` against the registered `policy`, charging
+    /// each a cost of 1. Requests fail with `500 Internal Server Error` while the policy isn't
+    /// registered.
+    pub fn with_policy(
+        limiter: Arc<RateLimiter<Key, C, St, O>>,
+        policy: impl Into<String>,
+        key: KF,
+    ) -> Self {
+        Self::with_limited(Limited::policy(limiter, policy.into()), key)
+    }
+
+    fn with_limited(limited: Limited<Key, C, St, O>, key: KF) -> Self {
+        Self {
+            limited,
+            routes: HashMap::new(),
+            key,
+            cost: UnitCost,
+            format: HeaderFormat::default(),
+            missing_key: MissingKey::default(),
+        }
+    }
+}
+
+impl<KF, CF, Key, C, St, O> GcraMiddleware<KF, CF, Key, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    /// Charges requests the cost returned by `cost`.
+    pub fn with_cost<CF2>(self, cost: CF2) -> GcraMiddleware<KF, CF2, Key, C, St, O> {
+        GcraMiddleware {
+            limited: self.limited,
+            routes: self.routes,
+            key: self.key,
+            cost,
+            format: self.format,
+            missing_key: self.missing_key,
+        }
+    }
+
+    /// Sets which rate limit headers responses carry, the draft's `RateLimit-*` by default.
+    pub fn with_header_format(mut self, format: HeaderFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets what happens to requests without a key, rejected with `400 Bad Request` by default.
+    pub fn with_missing_key(mut self, missing_key: MissingKey) -> Self {
+        self.missing_key = missing_key;
+        self
+    }
+}
+
+impl<KF, CF, C, St, O> GcraMiddleware<KF, CF, String, C, St, O> {
+    /// Limits requests matching the route pattern `route`, e.g. `/users/{id}`, against the
+    /// registered `policy` of the same rate limiter instead. Their keys are prefixed with the
+    /// route, so clients are limited separately on every route with a policy.
+    pub fn with_route_policy(
+        mut self,
+        route: impl Into<String>,
+        policy: impl Into<String>,
+    ) -> Self {
+        let limit = RouteLimit {
+            limited: self.limited.with_policy(policy.into()),
+            scope: |route, key| format!("{route} {key}"),
+        };
+        self.routes.insert(route.into(), limit);
+        self
+    }
+}
+
+impl<S, B, KF, CF, Key, C, St, O> Transform<S, ServiceRequest>
+    for GcraMiddleware<KF, CF, Key, C, St, O>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+    KF: KeyExtractor<ServiceRequest, Key = Option<Key>> + Clone,
+    CF: RequestCost<ServiceRequest> + Clone,
+    Key: Send + Clone + Hash + Eq + Display + 'static,
+    C: Clock,
+    St: GcraStore<Key>,
+    O: Observer<Key>,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = GcraMiddlewareService<S, KF, CF, Key, C, St, O>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(GcraMiddlewareService {
+            service,
+            middleware: self.clone(),
+        }))
+    }
+}
+
+/// Rate limits requests before passing them on to the wrapped service, see [GcraMiddleware].
+///
+/// Store errors are answered with `503 Service Unavailable`, other errors of the check with
+/// `500 Internal Server Error`.
+pub struct GcraMiddlewareService<
+    S,
+    KF,
+    CF = UnitCost,
+    Key: Eq + Hash = String,
+    C = InstantClock,
+    St = DashMapStore<Key>,
+    O = NoopObserver,
+> {
+    service: S,
+    middleware: GcraMiddleware<KF, CF, Key, C, St, O>,
+}
+
+impl<S, B, KF, CF, Key, C, St, O> Service<ServiceRequest>
+    for GcraMiddlewareService<S, KF, CF, Key, C, St, O>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+    KF: KeyExtractor<ServiceRequest, Key = Option<Key>>,
+    CF: RequestCost<ServiceRequest>,
+    Key: Send + Clone + Hash + Eq + Display + 'static,
+    C: Clock,
+    St: GcraStore<Key>,
+    O: Observer<Key>,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let middleware = &self.middleware;
+        let Some(key) = middleware.key.extract(&request) else {
+            if middleware.missing_key == MissingKey::Allow {
+                let response = self.service.call(request);
+                return Box::pin(async { Ok(response.await?.map_into_left_body()) });
+            }
+            let response = request.into_response(HttpResponse::new(StatusCode::BAD_REQUEST));
+            return Box::pin(ready(Ok(response.map_into_right_body())));
+        };
+        let cost = middleware.cost.cost(&request);
+        let (limited, key) = match request
+            .match_pattern()
+            .and_then(|route| Some((middleware.routes.get(&route)?, route)))
+        {
+            Some((limit, route)) => (&limit.limited, (limit.scope)(&route, key)),
+            None => (&middleware.limited, key),
+        };
+        let decision = match limited.decide(key, cost, limited.now()) {
+            Ok(decision) => decision,
+            Err(e) => {
+                let status = match e {
+                    RateLimiterError::Store(_) => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                let response = request.into_response(HttpResponse::new(status));
+                return Box::pin(ready(Ok(response.map_into_right_body())));
+            }
+        };
+
+        if !decision.allowed {
+            let mut response = HttpResponse::new(StatusCode::TOO_MANY_REQUESTS);
+            insert_headers(response.headers_mut(), &decision, middleware.format);
+            let response = request.into_response(response);
+            return Box::pin(ready(Ok(response.map_into_right_body())));
+        }
+        let response = self.service.call(request);
+        let format = middleware.format;
+        Box::pin(async move {
+            let mut response = response.await?;
+            insert_headers(response.headers_mut(), &decision, format);
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use actix_web::{
+        test::{call_service, init_service, TestRequest},
+        web, App,
+    };
+
+    use crate::{clock::tests::FakeClock, headers::*};
+
+    use super::*;
+
+    fn limiter() -> (Arc<RateLimiter<String, FakeClock>>, FakeClock) {
+        let clock = FakeClock::new();
+        let limiter = Arc::new(RateLimiter::with_clock(clock.clone()));
+        (limiter, clock)
+    }
+
+    fn from_ip(path: &str, ip: [u8; 4]) -> TestRequest {
+        TestRequest::get()
+            .uri(path)
+            .peer_addr(SocketAddr::from((ip, 1234)))
+    }
+
+    fn header<B>(response: &ServiceResponse<B>, name: &str) -> Option<String> {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[actix_web::test]
+    async fn limits_by_peer_ip() {
+        let (limiter, clock) = limiter();
+        let app = init_service(
+            App::new()
+                .wrap(GcraMiddleware::new(
+                    limiter,
+                    RateLimit::per_sec(2),
+                    KeyBy::PeerIp,
+                ))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let response = call_service(&app, from_ip("/", [10, 0, 0, 1]).to_request()).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(Some("2".into()), header(&response, RATELIMIT_LIMIT));
+        assert_eq!(Some("1".into()), header(&response, RATELIMIT_REMAINING));
+        assert_eq!(None, header(&response, RETRY_AFTER));
+
+        call_service(&app, from_ip("/", [10, 0, 0, 1]).to_request()).await;
+        let denied = call_service(&app, from_ip("/", [10, 0, 0, 1]).to_request()).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, denied.status());
+        assert_eq!(Some("1".into()), header(&denied, RETRY_AFTER));
+
+        let other = call_service(&app, from_ip("/", [10, 0, 0, 2]).to_request()).await;
+        assert_eq!(
+            StatusCode::OK,
+            other.status(),
+            "Peers are limited separately"
+        );
+
+        clock.advance_by(Duration::from_millis(500));
+        let response = call_service(&app, from_ip("/", [10, 0, 0, 1]).to_request()).await;
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[actix_web::test]
+    async fn limits_by_header_and_path_param() {
+        let (limiter, _clock) = limiter();
+        let app = init_service(
+            App::new()
+                .service(
+                    web::resource("/users/{id}")
+                        .wrap(GcraMiddleware::new(
+                            limiter.clone(),
+                            RateLimit::per_sec(1),
+                            KeyBy::PathParam("id".to_string()),
+                        ))
+                        .route(web::get().to(HttpResponse::Ok)),
+                )
+                .service(
+                    web::resource("/items")
+                        .wrap(GcraMiddleware::new(
+                            limiter,
+                            RateLimit::per_sec(1),
+                            KeyBy::Header(HeaderName::from_static("x-api-key")),
+                        ))
+                        .route(web::get().to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+
+        let status = |request: TestRequest| {
+            let app = &app;
+            async move { call_service(app, request.to_request()).await.status() }
+        };
+        assert_eq!(
+            StatusCode::OK,
+            status(TestRequest::get().uri("/users/1")).await
+        );
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS,
+            status(TestRequest::get().uri("/users/1")).await
+        );
+        assert_eq!(
+            StatusCode::OK,
+            status(TestRequest::get().uri("/users/2")).await
+        );
+
+        let with_key = |key| {
+            TestRequest::get()
+                .uri("/items")
+                .insert_header(("x-api-key", key))
+        };
+        assert_eq!(StatusCode::OK, status(with_key("a")).await);
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, status(with_key("a")).await);
+        assert_eq!(StatusCode::OK, status(with_key("b")).await);
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            status(TestRequest::get().uri("/items")).await,
+            "Leaving the header out must not get around the limit"
+        );
+    }
+
+    #[actix_web::test]
+    async fn allows_unkeyed_requests_when_configured() {
+        let (limiter, _clock) = limiter();
+        let app = init_service(
+            App::new()
+                .wrap(
+                    GcraMiddleware::new(
+                        limiter,
+                        RateLimit::per_sec(1),
+                        KeyBy::Header(HeaderName::from_static("x-api-key")),
+                    )
+                    .with_missing_key(MissingKey::Allow),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for _ in 0..3 {
+            let response = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+            assert_eq!(StatusCode::OK, response.status());
+            assert_eq!(
+                None,
+                header(&response, RATELIMIT_LIMIT),
+                "Allowed requests without a key aren't limited"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn applies_route_policies() {
+        let (limiter, _clock) = limiter();
+        limiter.register_policy("login", RateLimit::new(1, Duration::from_secs(60)));
+        let app = init_service(
+            App::new()
+                .wrap(
+                    GcraMiddleware::new(limiter, RateLimit::per_sec(10), KeyBy::PeerIp)
+                        .with_route_policy("/login/{realm}", "login"),
+                )
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/login/{realm}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let response = call_service(&app, from_ip("/login/a", [10, 0, 0, 1]).to_request()).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(Some("1".into()), header(&response, RATELIMIT_LIMIT));
+
+        let denied = call_service(&app, from_ip("/login/b", [10, 0, 0, 1]).to_request()).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, denied.status());
+        assert_eq!(Some("60".into()), header(&denied, RETRY_AFTER));
+
+        let response = call_service(&app, from_ip("/", [10, 0, 0, 1]).to_request()).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(Some("10".into()), header(&response, RATELIMIT_LIMIT));
+    }
+
+    #[actix_web::test]
+    async fn answers_unknown_policy_with_server_error() {
+        let (limiter, _clock) = limiter();
+        let app = init_service(
+            App::new()
+                .wrap(GcraMiddleware::with_policy(
+                    limiter,
+                    "missing",
+                    KeyBy::Route,
+                ))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let response = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+    }
+}
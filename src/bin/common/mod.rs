@@ -0,0 +1,33 @@
+//! Helpers shared by the binaries.
+
+use std::future::pending;
+
+/// Resolves once the process is asked to stop, with Ctrl-C or SIGTERM (e.g. `docker stop` or
+/// Kubernetes).
+pub async fn shutdown_signal() {
+    // A signal that can't be listened for never arrives, the other one may still stop us
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    eprintln!("Shutting down");
+}
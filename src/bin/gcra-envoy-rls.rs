@@ -0,0 +1,133 @@
+//! Serves Envoy's global rate limit service over gRPC, see [gcra::envoy].
+//!
+//! ```text
+//! gcra-envoy-rls --config <policies.toml> [--listen <addr>] [--capacity <keys>]
+//!                [--prune-interval <seconds>] [--reload-interval <seconds>]
+//! ```
+//!
+//! Policies are reloaded whenever the config file changes, while descriptor rules are only read
+//! on startup.
+
+mod common;
+
+use std::{
+    error::Error, net::SocketAddr, path::PathBuf, process::ExitCode, sync::Arc, time::Duration,
+};
+
+use gcra::{
+    config::PolicyWatcher,
+    envoy::{DescriptorRules, RateLimitService},
+    RateLimiter,
+};
+use tokio::net::TcpListener;
+
+const USAGE: &str = "Usage: gcra-envoy-rls --config <policies.toml> [--listen <addr>] \
+                     [--capacity <keys>] [--prune-interval <seconds>] [--reload-interval <seconds>]";
+
+struct Args {
+    config: PathBuf,
+    listen: SocketAddr,
+    capacity: usize,
+    prune_interval: Duration,
+    reload_interval: Duration,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut config = None;
+        let mut parsed = Args {
+            config: PathBuf::new(),
+            listen: SocketAddr::from(([127, 0, 0, 1], 8081)),
+            capacity: 1024,
+            prune_interval: Duration::from_secs(60),
+            reload_interval: Duration::from_secs(5),
+        };
+        let secs = |value: String| {
+            value
+                .parse()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| format!("Invalid interval: {value}"))
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {arg}"))
+            };
+            match arg.as_str() {
+                "--config" => config = Some(PathBuf::from(value()?)),
+                "--listen" => {
+                    let value = value()?;
+                    parsed.listen = value
+                        .parse()
+                        .map_err(|_| format!("Invalid address: {value}"))?;
+                }
+                "--capacity" => {
+                    let value = value()?;
+                    parsed.capacity = value
+                        .parse()
+                        .map_err(|_| format!("Invalid capacity: {value}"))?;
+                }
+                "--prune-interval" => parsed.prune_interval = secs(value()?)?,
+                "--reload-interval" => parsed.reload_interval = secs(value()?)?,
+                _ => return Err(format!("Unknown argument: {arg}")),
+            }
+        }
+        parsed.config = config.ok_or("Missing --config")?;
+        Ok(parsed)
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("gcra-envoy-rls: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let rules = DescriptorRules::from_path(&args.config)?;
+
+    let limiter = Arc::new(RateLimiter::new(args.capacity));
+    // Rules are only read at startup, so reloads may not drop the policies they refer to
+    let validated = rules.clone();
+    let _watcher = PolicyWatcher::spawn_validated(
+        &args.config,
+        limiter.clone(),
+        args.reload_interval,
+        move |config| validated.validate(config),
+        |reloaded| match reloaded {
+            Ok(config) => eprintln!("Reloaded {} policies", config.policies.len()),
+            Err(e) => eprintln!("Failed to reload policies, keeping the previous ones: {e}"),
+        },
+    )?;
+
+    let listener = TcpListener::bind(args.listen).await?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+
+    let pruned = limiter.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(args.prune_interval);
+        loop {
+            interval.tick().await;
+            pruned.prune_expired();
+        }
+    });
+
+    RateLimitService::new(limiter, rules)
+        .serve(listener, common::shutdown_signal())
+        .await?;
+    Ok(())
+}
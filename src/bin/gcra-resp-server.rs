@@ -0,0 +1,100 @@
+//! Serves redis-cell's `CL.THROTTLE` over the Redis protocol, see [gcra::resp].
+//!
+//! ```text
+//! gcra-resp-server [--listen <addr>] [--capacity <keys>] [--prune-interval <seconds>]
+//! ```
+
+mod common;
+
+use std::{error::Error, net::SocketAddr, process::ExitCode, sync::Arc, time::Duration};
+
+use gcra::{resp::RespServer, RateLimiter};
+use tokio::net::TcpListener;
+
+const USAGE: &str =
+    "Usage: gcra-resp-server [--listen <addr>] [--capacity <keys>] [--prune-interval <seconds>]";
+
+struct Args {
+    listen: SocketAddr,
+    capacity: usize,
+    prune_interval: Duration,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Args {
+            listen: SocketAddr::from(([127, 0, 0, 1], 6379)),
+            capacity: 1024,
+            prune_interval: Duration::from_secs(60),
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {arg}"))
+            };
+            match arg.as_str() {
+                "--listen" => {
+                    let value = value()?;
+                    parsed.listen = value
+                        .parse()
+                        .map_err(|_| format!("Invalid address: {value}"))?;
+                }
+                "--capacity" => {
+                    let value = value()?;
+                    parsed.capacity = value
+                        .parse()
+                        .map_err(|_| format!("Invalid capacity: {value}"))?;
+                }
+                "--prune-interval" => {
+                    let value = value()?;
+                    let secs = value
+                        .parse()
+                        .ok()
+                        .filter(|&secs| secs > 0)
+                        .ok_or_else(|| format!("Invalid prune interval: {value}"))?;
+                    parsed.prune_interval = Duration::from_secs(secs);
+                }
+                _ => return Err(format!("Unknown argument: {arg}")),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("gcra-resp-server: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let limiter = Arc::new(RateLimiter::new(args.capacity));
+    let listener = TcpListener::bind(args.listen).await?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+
+    let pruned = limiter.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(args.prune_interval);
+        loop {
+            interval.tick().await;
+            pruned.prune_expired();
+        }
+    });
+
+    RespServer::new(limiter)
+        .serve(listener, common::shutdown_signal())
+        .await?;
+    Ok(())
+}
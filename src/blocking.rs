@@ -0,0 +1,189 @@
+//! Limiting the bandwidth of blocking [Read]ers and [Write]rs with a [RateLimitGuard], with
+//! every byte costing 1.
+//!
+//! ```rust
+//! use std::io::Write;
+//! use gcra::{blocking::ThrottledWriter, RateLimit};
+//!
+//! // 64 KiB/s
+//! let mut backup = ThrottledWriter::new(Vec::new(), RateLimit::per_sec(64 * 1024));
+//! backup.write_all(&[0; 1024]).unwrap();
+//! assert_eq!(1024, backup.get_ref().len());
+//! ```
+//!
+//! While over the limit, the calling thread sleeps on the guard's [SleepClock] until the next
+//! chunk is allowed. As with the async wrappers of the `io` feature, bytes are charged up front in
+//! chunks of at most the burst, and charged bytes a call didn't move are kept for the next one.
+//! They're given back once the reader reaches its end, or when the reader or writer is dropped.
+
+use std::io::{self, Read, Write};
+
+use crate::{
+    clock::{InstantClock, SleepClock},
+    pacer::{Allowance, Pacer},
+    RateLimit, RateLimitGuard,
+};
+
+impl<C: SleepClock> Pacer<RateLimitGuard<C>> {
+    /// How many of the `wanted` bytes may be moved, sleeping on the guard's clock while over the
+    /// limit.
+    fn wait_allowance(&mut self, wanted: usize) -> io::Result<usize> {
+        loop {
+            match self.allowance(wanted)? {
+                Allowance::Allowed(allowed) => return Ok(allowed),
+                Allowance::DeniedUntil(next_allowed_at) => {
+                    self.bandwidth.clock().sleep_until(next_allowed_at)
+                }
+            }
+        }
+    }
+}
+
+/// Reader limiting the bytes read from the inner reader to its [RateLimitGuard].
+pub struct ThrottledReader<R, C: SleepClock = InstantClock> {
+    inner: R,
+    pacer: Pacer<RateLimitGuard<C>>,
+}
+
+impl<R> ThrottledReader<R> {
+    /// Limits reads from `reader` to `rate_limit` bytes.
+    pub fn new(reader: R, rate_limit: RateLimit) -> Self {
+        Self::with_guard(reader, RateLimitGuard::new_state(rate_limit))
+    }
+}
+
+impl<R, C: SleepClock> ThrottledReader<R, C> {
+    /// Limits reads from `reader` to `guard`, sleeping on its clock.
+    pub fn with_guard(reader: R, guard: RateLimitGuard<C>) -> Self {
+        Self {
+            inner: reader,
+            pacer: Pacer::new(guard),
+        }
+    }
+
+    pub fn guard(&self) -> &RateLimitGuard<C> {
+        &self.pacer.bandwidth
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read, C: SleepClock> Read for ThrottledReader<R, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let allowed = self.pacer.wait_allowance(buf.len())?;
+        let read = self.inner.read(&mut buf[..allowed])?;
+        self.pacer.consume_read(allowed, read)?;
+        Ok(read)
+    }
+}
+
+/// Writer limiting the bytes written to the inner writer to its [RateLimitGuard].
+pub struct ThrottledWriter<W, C: SleepClock = InstantClock> {
+    inner: W,
+    pacer: Pacer<RateLimitGuard<C>>,
+}
+
+impl<W> ThrottledWriter<W> {
+    /// Limits writes to `writer` to `rate_limit` bytes.
+    pub fn new(writer: W, rate_limit: RateLimit) -> Self {
+        Self::with_guard(writer, RateLimitGuard::new_state(rate_limit))
+    }
+}
+
+impl<W, C: SleepClock> ThrottledWriter<W, C> {
+    /// Limits writes to `writer` to `guard`, sleeping on its clock.
+    pub fn with_guard(writer: W, guard: RateLimitGuard<C>) -> Self {
+        Self {
+            inner: writer,
+            pacer: Pacer::new(guard),
+        }
+    }
+
+    pub fn guard(&self) -> &RateLimitGuard<C> {
+        &self.pacer.bandwidth
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write, C: SleepClock> Write for ThrottledWriter<W, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let allowed = self.pacer.wait_allowance(buf.len())?;
+        let written = self.inner.write(&buf[..allowed])?;
+        self.pacer.consume(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        clock::{tests::FakeClock, Clock},
+        GcraState,
+    };
+
+    use super::*;
+
+    /// 1000 bytes per second, with a burst of as many.
+    fn guard(clock: &FakeClock) -> RateLimitGuard<FakeClock> {
+        RateLimitGuard::new(
+            clock.clone(),
+            RateLimit::per_sec(1000),
+            GcraState::default(),
+        )
+    }
+
+    #[test]
+    fn paces_reads() {
+        let clock = FakeClock::new();
+        let start = clock.now();
+        let data: Vec<u8> = (0..5000).map(|byte| byte as u8).collect();
+        let mut reader = ThrottledReader::with_guard(data.as_slice(), guard(&clock));
+
+        let mut read = vec![0; 5000];
+        reader.read_exact(&mut read).unwrap();
+
+        assert_eq!(data, read);
+        assert_eq!(Duration::from_secs(4), clock.now() - start);
+    }
+
+    #[test]
+    fn splits_writes_into_chunks_within_the_burst() {
+        let clock = FakeClock::new();
+        let start = clock.now();
+        let mut writer = ThrottledWriter::with_guard(Vec::new(), guard(&clock));
+
+        assert_eq!(1000, writer.write(&[1; 5000]).unwrap());
+        assert_eq!(start, clock.now(), "The burst is written without sleeping");
+
+        writer.write_all(&[1; 2500]).unwrap();
+        assert_eq!(3500, writer.get_ref().len());
+        assert_eq!(Duration::from_millis(2500), clock.now() - start);
+    }
+}
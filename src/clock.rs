@@ -1,4 +1,11 @@
-use std::time::Instant;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 /// Abstraction for getting time.
 pub trait Clock {
@@ -7,9 +14,113 @@ pub trait Clock {
     }
 }
 
+/// A [Clock] the current thread can block on until it reaches a deadline.
+pub trait SleepClock: Clock {
+    fn sleep_until(&self, deadline: Instant) {
+        thread::sleep(deadline.saturating_duration_since(self.now()));
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InstantClock;
 impl Clock for InstantClock {}
+impl SleepClock for InstantClock {}
+
+/// A [Clock] reading tokio's time, so rate limits follow `tokio::time::pause` and
+/// `tokio::time::advance` in tests, and line up with the deadlines of tokio's timers.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokioClock;
+
+#[cfg(feature = "tokio")]
+impl Clock for TokioClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+}
+
+/// A cached [Clock] that is refreshed by a background thread every `resolution`.
+///
+/// Reading the time is a single atomic load, which is cheaper than [Instant::now] on hot paths
+/// doing millions of checks per second.
+///
+/// # Accuracy
+/// The returned [Instant] lags behind the real time by up to `resolution` (plus any scheduling
+/// delay of the refresh thread). For GCRA this means requests appear to arrive slightly earlier
+/// than they really did, so decisions err on the side of denying: a request may be denied for up
+/// to `resolution` longer than strictly necessary. Pick a `resolution` well below the smallest
+/// [RateLimit::emission_interval](crate::RateLimit::emission_interval) you check against,
+/// otherwise several emission intervals can leak within a single tick and bursts become coarser.
+///
+/// The refresh thread stops once all clones of the clock have been dropped.
+#[derive(Debug, Clone)]
+pub struct CoarseClock {
+    inner: Arc<CoarseClockInner>,
+}
+
+#[derive(Debug)]
+struct CoarseClockInner {
+    base: Instant,
+    resolution: Duration,
+    /// Nanoseconds elapsed since `base` as of the last refresh
+    elapsed_nanos: AtomicU64,
+}
+
+impl CoarseClock {
+    /// Starts a clock refreshed every `resolution`.
+    ///
+    /// # Panics
+    /// If `resolution` is zero.
+    pub fn new(resolution: Duration) -> Self {
+        assert!(!resolution.is_zero(), "resolution must be non-zero");
+
+        let inner = Arc::new(CoarseClockInner {
+            base: Instant::now(),
+            resolution,
+            elapsed_nanos: AtomicU64::new(0),
+        });
+
+        let weak_inner = Arc::downgrade(&inner);
+        thread::Builder::new()
+            .name("gcra-coarse-clock".to_string())
+            .spawn(move || {
+                while let Some(inner) = weak_inner.upgrade() {
+                    inner.refresh();
+                    let resolution = inner.resolution;
+                    // Don't keep the clock alive while sleeping
+                    drop(inner);
+                    thread::sleep(resolution);
+                }
+            })
+            .expect("failed to spawn coarse clock thread");
+
+        Self { inner }
+    }
+
+    /// How often the cached time is refreshed.
+    pub fn resolution(&self) -> Duration {
+        self.inner.resolution
+    }
+}
+
+impl CoarseClockInner {
+    fn refresh(&self) {
+        let elapsed_nanos = self.base.elapsed().as_nanos() as u64;
+        self.elapsed_nanos
+            .fetch_max(elapsed_nanos, Ordering::Relaxed);
+    }
+}
+
+impl Clock for CoarseClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        let elapsed_nanos = self.inner.elapsed_nanos.load(Ordering::Relaxed);
+        self.inner.base + Duration::from_nanos(elapsed_nanos)
+    }
+}
+
+impl SleepClock for CoarseClock {}
 
 #[cfg(test)]
 pub mod tests {
@@ -32,6 +143,19 @@ pub mod tests {
         }
     }
 
+    /// Sleeping advances the fake time instead of blocking.
+    impl SleepClock for FakeClock {
+        fn sleep_until(&self, deadline: Instant) {
+            self.advance_by(deadline.saturating_duration_since(self.now()));
+        }
+    }
+
+    impl Default for FakeClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
     impl FakeClock {
         pub fn new() -> Self {
             Self {
@@ -45,4 +169,27 @@ pub mod tests {
             *delta += duration;
         }
     }
+
+    #[test]
+    fn coarse_clock_advances() {
+        let clock = CoarseClock::new(Duration::from_millis(1));
+        let start = clock.now();
+        assert!(start <= Instant::now(), "coarse time never runs ahead");
+
+        thread::sleep(Duration::from_millis(50));
+        let later = clock.now();
+        assert!(later > start, "background thread should refresh the time");
+        assert!(later <= Instant::now(), "coarse time never runs ahead");
+    }
+
+    #[test]
+    fn coarse_clock_keeps_up() {
+        let clock = CoarseClock::new(Duration::from_millis(1));
+        thread::sleep(Duration::from_millis(50));
+
+        let lag = Instant::now() - clock.now();
+        // Only catches a refresh thread that stopped: a loaded machine may deschedule it for a
+        // while, so the bound is far from the resolution.
+        assert!(lag < Duration::from_secs(5), "lag {:?} is too large", lag);
+    }
 }
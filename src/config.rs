@@ -0,0 +1,391 @@
+//! Loads named [RateLimit] policies from TOML or JSON files into a [RateLimiter].
+//!
+//! Policies are written with the `<resource_limit>/<period>` grammar understood by
+//! [RateLimit]'s [FromStr](std::str::FromStr) implementation:
+//!
+//! ```toml
+//! [policies]
+//! login = "5/1m"
+//! api = "100/1s"
+//! ```
+//!
+//! or as JSON: `{ "policies": { "login": "5/1m", "api": "100/1s" } }`.
+//!
+//! What happens to the state of existing keys when a policy's rate changes is controlled by the
+//! limiter's [PolicyChange](crate::PolicyChange).
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs,
+    hash::Hash,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use thiserror::Error;
+
+use crate::{clock::Clock, GcraStore, Observer, ParseRateLimitError, RateLimit, RateLimiter};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid TOML config: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("Invalid JSON config: {0}")]
+    Json(#[from] serde_json::Error),
+    /// Only `.toml` and `.json` files are supported
+    #[error("Unsupported config format: {0:?}")]
+    UnsupportedFormat(PathBuf),
+    #[error("Invalid rate limit for policy {policy:?}: {source}")]
+    InvalidPolicy {
+        policy: String,
+        source: ParseRateLimitError,
+    },
+    /// The config refers to a policy which has not been defined
+    #[error("Unknown policy: {0:?}")]
+    UnknownPolicy(String),
+}
+
+#[derive(Deserialize)]
+struct RawPolicyConfig {
+    #[serde(default)]
+    policies: BTreeMap<String, String>,
+}
+
+/// A validated set of named [RateLimit] policies.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct PolicyConfig {
+    pub policies: BTreeMap<String, RateLimit>,
+}
+
+impl PolicyConfig {
+    pub fn from_toml_str(config: &str) -> Result<Self, ConfigError> {
+        Self::from_raw(toml::from_str(config)?)
+    }
+
+    pub fn from_json_str(config: &str) -> Result<Self, ConfigError> {
+        Self::from_raw(serde_json::from_str(config)?)
+    }
+
+    /// Loads the config from a file, using its extension to pick the format.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        Self::from_raw(read_path(path.as_ref())?)
+    }
+
+    fn from_raw(raw: RawPolicyConfig) -> Result<Self, ConfigError> {
+        let policies = raw
+            .policies
+            .into_iter()
+            .map(|(policy, rate_limit)| match rate_limit.parse() {
+                Ok(rate_limit) => Ok((policy, rate_limit)),
+                Err(source) => Err(ConfigError::InvalidPolicy { policy, source }),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { policies })
+    }
+
+    /// Atomically swaps these policies into `rate_limiter`, replacing all existing ones.
+    pub fn apply<Key, C, St, O>(&self, rate_limiter: &RateLimiter<Key, C, St, O>)
+    where
+        Key: Send + Clone + Hash + Eq + Display + 'static,
+        C: Clock,
+        St: GcraStore<Key>,
+        O: Observer<Key>,
+    {
+        rate_limiter.replace_policies(
+            self.policies
+                .iter()
+                .map(|(policy, rate_limit)| (policy.as_str(), rate_limit.clone())),
+        );
+    }
+}
+
+/// Polls a config file and reloads its policies into a [RateLimiter] whenever it is modified.
+///
+/// Invalid configs are reported to the reload callback and leave the current policies in place.
+/// Polling stops when the watcher is dropped.
+pub struct PolicyWatcher {
+    _stop: mpsc::Sender<()>,
+}
+
+impl PolicyWatcher {
+    /// Loads the config at `path` into `rate_limiter` and starts watching it for changes.
+    ///
+    /// # Errors
+    /// If the initial config could not be loaded.
+    pub fn spawn<Key, C, St, O>(
+        path: impl Into<PathBuf>,
+        rate_limiter: Arc<RateLimiter<Key, C, St, O>>,
+        poll_interval: Duration,
+    ) -> Result<Self, ConfigError>
+    where
+        Key: Send + Sync + Clone + Hash + Eq + Display + 'static,
+        C: Clock + Send + Sync + 'static,
+        St: GcraStore<Key> + Send + Sync + 'static,
+        O: Observer<Key> + Send + Sync + 'static,
+    {
+        Self::spawn_with_callback(path, rate_limiter, poll_interval, |_| {})
+    }
+
+    /// Same as [PolicyWatcher::spawn], calling `on_reload` with the outcome of every reload.
+    pub fn spawn_with_callback<Key, C, St, O, F>(
+        path: impl Into<PathBuf>,
+        rate_limiter: Arc<RateLimiter<Key, C, St, O>>,
+        poll_interval: Duration,
+        on_reload: F,
+    ) -> Result<Self, ConfigError>
+    where
+        Key: Send + Sync + Clone + Hash + Eq + Display + 'static,
+        C: Clock + Send + Sync + 'static,
+        St: GcraStore<Key> + Send + Sync + 'static,
+        O: Observer<Key> + Send + Sync + 'static,
+        F: FnMut(Result<&PolicyConfig, ConfigError>) + Send + 'static,
+    {
+        Self::spawn_validated(path, rate_limiter, poll_interval, |_| Ok(()), on_reload)
+    }
+
+    /// Same as [PolicyWatcher::spawn_with_callback], only applying configs accepted by
+    /// `validate`, e.g. because something else refers to their policies. Rejected reloads are
+    /// reported to `on_reload` like invalid ones.
+    ///
+    /// # Errors
+    /// If the initial config could not be loaded or was rejected.
+    pub fn spawn_validated<Key, C, St, O, V, F>(
+        path: impl Into<PathBuf>,
+        rate_limiter: Arc<RateLimiter<Key, C, St, O>>,
+        poll_interval: Duration,
+        validate: V,
+        mut on_reload: F,
+    ) -> Result<Self, ConfigError>
+    where
+        Key: Send + Sync + Clone + Hash + Eq + Display + 'static,
+        C: Clock + Send + Sync + 'static,
+        St: GcraStore<Key> + Send + Sync + 'static,
+        O: Observer<Key> + Send + Sync + 'static,
+        V: Fn(&PolicyConfig) -> Result<(), ConfigError> + Send + 'static,
+        F: FnMut(Result<&PolicyConfig, ConfigError>) + Send + 'static,
+    {
+        let path = path.into();
+        let load = move |path: &Path| {
+            let config = PolicyConfig::from_path(path)?;
+            validate(&config)?;
+            Ok::<_, ConfigError>(config)
+        };
+        let mut last_modified = fs::metadata(&path)?.modified()?;
+        load(&path)?.apply(&rate_limiter);
+
+        let (stop, stopped) = mpsc::channel();
+        thread::Builder::new()
+            .name("gcra-policy-watcher".to_string())
+            .spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(poll_interval)
+                {
+                    let modified = match modified_at(&path) {
+                        Ok(modified) if modified == last_modified => continue,
+                        Ok(modified) => modified,
+                        Err(e) => {
+                            on_reload(Err(e));
+                            continue;
+                        }
+                    };
+                    last_modified = modified;
+
+                    match load(&path) {
+                        Ok(config) => {
+                            config.apply(&rate_limiter);
+                            on_reload(Ok(&config));
+                        }
+                        Err(e) => on_reload(Err(e)),
+                    }
+                }
+            })?;
+
+        Ok(Self { _stop: stop })
+    }
+}
+
+/// Deserializes a TOML or JSON file, using its extension to pick the format.
+pub(crate) fn read_path<T: DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    match extension {
+        Some("toml") => Ok(toml::from_str(&fs::read_to_string(path)?)?),
+        Some("json") => Ok(serde_json::from_str(&fs::read_to_string(path)?)?),
+        _ => Err(ConfigError::UnsupportedFormat(path.to_path_buf())),
+    }
+}
+
+fn modified_at(path: &Path) -> Result<SystemTime, ConfigError> {
+    Ok(fs::metadata(path)?.modified()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::{GcraError, PolicyChange, RateLimiterError};
+
+    use super::*;
+
+    #[test]
+    fn config_from_toml() {
+        let config = PolicyConfig::from_toml_str(
+            r#"
+            [policies]
+            login = "5/1m"
+            api = "100/1s"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            BTreeMap::from([
+                ("api".to_string(), RateLimit::per_sec(100)),
+                (
+                    "login".to_string(),
+                    RateLimit::new(5, Duration::from_secs(60))
+                ),
+            ]),
+            config.policies
+        );
+    }
+
+    #[test]
+    fn config_from_json() {
+        let config =
+            PolicyConfig::from_json_str(r#"{ "policies": { "login": "5/1m", "api": "100/1s" } }"#)
+                .unwrap();
+
+        assert_eq!(Some(&RateLimit::per_sec(100)), config.policies.get("api"));
+        assert_eq!(
+            Some(&RateLimit::new(5, Duration::from_secs(60))),
+            config.policies.get("login")
+        );
+    }
+
+    #[test]
+    fn config_invalid_policy() {
+        match PolicyConfig::from_json_str(r#"{ "policies": { "login": "5 per minute" } }"#) {
+            Err(ConfigError::InvalidPolicy { policy, source }) => {
+                assert_eq!("login", policy);
+                assert_eq!(
+                    ParseRateLimitError::InvalidFormat("5 per minute".to_string()),
+                    source
+                );
+            }
+            unexpected => panic!("Config should be invalid: {:?}", unexpected),
+        }
+    }
+
+    #[test]
+    fn config_unsupported_format() {
+        assert!(matches!(
+            PolicyConfig::from_path("policies.yaml"),
+            Err(ConfigError::UnsupportedFormat(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn policy_watcher_reloads() {
+        let path =
+            std::env::temp_dir().join(format!("gcra-policy-watcher-{}.toml", std::process::id()));
+        fs::write(&path, "[policies]\nlogin = \"1/1s\"\n").unwrap();
+
+        let rate_limiter =
+            Arc::new(RateLimiter::with_shards(4, 2).with_policy_change(PolicyChange::Reset));
+        let (reloaded, reloads) = mpsc::channel();
+        let _watcher = PolicyWatcher::spawn_with_callback(
+            &path,
+            rate_limiter.clone(),
+            Duration::from_millis(10),
+            move |result| {
+                let _ = reloaded.send(result.cloned());
+            },
+        )
+        .unwrap();
+        assert_eq!(Some(RateLimit::per_sec(1)), rate_limiter.policy("login"));
+
+        let now = Instant::now();
+        assert!(rate_limiter
+            .check_policy_at("user", "login", 1, now)
+            .await
+            .is_ok());
+        assert!(matches!(
+            rate_limiter.check_policy_at("user", "login", 1, now).await,
+            Err(RateLimiterError::Gcra(GcraError::DeniedUntil { .. }))
+        ));
+
+        fs::write(&path, "[policies]\nlogin = \"2/1s\"\n").unwrap();
+        // Make sure the change is visible even with coarse filesystem timestamps
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+
+        let config = reloads
+            .recv_timeout(Duration::from_secs(5))
+            .expect("config should have been reloaded")
+            .expect("config should be valid");
+        assert_eq!(Some(&RateLimit::per_sec(2)), config.policies.get("login"));
+        assert_eq!(Some(RateLimit::per_sec(2)), rate_limiter.policy("login"));
+        assert!(
+            rate_limiter
+                .check_policy_at("user", "login", 1, now)
+                .await
+                .is_ok(),
+            "State should have been reset by the reload"
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn policy_watcher_rejects_invalid_reloads() {
+        let path = std::env::temp_dir().join(format!(
+            "gcra-policy-watcher-validated-{}.toml",
+            std::process::id()
+        ));
+        fs::write(&path, "[policies]\nlogin = \"1/1s\"\n").unwrap();
+
+        let rate_limiter = Arc::new(RateLimiter::<String>::with_shards(4, 2));
+        let (reloaded, reloads) = mpsc::channel();
+        let _watcher = PolicyWatcher::spawn_validated(
+            &path,
+            rate_limiter.clone(),
+            Duration::from_millis(10),
+            |config| {
+                if !config.policies.contains_key("login") {
+                    return Err(ConfigError::UnknownPolicy("login".to_string()));
+                }
+                Ok(())
+            },
+            move |result| {
+                let _ = reloaded.send(result.map(|_| ()).map_err(|e| e.to_string()));
+            },
+        )
+        .unwrap();
+
+        fs::write(&path, "[policies]\nsignin = \"2/1s\"\n").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+
+        assert_eq!(
+            Err("Unknown policy: \"login\"".to_string()),
+            reloads.recv_timeout(Duration::from_secs(5)).unwrap()
+        );
+        assert_eq!(Some(RateLimit::per_sec(1)), rate_limiter.policy("login"));
+        assert_eq!(None, rate_limiter.policy("signin"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+use std::{fmt::Display, str::FromStr};
+
+use thiserror::Error;
+
+/// A cost, or an amount of resources, in fixed-point with a millionth of a unit precision.
+///
+/// Whole costs are written as plain integers wherever an [IntoCost] is taken, fractional ones
+/// as a [Cost]:
+/// ```rust
+/// use gcra::{Cost, GcraState, RateLimit};
+///
+/// let rate_limit = RateLimit::per_sec(10);
+/// let mut state = GcraState::default();
+/// assert!(state.check_and_modify(&rate_limit, 2).is_ok());
+/// assert!(state.check_and_modify(&rate_limit, "0.25".parse::<Cost>().unwrap()).is_ok());
+/// ```
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cost {
+    /// Millionths of a unit
+    parts: u128,
+}
+
+impl Cost {
+    /// Number of parts in a unit.
+    pub const SCALE: u64 = 1_000_000;
+    pub const ZERO: Cost = Cost { parts: 0 };
+    pub const ONE: Cost = Cost::units(1);
+
+    /// A whole number of `units`.
+    pub const fn units(units: u64) -> Self {
+        Self {
+            parts: units as u128 * Self::SCALE as u128,
+        }
+    }
+
+    /// A cost of `parts` millionths of a unit.
+    pub const fn from_parts(parts: u128) -> Self {
+        Self { parts }
+    }
+
+    /// The nearest cost to `value`, unless it is negative, not finite or too large.
+    pub fn from_f64(value: f64) -> Option<Self> {
+        let parts = (value * Self::SCALE as f64).round();
+        if !(0.0..=Self::units(u64::MAX).parts as f64).contains(&parts) {
+            return None;
+        }
+        Some(Self::from_parts(parts as u128))
+    }
+
+    /// Millionths of a unit.
+    pub const fn parts(self) -> u128 {
+        self.parts
+    }
+
+    /// Whole units, rounded down and saturating at [u64::MAX].
+    pub fn floor(self) -> u64 {
+        u64::try_from(self.parts / u128::from(Self::SCALE)).unwrap_or(u64::MAX)
+    }
+
+    /// Whole units, rounded up and saturating at [u64::MAX].
+    pub fn ceil(self) -> u64 {
+        u64::try_from(self.parts.div_ceil(u128::from(Self::SCALE))).unwrap_or(u64::MAX)
+    }
+
+    pub fn as_f64(self) -> f64 {
+        self.parts as f64 / Self::SCALE as f64
+    }
+}
+
+impl From<u32> for Cost {
+    fn from(units: u32) -> Self {
+        Self::units(u64::from(units))
+    }
+}
+
+impl From<u64> for Cost {
+    fn from(units: u64) -> Self {
+        Self::units(units)
+    }
+}
+
+/// Formats as a decimal number without trailing zeros, e.g. `2`, `0.25` or `1.000001`.
+impl Display for Cost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scale = u128::from(Self::SCALE);
+        let (units, fraction) = (self.parts / scale, self.parts % scale);
+        if fraction == 0 {
+            return write!(f, "{}", units);
+        }
+        let fraction = format!("{:06}", fraction);
+        write!(f, "{}.{}", units, fraction.trim_end_matches('0'))
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseCostError {
+    #[error("Invalid cost {0:?}")]
+    Invalid(String),
+    /// Costs can't be more precise than a millionth of a unit
+    #[error("Cost {0:?} has more than 6 decimals")]
+    TooPrecise(String),
+}
+
+/// Parses decimal numbers such as `2`, `2.5` or `.25` exactly, up to 6 decimals.
+impl FromStr for Cost {
+    type Err = ParseCostError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseCostError::Invalid(s.to_string());
+        let is_digits = |digits: &str| digits.bytes().all(|byte| byte.is_ascii_digit());
+
+        let trimmed = s.trim();
+        let (units, fraction) = trimmed.split_once('.').unwrap_or((trimmed, ""));
+        if (units.is_empty() && fraction.is_empty()) || !is_digits(units) || !is_digits(fraction) {
+            return Err(invalid());
+        }
+        if fraction.len() > 6 {
+            return Err(ParseCostError::TooPrecise(s.to_string()));
+        }
+
+        let units = match units {
+            "" => 0,
+            units => units.parse::<u64>().map_err(|_| invalid())?,
+        };
+        let fraction = match fraction {
+            "" => 0,
+            fraction => format!("{:0<6}", fraction)
+                .parse::<u128>()
+                .map_err(|_| invalid())?,
+        };
+        Ok(Self::from_parts(Self::units(units).parts + fraction))
+    }
+}
+
+/// Anything that can be charged as a [Cost]: whole `u32` or `u64` units, or a [Cost] itself.
+///
+/// `i32` is implemented too, as integer literals fall back to it when several integer types fit.
+/// Negative `i32` costs charge nothing.
+pub trait IntoCost: Copy {
+    fn into_cost(self) -> Cost;
+}
+
+impl IntoCost for u32 {
+    #[inline]
+    fn into_cost(self) -> Cost {
+        Cost::from(self)
+    }
+}
+
+impl IntoCost for u64 {
+    #[inline]
+    fn into_cost(self) -> Cost {
+        Cost::units(self)
+    }
+}
+
+impl IntoCost for i32 {
+    #[inline]
+    fn into_cost(self) -> Cost {
+        u64::try_from(self).map_or(Cost::ZERO, Cost::units)
+    }
+}
+
+impl IntoCost for Cost {
+    #[inline]
+    fn into_cost(self) -> Cost {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cost_from_str() {
+        assert_eq!(Ok(Cost::units(2)), "2".parse());
+        assert_eq!(Ok(Cost::from_parts(2_500_000)), "2.5".parse());
+        assert_eq!(Ok(Cost::from_parts(250_000)), " .25 ".parse());
+        assert_eq!(Ok(Cost::from_parts(1)), "0.000001".parse());
+
+        assert_eq!(
+            Err(ParseCostError::TooPrecise("0.0000001".to_string())),
+            "0.0000001".parse::<Cost>()
+        );
+        for invalid in ["", ".", "-1", "1.2.3", "1e3", "abc"] {
+            assert_eq!(
+                Err(ParseCostError::Invalid(invalid.to_string())),
+                invalid.parse::<Cost>()
+            );
+        }
+    }
+
+    #[test]
+    fn cost_display_round_trips() {
+        for cost in [
+            "0",
+            "2",
+            "2.5",
+            "0.25",
+            "1.000001",
+            "18446744073709551615.999999",
+        ] {
+            assert_eq!(cost, cost.parse::<Cost>().unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn cost_conversions() {
+        assert_eq!(Some(Cost::from_parts(250_000)), Cost::from_f64(0.25));
+        assert_eq!(None, Cost::from_f64(-1.0));
+        assert_eq!(None, Cost::from_f64(f64::NAN));
+
+        let cost = Cost::from_parts(2_500_000);
+        assert_eq!((2, 3), (cost.floor(), cost.ceil()));
+        assert_eq!(2.5, cost.as_f64());
+
+        assert_eq!(Cost::units(2), 2.into_cost());
+        assert_eq!(Cost::units(u64::MAX), u64::MAX.into_cost());
+        assert_eq!(Cost::ZERO, (-1).into_cost());
+    }
+}
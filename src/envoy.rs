@@ -0,0 +1,609 @@
+//! Envoy's [global rate limit service](https://www.envoyproxy.io/docs/envoy/latest/configuration/other_features/global_rate_limiting)
+//! over gRPC, implementing `envoy.service.ratelimit.v3.RateLimitService/ShouldRateLimit` on top
+//! of a [RateLimiter].
+//!
+//! Descriptors are mapped to named policies by [DescriptorRules], which live next to the
+//! policies in the same config file:
+//!
+//! ```toml
+//! [policies]
+//! per_ip = "100/1s"
+//! login = "5/1m"
+//!
+//! [[descriptors]]
+//! domain = "edge"
+//! entries = [{ key = "remote_address" }]
+//! policy = "per_ip"
+//!
+//! [[descriptors]]
+//! domain = "edge"
+//! entries = [{ key = "remote_address" }, { key = "path", value = "/login" }]
+//! policy = "login"
+//! ```
+//!
+//! Every descriptor of a request is checked as its own key, made of the domain and the
+//! descriptor's entries, using the request's `hits_addend` as the cost. Descriptors without a
+//! matching rule are not limited. As with Envoy's reference implementation, descriptors are
+//! charged even if another descriptor of the same request is over its limit.
+
+use std::{convert::Infallible, future::Future, sync::Arc, time::Duration};
+
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tonic::{
+    codec::ProstCodec,
+    codegen::{http, Body, BoxFuture, Context, Poll, Service, StdError},
+    server::{Grpc, NamedService, UnaryService},
+    transport::{server::TcpIncoming, Server},
+    Status,
+};
+
+use crate::{
+    clock::{Clock, InstantClock},
+    config::{self, ConfigError, PolicyConfig},
+    Decision, RateLimit, RateLimiter, RateLimiterError,
+};
+
+pub mod proto;
+
+use proto::{
+    rate_limit_response::{rate_limit::Unit, Code, DescriptorStatus},
+    RateLimitDescriptor, RateLimitRequest, RateLimitResponse,
+};
+
+/// Path of the `ShouldRateLimit` method.
+pub const SHOULD_RATE_LIMIT_PATH: &str =
+    "/envoy.service.ratelimit.v3.RateLimitService/ShouldRateLimit";
+
+/// Matches a descriptor entry by key, and by value if set.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct EntryMatcher {
+    pub key: String,
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+/// Applies `policy` to descriptors of `domain` whose entries match `entries`, in order.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DescriptorRule {
+    pub domain: String,
+    pub entries: Vec<EntryMatcher>,
+    pub policy: String,
+}
+
+impl DescriptorRule {
+    fn matches(&self, domain: &str, descriptor: &RateLimitDescriptor) -> bool {
+        self.domain == domain
+            && self.entries.len() == descriptor.entries.len()
+            && self
+                .entries
+                .iter()
+                .zip(&descriptor.entries)
+                .all(|(matcher, entry)| {
+                    matcher.key == entry.key
+                        && matcher
+                            .value
+                            .as_ref()
+                            .map_or(true, |value| *value == entry.value)
+                })
+    }
+}
+
+/// Maps descriptors to policies. The first matching rule wins, so more specific rules should
+/// come first.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DescriptorRules {
+    #[serde(default)]
+    pub descriptors: Vec<DescriptorRule>,
+}
+
+impl DescriptorRules {
+    pub fn from_toml_str(config: &str) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(config)?)
+    }
+
+    pub fn from_json_str(config: &str) -> Result<Self, ConfigError> {
+        Ok(serde_json::from_str(config)?)
+    }
+
+    /// Loads the rules from a file, using its extension to pick the format.
+    pub fn from_path(path: impl AsRef<std::path::Path>) -> Result<Self, ConfigError> {
+        config::read_path(path.as_ref())
+    }
+
+    /// Makes sure every rule refers to one of `config`'s policies.
+    pub fn validate(&self, config: &PolicyConfig) -> Result<(), ConfigError> {
+        match self
+            .descriptors
+            .iter()
+            .find(|rule| !config.policies.contains_key(&rule.policy))
+        {
+            Some(rule) => Err(ConfigError::UnknownPolicy(rule.policy.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// The rule applying to `descriptor`, if any.
+    pub fn find(&self, domain: &str, descriptor: &RateLimitDescriptor) -> Option<&DescriptorRule> {
+        self.descriptors
+            .iter()
+            .find(|rule| rule.matches(domain, descriptor))
+    }
+}
+
+/// Key `descriptor` is tracked under, e.g. `edge|remote_address=10.0.0.1|path=/login`.
+///
+/// Separators within the domain, keys and values are escaped with a backslash, so distinct
+/// descriptors never share a key.
+fn descriptor_key(domain: &str, descriptor: &RateLimitDescriptor) -> String {
+    fn push_escaped(key: &mut String, part: &str) {
+        for c in part.chars() {
+            if matches!(c, '\\' | '|' | '=') {
+                key.push('\\');
+            }
+            key.push(c);
+        }
+    }
+
+    let mut key = String::new();
+    push_escaped(&mut key, domain);
+    for entry in &descriptor.entries {
+        key.push('|');
+        push_escaped(&mut key, &entry.key);
+        key.push('=');
+        push_escaped(&mut key, &entry.value);
+    }
+    key
+}
+
+/// Envoy only knows about limits per unit of time, so express the rate per the smallest unit
+/// spanning the whole period.
+fn current_limit(policy: &str, rate_limit: &RateLimit) -> proto::rate_limit_response::RateLimit {
+    const UNITS: [(Unit, u64); 4] = [
+        (Unit::Second, 1),
+        (Unit::Minute, 60),
+        (Unit::Hour, 60 * 60),
+        (Unit::Day, 24 * 60 * 60),
+    ];
+    let period = rate_limit.period.as_nanos().max(1);
+    let (unit, secs) = UNITS
+        .into_iter()
+        .find(|(_, secs)| Duration::from_secs(*secs).as_nanos() >= period)
+        .unwrap_or(UNITS[UNITS.len() - 1]);
+    let requests_per_unit =
+        u128::from(rate_limit.resource_limit_u64()) * Duration::from_secs(secs).as_nanos() / period;
+    proto::rate_limit_response::RateLimit {
+        name: policy.to_string(),
+        requests_per_unit: requests_per_unit.clamp(1, u32::MAX.into()) as u32,
+        unit: unit.into(),
+    }
+}
+
+fn descriptor_status(policy: &str, decision: &Decision) -> DescriptorStatus {
+    let code = if decision.allowed {
+        Code::Ok
+    } else {
+        Code::OverLimit
+    };
+    DescriptorStatus {
+        code: code.into(),
+        current_limit: Some(current_limit(policy, &decision.rate_limit)),
+        limit_remaining: decision.remaining.try_into().unwrap_or(u32::MAX),
+        duration_until_reset: decision.reset_after.try_into().ok(),
+    }
+}
+
+fn status(e: RateLimiterError) -> Status {
+    match e {
+        RateLimiterError::UnknownPolicy { .. } | RateLimiterError::PolicyMismatch { .. } => {
+            Status::failed_precondition(e.to_string())
+        }
+        RateLimiterError::Store(_) => Status::unavailable(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+/// Implements `ShouldRateLimit` backed by a [RateLimiter] keyed by descriptor.
+pub struct RateLimitService<C = InstantClock> {
+    limiter: Arc<RateLimiter<String, C>>,
+    rules: Arc<DescriptorRules>,
+}
+
+impl<C> Clone for RateLimitService<C> {
+    fn clone(&self) -> Self {
+        Self {
+            limiter: self.limiter.clone(),
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+impl<C> RateLimitService<C>
+where
+    C: Clock + Send + Sync + 'static,
+{
+    pub fn new(limiter: Arc<RateLimiter<String, C>>, rules: DescriptorRules) -> Self {
+        Self {
+            limiter,
+            rules: Arc::new(rules),
+        }
+    }
+
+    pub fn limiter(&self) -> &Arc<RateLimiter<String, C>> {
+        &self.limiter
+    }
+
+    pub fn rules(&self) -> &DescriptorRules {
+        &self.rules
+    }
+
+    /// Checks every descriptor of `request`, all at the same instant.
+    ///
+    /// # Errors
+    /// - [Status::failed_precondition] if a rule's policy is no longer registered, or a key is
+    ///   still tracked under another policy.
+    /// - [Status::unavailable] if the limiter's store failed.
+    pub async fn should_rate_limit(
+        &self,
+        request: RateLimitRequest,
+    ) -> Result<RateLimitResponse, Status> {
+        let now = self.limiter.clock().now();
+        let hits_addend = u64::from(request.hits_addend.max(1));
+
+        let mut statuses = Vec::with_capacity(request.descriptors.len());
+        for descriptor in &request.descriptors {
+            let Some(rule) = self.rules.find(&request.domain, descriptor) else {
+                statuses.push(DescriptorStatus {
+                    code: Code::Ok.into(),
+                    ..Default::default()
+                });
+                continue;
+            };
+            let cost = descriptor.hits_addend.unwrap_or(hits_addend);
+            let decision = self
+                .limiter
+                .decide_policy_at(
+                    descriptor_key(&request.domain, descriptor),
+                    &rule.policy,
+                    cost,
+                    now,
+                )
+                .await
+                .map_err(status)?;
+            statuses.push(descriptor_status(&rule.policy, &decision));
+        }
+
+        let over_limit = statuses
+            .iter()
+            .any(|status| status.code == i32::from(Code::OverLimit));
+        let overall_code = if over_limit {
+            Code::OverLimit
+        } else {
+            Code::Ok
+        };
+        Ok(RateLimitResponse {
+            overall_code: overall_code.into(),
+            statuses,
+        })
+    }
+
+    /// Serves gRPC requests on `listener` until `shutdown` completes, then waits for in-flight
+    /// requests to finish.
+    pub async fn serve(
+        self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), tonic::transport::Error> {
+        Server::builder()
+            .add_service(self)
+            .serve_with_incoming_shutdown(TcpIncoming::from(listener), shutdown)
+            .await
+    }
+}
+
+impl<C> NamedService for RateLimitService<C> {
+    const NAME: &'static str = "envoy.service.ratelimit.v3.RateLimitService";
+}
+
+struct ShouldRateLimit<C>(RateLimitService<C>);
+
+impl<C> UnaryService<RateLimitRequest> for ShouldRateLimit<C>
+where
+    C: Clock + Send + Sync + 'static,
+{
+    type Response = RateLimitResponse;
+    type Future = BoxFuture<tonic::Response<RateLimitResponse>, Status>;
+
+    fn call(&mut self, request: tonic::Request<RateLimitRequest>) -> Self::Future {
+        let service = self.0.clone();
+        Box::pin(async move {
+            service
+                .should_rate_limit(request.into_inner())
+                .await
+                .map(tonic::Response::new)
+        })
+    }
+}
+
+impl<C, B> Service<http::Request<B>> for RateLimitService<C>
+where
+    C: Clock + Send + Sync + 'static,
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        if request.uri().path() != SHOULD_RATE_LIMIT_PATH {
+            return Box::pin(async { Ok(Status::unimplemented("").into_http()) });
+        }
+        let method = ShouldRateLimit(self.clone());
+        Box::pin(async move {
+            let mut grpc = Grpc::new(ProstCodec::default());
+            Ok(grpc.unary(method, request).await)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{sync::oneshot, task::JoinHandle};
+    use tonic::{client::Grpc, transport::Channel, Code as StatusCode};
+
+    use crate::clock::tests::FakeClock;
+
+    use super::{proto::rate_limit_descriptor::Entry, *};
+
+    const CONFIG: &str = r#"
+        [policies]
+        per_ip = "2/1s"
+        login = "1/1m"
+
+        [[descriptors]]
+        domain = "edge"
+        entries = [{ key = "remote_address" }, { key = "path", value = "/login" }]
+        policy = "login"
+
+        [[descriptors]]
+        domain = "edge"
+        entries = [{ key = "remote_address" }]
+        policy = "per_ip"
+    "#;
+
+    struct TestServer {
+        addr: SocketAddr,
+        clock: FakeClock,
+        shutdown: oneshot::Sender<()>,
+        served: JoinHandle<Result<(), tonic::transport::Error>>,
+    }
+
+    async fn start() -> TestServer {
+        let clock = FakeClock::new();
+        let limiter = Arc::new(RateLimiter::with_clock(clock.clone()));
+        let policies = PolicyConfig::from_toml_str(CONFIG).unwrap();
+        policies.apply(&limiter);
+        let rules = DescriptorRules::from_toml_str(CONFIG).unwrap();
+        rules.validate(&policies).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, stopped) = oneshot::channel();
+        let served = tokio::spawn(
+            RateLimitService::new(limiter, rules).serve(listener, async {
+                let _ = stopped.await;
+            }),
+        );
+        TestServer {
+            addr,
+            clock,
+            shutdown,
+            served,
+        }
+    }
+
+    /// Calls `ShouldRateLimit` the way Envoy does, over its own connection.
+    async fn should_rate_limit(
+        addr: SocketAddr,
+        request: RateLimitRequest,
+    ) -> Result<RateLimitResponse, Status> {
+        let channel = Channel::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut grpc = Grpc::new(channel);
+        grpc.ready().await.unwrap();
+        grpc.unary(
+            tonic::Request::new(request),
+            http::uri::PathAndQuery::from_static(SHOULD_RATE_LIMIT_PATH),
+            ProstCodec::default(),
+        )
+        .await
+        .map(tonic::Response::into_inner)
+    }
+
+    fn descriptor(entries: &[(&str, &str)]) -> RateLimitDescriptor {
+        RateLimitDescriptor {
+            entries: entries
+                .iter()
+                .map(|(key, value)| Entry {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            hits_addend: None,
+        }
+    }
+
+    fn request(descriptors: Vec<RateLimitDescriptor>) -> RateLimitRequest {
+        RateLimitRequest {
+            domain: "edge".to_string(),
+            descriptors,
+            hits_addend: 0,
+        }
+    }
+
+    fn ip_request() -> RateLimitRequest {
+        request(vec![descriptor(&[("remote_address", "10.0.0.3")])])
+    }
+
+    fn per_ip_status(code: Code, limit_remaining: u32, reset_after: Duration) -> DescriptorStatus {
+        DescriptorStatus {
+            code: code.into(),
+            current_limit: Some(proto::rate_limit_response::RateLimit {
+                name: "per_ip".to_string(),
+                requests_per_unit: 2,
+                unit: Unit::Second.into(),
+            }),
+            limit_remaining,
+            duration_until_reset: Some(reset_after.try_into().unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_rate_limit_until_over_limit() {
+        let server = start().await;
+        let ip = || request(vec![descriptor(&[("remote_address", "10.0.0.1")])]);
+
+        let ok = should_rate_limit(server.addr, ip()).await.unwrap();
+        assert_eq!(
+            RateLimitResponse {
+                overall_code: Code::Ok.into(),
+                statuses: vec![per_ip_status(Code::Ok, 1, Duration::from_millis(500))],
+            },
+            ok
+        );
+
+        should_rate_limit(server.addr, ip()).await.unwrap();
+        let over = should_rate_limit(server.addr, ip()).await.unwrap();
+        assert_eq!(
+            RateLimitResponse {
+                overall_code: Code::OverLimit.into(),
+                statuses: vec![per_ip_status(Code::OverLimit, 0, Duration::from_secs(1))],
+            },
+            over
+        );
+
+        server.clock.advance_by(Duration::from_millis(500));
+        let ok = should_rate_limit(server.addr, ip()).await.unwrap();
+        assert_eq!(i32::from(Code::Ok), ok.overall_code);
+    }
+
+    #[tokio::test]
+    async fn should_rate_limit_descriptors() {
+        let server = start().await;
+
+        let login = descriptor(&[("remote_address", "10.0.0.1"), ("path", "/login")]);
+        let mut other_path = descriptor(&[("remote_address", "10.0.0.1"), ("path", "/")]);
+        // Descriptor costs override the request's
+        let mut ip = descriptor(&[("remote_address", "10.0.0.2")]);
+        ip.hits_addend = Some(2);
+        let mut over = request(vec![login.clone(), other_path.clone(), ip]);
+        over.hits_addend = 5;
+
+        let response = should_rate_limit(server.addr, over).await.unwrap();
+        assert_eq!(i32::from(Code::OverLimit), response.overall_code);
+        let codes: Vec<_> = response.statuses.iter().map(|s| s.code()).collect();
+        assert_eq!(vec![Code::OverLimit, Code::Ok, Code::Ok], codes);
+        assert_eq!(
+            Some(proto::rate_limit_response::RateLimit {
+                name: "login".to_string(),
+                requests_per_unit: 1,
+                unit: Unit::Minute.into(),
+            }),
+            response.statuses[0].current_limit
+        );
+        assert_eq!(None, response.statuses[1].current_limit, "Not limited");
+        assert_eq!(0, response.statuses[2].limit_remaining);
+
+        other_path.hits_addend = Some(1);
+        let response = should_rate_limit(server.addr, request(vec![login, other_path]))
+            .await
+            .unwrap();
+        assert_eq!(i32::from(Code::Ok), response.overall_code);
+        assert_eq!(
+            Some(Duration::from_secs(60).try_into().unwrap()),
+            response.statuses[0].duration_until_reset
+        );
+
+        let mut unknown_domain = ip_request();
+        unknown_domain.domain = "internal".to_string();
+        let response = should_rate_limit(server.addr, unknown_domain)
+            .await
+            .unwrap();
+        assert_eq!(i32::from(Code::Ok), response.overall_code);
+    }
+
+    #[tokio::test]
+    async fn should_rate_limit_unknown_policy() {
+        // The policy went away after the rules were validated, e.g. on reload
+        let limiter = Arc::new(RateLimiter::with_clock(FakeClock::new()));
+        limiter.register_policy("login", RateLimit::per_sec(1));
+        let service =
+            RateLimitService::new(limiter, DescriptorRules::from_toml_str(CONFIG).unwrap());
+
+        let error = service.should_rate_limit(ip_request()).await.unwrap_err();
+        assert_eq!(StatusCode::FailedPrecondition, error.code());
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        let server = start().await;
+        should_rate_limit(server.addr, ip_request()).await.unwrap();
+
+        server.shutdown.send(()).unwrap();
+        server.served.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn rules_reject_unknown_policies() {
+        let rules = DescriptorRules::from_json_str(
+            r#"{ "descriptors": [{ "domain": "edge", "entries": [{ "key": "user" }], "policy": "nope" }] }"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            rules.validate(&PolicyConfig::from_toml_str(CONFIG).unwrap()),
+            Err(ConfigError::UnknownPolicy(policy)) if policy == "nope"
+        ));
+    }
+
+    #[test]
+    fn descriptor_keys_are_unambiguous() {
+        assert_eq!(
+            "edge|remote_address=10.0.0.1|path=/login",
+            descriptor_key(
+                "edge",
+                &descriptor(&[("remote_address", "10.0.0.1"), ("path", "/login")])
+            )
+        );
+        let keys = [
+            descriptor_key("edge", &descriptor(&[("user", "x|path=/login")])),
+            descriptor_key("edge", &descriptor(&[("user", "x"), ("path", "/login")])),
+            descriptor_key("edge|user=x", &descriptor(&[("path", "/login")])),
+            descriptor_key("edge", &descriptor(&[("user=x|path", "/login")])),
+            descriptor_key("edge", &descriptor(&[("user", "x\\"), ("path", "/login")])),
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            assert!(!keys[..i].contains(key), "{key} collides");
+        }
+    }
+
+    #[test]
+    fn current_limit_units() {
+        let limit = |rate_limit: &str| {
+            let limit = current_limit("policy", &rate_limit.parse().unwrap());
+            (limit.requests_per_unit, limit.unit())
+        };
+        assert_eq!((100, Unit::Second), limit("10/100ms"));
+        assert_eq!((200, Unit::Hour), limit("5/90s"));
+        assert_eq!((1, Unit::Day), limit("1/7d"));
+    }
+}
@@ -0,0 +1,96 @@
+//! Messages of Envoy's `envoy.service.ratelimit.v3.RateLimitService`, limited to the fields used
+//! here. Field tags match the upstream protos, so they stay wire compatible with Envoy.
+//!
+//! See <https://www.envoyproxy.io/docs/envoy/latest/api-v3/service/ratelimit/v3/rls.proto>.
+
+/// `envoy.service.ratelimit.v3.RateLimitRequest`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RateLimitRequest {
+    #[prost(string, tag = "1")]
+    pub domain: String,
+    #[prost(message, repeated, tag = "2")]
+    pub descriptors: Vec<RateLimitDescriptor>,
+    /// Cost of the request for every descriptor, treated as 1 when unset.
+    #[prost(uint32, tag = "3")]
+    pub hits_addend: u32,
+}
+
+/// `envoy.extensions.common.ratelimit.v3.RateLimitDescriptor`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RateLimitDescriptor {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: Vec<rate_limit_descriptor::Entry>,
+    /// Overrides the request's `hits_addend` for this descriptor.
+    #[prost(message, optional, tag = "3")]
+    pub hits_addend: Option<u64>,
+}
+
+pub mod rate_limit_descriptor {
+    /// `envoy.extensions.common.ratelimit.v3.RateLimitDescriptor.Entry`
+    #[derive(Clone, PartialEq, Eq, Hash, prost::Message)]
+    pub struct Entry {
+        #[prost(string, tag = "1")]
+        pub key: String,
+        #[prost(string, tag = "2")]
+        pub value: String,
+    }
+}
+
+/// `envoy.service.ratelimit.v3.RateLimitResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RateLimitResponse {
+    #[prost(enumeration = "rate_limit_response::Code", tag = "1")]
+    pub overall_code: i32,
+    /// One per descriptor of the request, in the same order.
+    #[prost(message, repeated, tag = "2")]
+    pub statuses: Vec<rate_limit_response::DescriptorStatus>,
+}
+
+pub mod rate_limit_response {
+    /// `envoy.service.ratelimit.v3.RateLimitResponse.Code`
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Code {
+        Unknown = 0,
+        Ok = 1,
+        OverLimit = 2,
+    }
+
+    /// `envoy.service.ratelimit.v3.RateLimitResponse.RateLimit`
+    #[derive(Clone, PartialEq, Eq, prost::Message)]
+    pub struct RateLimit {
+        #[prost(string, tag = "3")]
+        pub name: String,
+        #[prost(uint32, tag = "1")]
+        pub requests_per_unit: u32,
+        #[prost(enumeration = "rate_limit::Unit", tag = "2")]
+        pub unit: i32,
+    }
+
+    pub mod rate_limit {
+        /// `envoy.service.ratelimit.v3.RateLimitResponse.RateLimit.Unit`
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+        #[repr(i32)]
+        pub enum Unit {
+            Unknown = 0,
+            Second = 1,
+            Minute = 2,
+            Hour = 3,
+            Day = 4,
+        }
+    }
+
+    /// `envoy.service.ratelimit.v3.RateLimitResponse.DescriptorStatus`
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DescriptorStatus {
+        #[prost(enumeration = "Code", tag = "1")]
+        pub code: i32,
+        /// Unset for descriptors without a matching policy.
+        #[prost(message, optional, tag = "2")]
+        pub current_limit: Option<RateLimit>,
+        #[prost(uint32, tag = "3")]
+        pub limit_remaining: u32,
+        #[prost(message, optional, tag = "4")]
+        pub duration_until_reset: Option<prost_types::Duration>,
+    }
+}
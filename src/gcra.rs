@@ -1,18 +1,57 @@
 use std::time::Instant;
 use thiserror::Error;
 
-use crate::rate_limit::RateLimit;
+use crate::{
+    rate_limit::{nanos_to_duration, RateLimit},
+    Cost, IntoCost,
+};
 
 #[derive(Error, Debug, PartialEq, Eq)]
 pub enum GcraError {
-    /// Cost of the increment exceeds the rate limit and  will never succeed
-    #[error("Cost of the increment ({cost}) exceeds the rate limit ({rate_limit:?}) and will never succeed)")]
-    DeniedIndefinitely { cost: u32, rate_limit: RateLimit },
+    /// Cost of the increment exceeds the rate limit and  will never succeed. `cost` is rounded up
+    /// and saturates at `u32::MAX`, `exact_cost` also holds fractional and `u64` costs.
+    #[error("Cost of the increment ({exact_cost}) exceeds the rate limit ({rate_limit:?}) and will never succeed)")]
+    DeniedIndefinitely {
+        cost: u32,
+        rate_limit: RateLimit,
+        exact_cost: Cost,
+    },
     /// Limited request until after the [Instant]
     #[error("Denied until {next_allowed_at:?}")]
     DeniedUntil { next_allowed_at: Instant },
 }
 
+impl GcraError {
+    /// [GcraError::DeniedIndefinitely] for a request costing `cost`.
+    pub(crate) fn denied_indefinitely(cost: Cost, rate_limit: RateLimit) -> Self {
+        Self::DeniedIndefinitely {
+            cost: u32::try_from(cost.ceil()).unwrap_or(u32::MAX),
+            rate_limit,
+            exact_cost: cost,
+        }
+    }
+}
+
+/// `a * b / c` rounded up, saturating at [u128::MAX].
+fn mul_div_ceil(a: u128, b: u128, c: u128) -> u128 {
+    match a.checked_mul(b) {
+        Some(product) => product.div_ceil(c),
+        None => (a / c)
+            .saturating_mul(b)
+            .saturating_add((a % c).saturating_mul(b).div_ceil(c)),
+    }
+}
+
+/// Selects what [GcraState::rescale] preserves when moving a state to a new [RateLimit].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RescaleMode {
+    /// Keep the same fraction of the burst consumed, e.g. half used stays half used.
+    #[default]
+    ConsumedFraction,
+    /// Keep the same number of resources consumed, capped at the new resource limit.
+    ConsumedUnits,
+}
+
 /// Holds the minmum amount of state necessary to implement a GRCA leaky buckets.
 /// Refer to: [understanding GCRA](https://blog.ian.stapletoncordas.co/2018/12/understanding-generic-cell-rate-limiting.html)
 #[derive(Default, Debug, Clone, PartialEq, Eq, Hash, Copy)]
@@ -27,7 +66,11 @@ impl GcraState {
     ///
     /// Simply passes the current Instant to [`check_and_modify_at()`]
     #[inline]
-    pub fn check_and_modify(&mut self, rate_limit: &RateLimit, cost: u32) -> Result<(), GcraError> {
+    pub fn check_and_modify(
+        &mut self,
+        rate_limit: &RateLimit,
+        cost: impl IntoCost,
+    ) -> Result<(), GcraError> {
         let arrived_at = Instant::now();
         self.check_and_modify_at(rate_limit, arrived_at, cost)
     }
@@ -42,16 +85,26 @@ impl GcraState {
         &mut self,
         rate_limit: &RateLimit,
         arrived_at: Instant,
-        cost: u32,
+        cost: impl IntoCost,
+    ) -> Result<(), GcraError> {
+        let cost = cost.into_cost();
+        let result = self.gcra_check_at(rate_limit, arrived_at, cost);
+        #[cfg(feature = "tracing")]
+        self.trace_check(rate_limit, arrived_at, cost, &result);
+        result
+    }
+
+    fn gcra_check_at(
+        &mut self,
+        rate_limit: &RateLimit,
+        arrived_at: Instant,
+        cost: Cost,
     ) -> Result<(), GcraError> {
         let increment_interval = rate_limit.increment_interval(cost);
 
         let compute_tat = |new_tat: Instant| {
             if increment_interval > rate_limit.period {
-                return Err(GcraError::DeniedIndefinitely {
-                    cost,
-                    rate_limit: rate_limit.clone(),
-                });
+                return Err(GcraError::denied_indefinitely(cost, rate_limit.clone()));
             }
 
             Ok(new_tat + increment_interval)
@@ -88,11 +141,42 @@ impl GcraState {
         }
     }
 
+    /// Allowed checks are logged at `TRACE` so they stay quiet by default, denials at `DEBUG`.
+    #[cfg(feature = "tracing")]
+    fn trace_check(
+        &self,
+        rate_limit: &RateLimit,
+        arrived_at: Instant,
+        cost: Cost,
+        result: &Result<(), GcraError>,
+    ) {
+        match result {
+            Ok(()) => tracing::trace!(
+                cost = %cost,
+                tat = ?self.tat,
+                remaining = self.remaining_resources(rate_limit, arrived_at),
+                "gcra allowed"
+            ),
+            Err(GcraError::DeniedUntil { next_allowed_at }) => tracing::debug!(
+                cost = %cost,
+                tat = ?self.tat,
+                next_allowed_at = ?next_allowed_at,
+                remaining = self.remaining_resources(rate_limit, arrived_at),
+                "gcra denied"
+            ),
+            Err(GcraError::DeniedIndefinitely { .. }) => tracing::debug!(
+                cost = %cost,
+                rate_limit = %rate_limit,
+                "gcra denied indefinitely"
+            ),
+        }
+    }
+
     /// Reverts rate_limit by cost, and updated our internal state.
     ///
     /// Simply passes the current Instant to [`revert_at()`]
     #[inline]
-    pub fn revert(&mut self, rate_limit: &RateLimit, cost: u32) -> Result<(), GcraError> {
+    pub fn revert(&mut self, rate_limit: &RateLimit, cost: impl IntoCost) -> Result<(), GcraError> {
         let arrived_at = Instant::now();
         self.revert_at(rate_limit, arrived_at, cost)
     }
@@ -104,7 +188,7 @@ impl GcraState {
         &mut self,
         rate_limit: &RateLimit,
         arrived_at: Instant,
-        cost: u32,
+        cost: impl IntoCost,
     ) -> Result<(), GcraError> {
         let increment_interval = rate_limit.increment_interval(cost);
 
@@ -129,23 +213,71 @@ impl GcraState {
         Ok(())
     }
 
+    /// Moves the state from the `old` [RateLimit] to the `new` one at the instant provided.
+    ///
+    /// The TAT is computed from the `emission_interval` of the [RateLimit] it was checked against,
+    /// so reusing it as-is under a different [RateLimit] either over-penalises or hands out a
+    /// free burst. This rescales the outstanding part of the TAT according to `mode`.
+    pub fn rescale(&mut self, old: &RateLimit, new: &RateLimit, now: Instant, mode: RescaleMode) {
+        let old_period = old.period.as_nanos();
+        let time_to_tat = match self.tat.and_then(|tat| tat.checked_duration_since(now)) {
+            Some(duration_until) if old_period > 0 => duration_until.as_nanos(),
+            // Nothing consumed, so nothing to rescale
+            _ => return,
+        };
+
+        // In integer nanoseconds and parts of a unit rather than through the truncated
+        // `emission_interval`, which is zero for limits above the period's nanoseconds
+        let new_time_to_tat = match mode {
+            RescaleMode::ConsumedFraction => {
+                nanos_to_duration(mul_div_ceil(time_to_tat, new.period.as_nanos(), old_period))
+            }
+            RescaleMode::ConsumedUnits => {
+                let limit = Cost::units(old.resource_limit_u64()).parts();
+                let consumed = Cost::from_parts(mul_div_ceil(time_to_tat, limit, old_period));
+                new.increment_interval(consumed).min(new.period)
+            }
+        };
+        self.tat = now.checked_add(new_time_to_tat).or(self.tat);
+    }
+
     /// Get the remaing resources that we have available for the guard at the instant provided.
+    ///
+    /// Rounded down to whole resources and saturating at `u32::MAX`, see
+    /// [GcraState::remaining_resources_u64] for larger limits and [GcraState::remaining_cost] for
+    /// the fractional part.
     pub fn remaining_resources(&self, rate_limit: &RateLimit, now: Instant) -> u32 {
-        if rate_limit.period.is_zero() {
-            return 0;
+        let remaining = self.remaining_resources_u64(rate_limit, now);
+        u32::try_from(remaining).unwrap_or(u32::MAX)
+    }
+
+    /// Get the remaining whole resources at the instant provided, for limits above `u32::MAX`.
+    pub fn remaining_resources_u64(&self, rate_limit: &RateLimit, now: Instant) -> u64 {
+        self.remaining_cost(rate_limit, now).floor()
+    }
+
+    /// Get the remaining resources, including fractions of a resource, at the instant provided.
+    pub fn remaining_cost(&self, rate_limit: &RateLimit, now: Instant) -> Cost {
+        let period = rate_limit.period.as_nanos();
+        if period == 0 {
+            return Cost::ZERO;
         }
 
+        let limit = Cost::units(rate_limit.resource_limit_u64());
         let time_to_tat = match self.tat.and_then(|tat| tat.checked_duration_since(now)) {
-            Some(duration_until) => duration_until,
-            None => return rate_limit.resource_limit,
+            Some(duration_until) => duration_until.as_nanos(),
+            None => return limit,
         };
 
-        // Logically this makes more sense as:
-        //   consumed_resources = time_to_tat * (resource_limit/period)
-        // but we run it this way because of Duration's arithmetic functions
-        let consumed_resources =
-            (time_to_tat * rate_limit.resource_limit).div_duration_f32(rate_limit.period);
-        rate_limit.resource_limit - consumed_resources.ceil() as u32
+        // consumed = ceil(time_to_tat * resource_limit / period), in integer parts so that large
+        // limits stay exact
+        let consumed = match time_to_tat.checked_mul(limit.parts()) {
+            Some(consumed) => consumed.div_ceil(period),
+            None => (time_to_tat.saturating_mul(u128::from(rate_limit.resource_limit_u64())))
+                .div_ceil(period)
+                .saturating_mul(u128::from(Cost::SCALE)),
+        };
+        Cost::from_parts(limit.parts().saturating_sub(consumed))
     }
 }
 
@@ -187,6 +319,111 @@ mod tests {
         );
     }
 
+    #[test]
+    fn gcra_rescale_consumed_fraction() {
+        let now = Instant::now();
+        let old = RateLimit::per_sec(10);
+        let new = RateLimit::new(100, Duration::from_secs(2));
+
+        let mut gcra = GcraState::default();
+        assert_eq!(Ok(()), gcra.check_and_modify_at(&old, now, 5));
+        gcra.rescale(&old, &new, now, RescaleMode::ConsumedFraction);
+
+        assert_eq!(Some(now + Duration::from_secs(1)), gcra.tat);
+        assert_eq!(
+            50,
+            gcra.remaining_resources(&new, now),
+            "Half used stays half used"
+        );
+    }
+
+    #[test]
+    fn gcra_rescale_consumed_units() {
+        let now = Instant::now();
+        let old = RateLimit::per_sec(10);
+        let new = RateLimit::per_sec(100);
+
+        let mut gcra = GcraState::default();
+        assert_eq!(Ok(()), gcra.check_and_modify_at(&old, now, 5));
+        gcra.rescale(&old, &new, now, RescaleMode::ConsumedUnits);
+        assert_eq!(
+            95,
+            gcra.remaining_resources(&new, now),
+            "5 used stays 5 used"
+        );
+
+        gcra.rescale(
+            &new,
+            &RateLimit::per_sec(2),
+            now,
+            RescaleMode::ConsumedUnits,
+        );
+        assert_eq!(
+            Some(now + Duration::from_secs(1)),
+            gcra.tat,
+            "Consumed units are capped at the new limit"
+        );
+    }
+
+    #[test]
+    fn gcra_rescale_zero_emission_interval() {
+        let now = Instant::now();
+        let old = RateLimit::per_sec(u32::MAX);
+        assert_eq!(Duration::ZERO, old.emission_interval);
+
+        for mode in [RescaleMode::ConsumedFraction, RescaleMode::ConsumedUnits] {
+            let mut gcra = GcraState { tat: Some(now) };
+            gcra.rescale(&old, &RateLimit::per_sec(10), now, mode);
+            assert_eq!(Some(now), gcra.tat, "Nothing outstanding stays so");
+        }
+
+        let mut gcra = GcraState::default();
+        let half = Cost::units(u64::from(u32::MAX / 2) + 1);
+        assert_eq!(Ok(()), gcra.check_and_modify_at(&old, now, half));
+        let mut units = gcra;
+        gcra.rescale(
+            &old,
+            &RateLimit::per_sec(10),
+            now,
+            RescaleMode::ConsumedFraction,
+        );
+        assert_eq!(5, gcra.remaining_resources(&RateLimit::per_sec(10), now));
+
+        units.rescale(
+            &old,
+            &RateLimit::per_sec(10),
+            now,
+            RescaleMode::ConsumedUnits,
+        );
+        assert_eq!(
+            Some(now + Duration::from_secs(1)),
+            units.tat,
+            "Consumed units are capped at the new limit"
+        );
+    }
+
+    #[test]
+    fn gcra_rescale_stale_state() {
+        let now = Instant::now();
+        let old = RateLimit::per_sec(10);
+        let new = RateLimit::per_sec(100);
+
+        let mut gcra = GcraState::default();
+        gcra.rescale(&old, &new, now, RescaleMode::ConsumedFraction);
+        assert_eq!(None, gcra.tat, "New state should be untouched");
+
+        let past_tat = now - Duration::from_secs(1);
+        let mut gcra = GcraState {
+            tat: Some(past_tat),
+        };
+        gcra.rescale(&old, &new, now, RescaleMode::ConsumedFraction);
+        assert_eq!(
+            Some(past_tat),
+            gcra.tat,
+            "Replenished state should be untouched"
+        );
+    }
+
     #[test]
     fn gcra_basics() {
         let mut gcra = GcraState::default();
@@ -422,8 +659,10 @@ mod tests {
             Err(GcraError::DeniedIndefinitely {
                 cost,
                 rate_limit: rl,
+                exact_cost,
             }) => {
                 assert_eq!(over_limit_cost, cost);
+                assert_eq!(Cost::from(over_limit_cost), exact_cost);
                 assert_eq!(rate_limit, rl);
             }
             e => panic!("request #2 would never succeed {:?}", e),
@@ -462,6 +701,69 @@ mod tests {
         assert_eq!(after_first_tat, gcra.tat, "State should be unchanged.")
     }
 
+    #[test]
+    fn gcra_large_costs() {
+        const GB: u64 = 1_000_000_000;
+        let mut gcra = GcraState::default();
+        let rate_limit = RateLimit::new_u64(50 * GB, Duration::from_secs(24 * 60 * 60));
+
+        let now = Instant::now();
+        let cost = Cost::units(40 * GB);
+        assert_eq!(Ok(()), gcra.check_and_modify_at(&rate_limit, now, cost));
+        assert_eq!(10 * GB, gcra.remaining_resources_u64(&rate_limit, now));
+        assert_eq!(
+            u32::MAX,
+            gcra.remaining_resources(&rate_limit, now),
+            "Remaining resources saturate as a u32"
+        );
+
+        assert_eq!(
+            Err(GcraError::DeniedUntil {
+                next_allowed_at: now + Duration::from_secs(4 * 60 * 60 + 48 * 60)
+            }),
+            gcra.check_and_modify_at(&rate_limit, now, Cost::units(20 * GB)),
+            "10 GB are replenished every 4.8 hours"
+        );
+
+        assert_eq!(
+            Err(GcraError::DeniedIndefinitely {
+                cost: u32::MAX,
+                rate_limit: rate_limit.clone(),
+                exact_cost: Cost::units(60 * GB),
+            }),
+            gcra.check_and_modify_at(&rate_limit, now, 60 * GB),
+            "Costs above u32::MAX saturate"
+        );
+    }
+
+    #[test]
+    fn gcra_fractional_costs() {
+        let mut gcra = GcraState::default();
+        let rate_limit = RateLimit::per_sec(10);
+        let quarter = Cost::from_parts(250_000);
+
+        let now = Instant::now();
+        assert_eq!(Ok(()), gcra.check_and_modify_at(&rate_limit, now, quarter));
+        assert_eq!(Some(now + Duration::from_millis(25)), gcra.tat);
+        assert_eq!(
+            Ok(()),
+            gcra.check_and_modify_at(&rate_limit, now, "2.5".parse::<Cost>().unwrap())
+        );
+        assert_eq!(
+            "7.25".parse(),
+            Ok(gcra.remaining_cost(&rate_limit, now)),
+            "Fractions of a resource remain"
+        );
+        assert_eq!(7, gcra.remaining_resources(&rate_limit, now));
+
+        assert_eq!(Ok(()), gcra.revert_at(&rate_limit, now, quarter));
+        assert_eq!(
+            Ok(gcra.remaining_cost(&rate_limit, now)),
+            "7.5".parse(),
+            "Reverting gives the fraction back"
+        );
+    }
+
     #[test]
     fn gcra_refreshed_after_period() {
         let past_time = Instant::now() - Duration::from_millis(1001);
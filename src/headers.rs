@@ -0,0 +1,598 @@
+//! Formats and parses rate limit response headers, independent of any web framework.
+//!
+//! Supported [HeaderFormat]s:
+//! - The `RateLimit-Policy` and `RateLimit` structured fields of the current
+//!   [IETF draft](https://datatracker.ietf.org/doc/draft-ietf-httpapi-ratelimit-headers/),
+//!   e.g. `"api";q=100;w=60` and `"api";r=42;t=30`.
+//! - `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` of draft 03.
+//! - The legacy `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`.
+//!
+//! `Retry-After` is added to all of them when set.
+//!
+//! Durations are written as delta-seconds rounded up, as clients acting on a value rounded down
+//! would come back too early and be denied again.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{GcraState, RateLimit};
+
+pub const RATELIMIT_POLICY: &str = "ratelimit-policy";
+pub const RATELIMIT: &str = "ratelimit";
+pub const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+pub const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+pub const RATELIMIT_RESET: &str = "ratelimit-reset";
+pub const X_RATELIMIT_LIMIT: &str = "x-ratelimit-limit";
+pub const X_RATELIMIT_REMAINING: &str = "x-ratelimit-remaining";
+pub const X_RATELIMIT_RESET: &str = "x-ratelimit-reset";
+pub const RETRY_AFTER: &str = "retry-after";
+
+/// Policy name used by the structured fields when none is set.
+const DEFAULT_POLICY: &str = "default";
+
+/// `X-RateLimit-Reset` values from this point on are Unix timestamps rather than delta-seconds,
+/// as some APIs send them.
+const MIN_UNIX_RESET: u64 = 1_000_000_000;
+
+/// Which headers to write.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeaderFormat {
+    /// `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`.
+    #[default]
+    Draft03,
+    /// `RateLimit-Policy` and `RateLimit`.
+    Structured,
+    /// `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`.
+    Legacy,
+}
+
+/// Delta-seconds for `duration`, rounded up.
+pub fn delta_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Delta-seconds from `now` until `instant`, zero if it has already passed.
+pub fn delta_seconds_until(instant: Instant, now: Instant) -> u64 {
+    delta_seconds(instant.saturating_duration_since(now))
+}
+
+/// What rate limit headers convey. Any field can be missing when parsed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimitHeaders {
+    /// Name of the policy, only conveyed by the structured fields.
+    pub policy: Option<String>,
+    pub limit: Option<u64>,
+    /// Period over which `limit` applies.
+    pub window: Option<Duration>,
+    pub remaining: Option<u64>,
+    /// Time until the limit is fully replenished.
+    pub reset_after: Option<Duration>,
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimitHeaders {
+    /// Describes the limit of a key with `state` at `now`.
+    pub fn new(rate_limit: &RateLimit, state: &GcraState, now: Instant) -> Self {
+        Self {
+            policy: None,
+            limit: Some(rate_limit.resource_limit_u64()),
+            window: Some(rate_limit.period),
+            remaining: Some(state.remaining_resources_u64(rate_limit, now)),
+            reset_after: Some(
+                state
+                    .tat
+                    .map_or(Duration::ZERO, |tat| tat.saturating_duration_since(now)),
+            ),
+            retry_after: None,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: impl Into<String>) -> Self {
+        self.policy = Some(policy.into());
+        self
+    }
+
+    /// Sets `Retry-After` for a request denied until `next_allowed_at`, e.g. from
+    /// [GcraError::DeniedUntil](crate::GcraError::DeniedUntil).
+    pub fn with_next_allowed_at(mut self, next_allowed_at: Instant, now: Instant) -> Self {
+        self.retry_after = Some(next_allowed_at.saturating_duration_since(now));
+        self
+    }
+
+    /// When the limit is fully replenished, `None` if unknown or too far away for an [Instant].
+    pub fn reset_at(&self, now: Instant) -> Option<Instant> {
+        now.checked_add(self.reset_after?)
+    }
+
+    /// When to retry a denied request, `None` if unknown or too far away for an [Instant].
+    pub fn retry_at(&self, now: Instant) -> Option<Instant> {
+        now.checked_add(self.retry_after?)
+    }
+
+    /// `RateLimit-Policy` value, e.g. `"api";q=100;w=60`.
+    pub fn ratelimit_policy(&self) -> Option<String> {
+        let mut value = format!("{};q={}", self.quoted_policy(), self.limit?);
+        if let Some(window) = self.window {
+            value.push_str(&format!(";w={}", delta_seconds(window)));
+        }
+        Some(value)
+    }
+
+    /// `RateLimit` value, e.g. `"api";r=42;t=30`.
+    pub fn ratelimit(&self) -> Option<String> {
+        let mut value = format!("{};r={}", self.quoted_policy(), self.remaining?);
+        if let Some(reset_after) = self.reset_after {
+            value.push_str(&format!(";t={}", delta_seconds(reset_after)));
+        }
+        Some(value)
+    }
+
+    fn quoted_policy(&self) -> String {
+        let policy = self.policy.as_deref().unwrap_or(DEFAULT_POLICY);
+        let escaped = policy.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("\"{escaped}\"")
+    }
+
+    /// Header names and values in `format`, skipping unknown fields.
+    pub fn to_headers(&self, format: HeaderFormat) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::with_capacity(4);
+        let mut push = |name, value: Option<String>| {
+            if let Some(value) = value {
+                headers.push((name, value));
+            }
+        };
+        let reset = self
+            .reset_after
+            .map(|reset| delta_seconds(reset).to_string());
+        match format {
+            HeaderFormat::Draft03 => {
+                push(RATELIMIT_LIMIT, self.limit.map(|limit| limit.to_string()));
+                push(RATELIMIT_REMAINING, self.remaining.map(|r| r.to_string()));
+                push(RATELIMIT_RESET, reset);
+            }
+            HeaderFormat::Structured => {
+                push(RATELIMIT_POLICY, self.ratelimit_policy());
+                push(RATELIMIT, self.ratelimit());
+            }
+            HeaderFormat::Legacy => {
+                push(X_RATELIMIT_LIMIT, self.limit.map(|limit| limit.to_string()));
+                push(X_RATELIMIT_REMAINING, self.remaining.map(|r| r.to_string()));
+                push(X_RATELIMIT_RESET, reset);
+            }
+        }
+        push(
+            RETRY_AFTER,
+            self.retry_after
+                .map(|retry_after| delta_seconds(retry_after).to_string()),
+        );
+        headers
+    }
+
+    /// [to_headers](Self::to_headers) converted to the header types of a web framework, skipping
+    /// the ones it rejects.
+    #[cfg(any(feature = "http", feature = "actix"))]
+    pub(crate) fn validated_headers<N, V>(
+        &self,
+        format: HeaderFormat,
+    ) -> impl Iterator<Item = (N, V)>
+    where
+        N: TryFrom<&'static str>,
+        V: TryFrom<String>,
+    {
+        self.to_headers(format)
+            .into_iter()
+            // Policy names aren't necessarily valid header values
+            .filter_map(|(name, value)| Some((N::try_from(name).ok()?, V::try_from(value).ok()?)))
+    }
+
+    /// Parses the rate limit headers among `headers`, e.g. of an upstream response. Names are
+    /// matched case-insensitively and unrelated headers are ignored. When several formats are
+    /// present the structured fields win over draft 03, which wins over the legacy headers.
+    ///
+    /// `now` is needed for `Retry-After` HTTP-dates and `X-RateLimit-Reset` Unix timestamps.
+    pub fn parse<'a>(
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+        now: SystemTime,
+    ) -> Self {
+        let mut structured = Self::default();
+        let mut draft03 = Self::default();
+        let mut legacy = Self::default();
+        let mut retry_after = None;
+
+        for (name, value) in headers {
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                RATELIMIT_POLICY => {
+                    let item = parse_item(value);
+                    structured.limit = item.param("q").or(item.integer);
+                    structured.window = item.param("w").map(Duration::from_secs);
+                    structured.policy = structured.policy.or(item.name);
+                }
+                RATELIMIT => {
+                    let item = parse_item(value);
+                    structured.remaining = item.param("r").or(item.param("remaining"));
+                    structured.reset_after = item
+                        .param("t")
+                        .or(item.param("reset"))
+                        .map(Duration::from_secs);
+                    structured.limit = structured.limit.or(item.param("limit"));
+                    structured.policy = item.name.or(structured.policy);
+                }
+                RATELIMIT_LIMIT => {
+                    let item = parse_item(value);
+                    draft03.limit = item.integer;
+                    draft03.window = item.param("w").map(Duration::from_secs);
+                }
+                RATELIMIT_REMAINING => draft03.remaining = value.parse().ok(),
+                RATELIMIT_RESET => draft03.reset_after = parse_seconds(value),
+                X_RATELIMIT_LIMIT => legacy.limit = value.parse().ok(),
+                X_RATELIMIT_REMAINING => legacy.remaining = value.parse().ok(),
+                X_RATELIMIT_RESET => {
+                    legacy.reset_after = value.parse::<u64>().ok().and_then(|reset| {
+                        if reset < MIN_UNIX_RESET {
+                            return Some(Duration::from_secs(reset));
+                        }
+                        let reset_at =
+                            SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(reset))?;
+                        Some(reset_at.duration_since(now).unwrap_or_default())
+                    })
+                }
+                RETRY_AFTER => {
+                    retry_after = parse_seconds(value).or_else(|| {
+                        let retry_at = parse_http_date(value)?;
+                        Some(retry_at.duration_since(now).unwrap_or_default())
+                    })
+                }
+                _ => {}
+            }
+        }
+
+        Self {
+            policy: structured.policy,
+            limit: structured.limit.or(draft03.limit).or(legacy.limit),
+            window: structured.window.or(draft03.window),
+            remaining: structured
+                .remaining
+                .or(draft03.remaining)
+                .or(legacy.remaining),
+            reset_after: structured
+                .reset_after
+                .or(draft03.reset_after)
+                .or(legacy.reset_after),
+            retry_after,
+        }
+    }
+}
+
+#[cfg(feature = "rate-limiter")]
+impl From<&crate::Decision> for RateLimitHeaders {
+    fn from(decision: &crate::Decision) -> Self {
+        Self {
+            policy: decision.policy.as_deref().map(str::to_string),
+            limit: Some(decision.limit()),
+            window: Some(decision.rate_limit.period),
+            remaining: Some(decision.remaining),
+            reset_after: Some(decision.reset_after),
+            retry_after: decision.retry_after,
+        }
+    }
+}
+
+fn parse_seconds(value: &str) -> Option<Duration> {
+    value.parse().ok().map(Duration::from_secs)
+}
+
+/// The first member of a structured field list or dictionary: its name or integer value, and
+/// its parameters. Dictionaries such as `limit=100, remaining=50` are read as parameters.
+#[derive(Default)]
+struct Item<'a> {
+    name: Option<String>,
+    integer: Option<u64>,
+    params: Vec<(&'a str, &'a str)>,
+}
+
+impl Item<'_> {
+    fn param<T: std::str::FromStr>(&self, key: &str) -> Option<T> {
+        self.params
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .and_then(|(_, value)| value.parse().ok())
+    }
+}
+
+fn parse_item(value: &str) -> Item<'_> {
+    let mut item = Item::default();
+    let is_dictionary = value
+        .split([',', ';'])
+        .next()
+        .is_some_and(|first| first.contains('='));
+    let first_member = if is_dictionary {
+        value
+    } else {
+        value.split(',').next().unwrap_or_default()
+    };
+
+    for (i, part) in first_member.split([',', ';']).map(str::trim).enumerate() {
+        if let Some((key, value)) = part.split_once('=') {
+            item.params.push((key.trim(), value.trim()));
+        } else if i == 0 {
+            match part
+                .strip_prefix('"')
+                .and_then(|part| part.strip_suffix('"'))
+            {
+                Some(name) => item.name = Some(name.replace("\\\"", "\"").replace("\\\\", "\\")),
+                None => item.integer = part.parse().ok(),
+            }
+        }
+    }
+    item
+}
+
+/// Parses an IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT`, the only HTTP-date format
+/// senders are allowed to generate.
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let mut parts = value.split_ascii_whitespace().skip(1);
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    let mut time = parts
+        .next()?
+        .split(':')
+        .map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if parts.next() != Some("GMT")
+        || year < 1970
+        || !(1..=days_in_month).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    // Days since the epoch of the civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    // Only the year is unbounded, dates too far away for a SystemTime are dropped
+    let days = era.checked_mul(146_097)?.checked_add(day_of_era)? - 719_468;
+
+    let secs = days
+        .checked_mul(86_400)?
+        .checked_add(hour * 3_600 + minute * 60 + second)?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers() -> RateLimitHeaders {
+        RateLimitHeaders {
+            policy: Some("api".to_string()),
+            limit: Some(100),
+            window: Some(Duration::from_secs(60)),
+            remaining: Some(42),
+            reset_after: Some(Duration::from_millis(29_500)),
+            retry_after: None,
+        }
+    }
+
+    #[test]
+    fn from_state() {
+        let rate_limit = RateLimit::new(10, Duration::from_secs(10));
+        let mut state = GcraState::default();
+        let now = Instant::now();
+        state.check_and_modify_at(&rate_limit, now, 4).unwrap();
+
+        let headers = RateLimitHeaders::new(&rate_limit, &state, now)
+            .with_next_allowed_at(now + Duration::from_millis(1_500), now);
+        assert_eq!(
+            RateLimitHeaders {
+                policy: None,
+                limit: Some(10),
+                window: Some(Duration::from_secs(10)),
+                remaining: Some(6),
+                reset_after: Some(Duration::from_secs(4)),
+                retry_after: Some(Duration::from_millis(1_500)),
+            },
+            headers
+        );
+        assert_eq!(
+            vec![
+                (RATELIMIT_LIMIT, "10".to_string()),
+                (RATELIMIT_REMAINING, "6".to_string()),
+                (RATELIMIT_RESET, "4".to_string()),
+                (RETRY_AFTER, "2".to_string()),
+            ],
+            headers.to_headers(HeaderFormat::Draft03)
+        );
+    }
+
+    #[test]
+    fn format() {
+        let headers = headers();
+        assert_eq!(
+            vec![
+                (RATELIMIT_POLICY, r#""api";q=100;w=60"#.to_string()),
+                (RATELIMIT, r#""api";r=42;t=30"#.to_string()),
+            ],
+            headers.to_headers(HeaderFormat::Structured)
+        );
+        assert_eq!(
+            vec![
+                (X_RATELIMIT_LIMIT, "100".to_string()),
+                (X_RATELIMIT_REMAINING, "42".to_string()),
+                (X_RATELIMIT_RESET, "30".to_string()),
+            ],
+            headers.to_headers(HeaderFormat::Legacy)
+        );
+
+        let unnamed = RateLimitHeaders {
+            policy: None,
+            ..headers
+        };
+        assert_eq!(
+            Some(r#""default";r=42;t=30"#.to_string()),
+            unnamed.ratelimit()
+        );
+        assert_eq!(
+            Vec::<(&str, String)>::new(),
+            RateLimitHeaders::default().to_headers(HeaderFormat::Structured)
+        );
+    }
+
+    #[test]
+    fn parse_round_trips() {
+        let now = SystemTime::now();
+        let rounded = RateLimitHeaders {
+            reset_after: Some(Duration::from_secs(30)),
+            retry_after: Some(Duration::from_secs(5)),
+            ..headers()
+        };
+        for format in [
+            HeaderFormat::Draft03,
+            HeaderFormat::Structured,
+            HeaderFormat::Legacy,
+        ] {
+            let written = rounded.to_headers(format);
+            let parsed = RateLimitHeaders::parse(
+                written.iter().map(|(name, value)| (*name, value.as_str())),
+                now,
+            );
+            let expected = match format {
+                HeaderFormat::Structured => rounded.clone(),
+                _ => RateLimitHeaders {
+                    policy: None,
+                    window: None,
+                    ..rounded.clone()
+                },
+            };
+            assert_eq!(expected, parsed, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn parse_upstream_variants() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let parsed = RateLimitHeaders::parse(
+            [
+                ("Content-Type", "text/plain"),
+                (
+                    "RateLimit-Policy",
+                    r#""burst";q=10;w=1, "daily";q=1000;w=86400"#,
+                ),
+                ("X-RateLimit-Remaining", "7"),
+                ("X-RateLimit-Reset", "1700000012"),
+                ("Retry-After", "Tue, 14 Nov 2023 22:13:30 GMT"),
+            ],
+            now,
+        );
+        assert_eq!(
+            RateLimitHeaders {
+                policy: Some("burst".to_string()),
+                limit: Some(10),
+                window: Some(Duration::from_secs(1)),
+                remaining: Some(7),
+                reset_after: Some(Duration::from_secs(12)),
+                retry_after: Some(Duration::from_secs(10)),
+            },
+            parsed
+        );
+
+        let parsed = RateLimitHeaders::parse(
+            [
+                ("ratelimit", "limit=100, remaining=50, reset=5"),
+                ("RateLimit-Limit", "100;w=60"),
+            ],
+            now,
+        );
+        assert_eq!(Some(100), parsed.limit);
+        assert_eq!(Some(Duration::from_secs(60)), parsed.window);
+        assert_eq!(Some(50), parsed.remaining);
+        assert_eq!(Some(Duration::from_secs(5)), parsed.reset_after);
+    }
+
+    #[test]
+    fn http_dates() {
+        assert_eq!(
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777)),
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT")
+        );
+        assert_eq!(
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            parse_http_date("Tue, 29 Feb 2000 00:00:00 GMT")
+        );
+        assert_eq!(None, parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"));
+        assert_eq!(None, parse_http_date("soon"));
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994 99:49:37 GMT"));
+        assert_eq!(None, parse_http_date("Tue, 31 Feb 2026 08:49:37 GMT"));
+        assert_eq!(None, parse_http_date("Fri, 29 Feb 2019 08:49:37 GMT"));
+        assert_eq!(None, parse_http_date("Thu, 31 Apr 2026 08:49:37 GMT"));
+        assert_eq!(None, parse_http_date("Sat, 00 Nov 1994 08:49:37 GMT"));
+        assert_eq!(
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_582_934_400)),
+            parse_http_date("Sat, 29 Feb 2020 00:00:00 GMT")
+        );
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994 08:60:37 GMT"));
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994 08:49:60 GMT"));
+        assert_eq!(
+            None,
+            parse_http_date("Sun, 06 Nov 18446744073709551615 08:49:37 GMT")
+        );
+    }
+
+    #[test]
+    fn parse_hostile_values() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let parsed = RateLimitHeaders::parse(
+            [
+                ("X-RateLimit-Reset", "18446744073709551615"),
+                ("Retry-After", "18446744073709551615"),
+            ],
+            now,
+        );
+        assert_eq!(None, parsed.reset_after);
+        assert_eq!(Some(Duration::from_secs(u64::MAX)), parsed.retry_after);
+        assert_eq!(None, parsed.retry_at(Instant::now()));
+
+        let parsed = RateLimitHeaders::parse(
+            [(
+                "Retry-After",
+                "Sun, 06 Nov 18446744073709551615 08:49:37 GMT",
+            )],
+            now,
+        );
+        assert_eq!(None, parsed.retry_after);
+    }
+
+    #[test]
+    fn delta_seconds_round_up() {
+        assert_eq!(0, delta_seconds(Duration::ZERO));
+        assert_eq!(1, delta_seconds(Duration::from_nanos(1)));
+        assert_eq!(2, delta_seconds(Duration::from_millis(1_001)));
+
+        let now = Instant::now();
+        assert_eq!(0, delta_seconds_until(now, now + Duration::from_secs(1)));
+        assert_eq!(
+            3,
+            delta_seconds_until(now + Duration::from_millis(2_500), now)
+        );
+    }
+}
@@ -0,0 +1,538 @@
+//! Middleware rate limiting [http] services such as [axum](https://docs.rs/axum) routers, built
+//! on the [tower](crate::tower) integration.
+//!
+//! Every limited response carries the `RateLimit-Limit`, `RateLimit-Remaining` and
+//! `RateLimit-Reset` headers of the IETF
+//! [RateLimit header fields draft](https://datatracker.ietf.org/doc/html/draft-ietf-httpapi-ratelimit-headers-03),
+//! or another [HeaderFormat] picked with [GcraHttpLayer::with_header_format]. Denied requests
+//! are answered with `429 Too Many Requests` and a `Retry-After` header, unless their cost
+//! exceeds the limit and retrying can never succeed.
+//!
+//! ```rust
+//! use std::sync::Arc;
+//! use axum::{routing::get, Router};
+//! use gcra::{http::{GcraHttpLayer, KeyBy}, RateLimit, RateLimiter};
+//!
+//! let limiter: Arc<RateLimiter<String>> = Arc::new(RateLimiter::new(1024));
+//! let app: Router = Router::new()
+//!     .route("/", get(|| async { "Hello" }))
+//!     .layer(GcraHttpLayer::new(limiter, RateLimit::per_sec(10), KeyBy::ClientIp));
+//! ```
+
+use std::{
+    fmt::Display,
+    future::Future,
+    hash::Hash,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::extract::{ConnectInfo, MatchedPath};
+use http::{HeaderMap, HeaderName, Request, Response, StatusCode};
+use tower::{Layer, Service};
+
+use crate::{
+    clock::{Clock, InstantClock},
+    headers::{HeaderFormat, RateLimitHeaders},
+    ip::{ClientIp, IpPrefix},
+    tower::{KeyExtractor, Limited, MissingKey, RequestCost, UnitCost},
+    DashMapStore, Decision, GcraStore, NoopObserver, Observer, RateLimit, RateLimiter,
+    RateLimiterError,
+};
+
+/// Built-in ways of keying requests.
+///
+/// Requests without a key are answered with `400 Bad Request`, unless [MissingKey::Allow] is
+/// set with [GcraHttpLayer::with_missing_key].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyBy {
+    /// The peer's IP address, from axum's `ConnectInfo<SocketAddr>` or a [SocketAddr] request
+    /// extension. Axum only provides it when served with `into_make_service_with_connect_info`.
+    ClientIp,
+    /// The value of a header, e.g. an API key.
+    Header(HeaderName),
+    /// The method and route of the request, shared by all clients. Uses axum's [MatchedPath]
+    /// when available so `/users/{id}` is limited as a whole rather than per user.
+    Route,
+}
+
+impl<B> KeyExtractor<Request<B>> for KeyBy {
+    type Key = Option<String>;
+
+    fn extract(&self, request: &Request<B>) -> Option<String> {
+        match self {
+            KeyBy::ClientIp => peer_addr(request).map(|addr| addr.ip().to_string()),
+            KeyBy::Header(name) => request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            KeyBy::Route => {
+                let path = request
+                    .extensions()
+                    .get::<MatchedPath>()
+                    .map_or_else(|| request.uri().path(), MatchedPath::as_str);
+                Some(format!("{} {path}", request.method()))
+            }
+        }
+    }
+}
+
+/// Keys requests by the [IpPrefix] of the client, read from the same request extensions as
+/// [KeyBy::ClientIp] and the headers.
+impl<B> KeyExtractor<Request<B>> for ClientIp {
+    type Key = Option<IpPrefix>;
+
+    fn extract(&self, request: &Request<B>) -> Option<IpPrefix> {
+        let peer = peer_addr(request)?;
+        let headers = request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)));
+        Some(self.key(peer.ip(), headers))
+    }
+}
+
+fn peer_addr<B>(request: &Request<B>) -> Option<SocketAddr> {
+    let extensions = request.extensions();
+    extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr)
+        .or_else(|| extensions.get::<SocketAddr>().copied())
+}
+
+fn insert_headers(headers: &mut HeaderMap, decision: &Decision, format: HeaderFormat) {
+    for (name, value) in RateLimitHeaders::from(decision).validated_headers(format) {
+        headers.insert::<HeaderName>(name, value);
+    }
+}
+
+fn status_response<B: Default>(status: StatusCode) -> Response<B> {
+    let mut response = Response::new(B::default());
+    *response.status_mut() = status;
+    response
+}
+
+/// Applies [GcraHttpService] to services.
+pub struct GcraHttpLayer<
+    KF,
+    CF = UnitCost,
+    Key: Eq + Hash = String,
+    C = InstantClock,
+    St = DashMapStore<Key>,
+    O = NoopObserver,
+> {
+    limited: Limited<Key, C, St, O>,
+    key: KF,
+    cost: CF,
+    format: HeaderFormat,
+    missing_key: MissingKey,
+}
+
+impl<KF, CF, Key, C, St, O> Clone for GcraHttpLayer<KF, CF, Key, C, St, O>
+where
+    KF: Clone,
+    CF: Clone,
+    Key: Eq + Hash,
+{
+    fn clone(&self) -> Self {
+        Self {
+            limited: self.limited.clone(),
+            key: self.key.clone(),
+            cost: self.cost.clone(),
+            format: self.format,
+            missing_key: self.missing_key,
+        }
+    }
+}
+
+impl<KF, Key, C, St, O> GcraHttpLayer<KF, UnitCost, Key, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    /// Limits requests by the key extracted by `key`, e.g. a [KeyBy], charging each a cost of 1.
+    pub fn new(limiter: Arc<RateLimiter<Key, C, St, O>>, rate_limit: RateLimit, key: KF) -> Self {
+        Self {
+            limited: Limited::rate_limit(limiter, rate_limit),
+            key,
+            cost: UnitCost,
+            format: HeaderFormat::default(),
+            missing_key: MissingKey::default(),
+        }
+    }
+
+    /// Limits requests by the key extracted by `key` against the registered `policy`, charging
+    /// each a cost of 1. Requests fail with `500 Internal Server Error` while the policy isn't
+    /// registered.
+    pub fn with_policy(
+        limiter: Arc<RateLimiter<Key, C, St, O>>,
+        policy: impl Into<String>,
+        key: KF,
+    ) -> Self {
+        Self {
+            limited: Limited::policy(limiter, policy.into()),
+            key,
+            cost: UnitCost,
+            format: HeaderFormat::default(),
+            missing_key: MissingKey::default(),
+        }
+    }
+}
+
+impl<KF, CF, Key, C, St, O> GcraHttpLayer<KF, CF, Key, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    /// Charges requests the cost returned by `cost`.
+    pub fn with_cost<CF2>(self, cost: CF2) -> GcraHttpLayer<KF, CF2, Key, C, St, O> {
+        GcraHttpLayer {
+            limited: self.limited,
+            key: self.key,
+            cost,
+            format: self.format,
+            missing_key: self.missing_key,
+        }
+    }
+
+    /// Sets which rate limit headers responses carry, the draft's `RateLimit-*` by default.
+    pub fn with_header_format(mut self, format: HeaderFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets what happens to requests without a key, rejected with `400 Bad Request` by default.
+    pub fn with_missing_key(mut self, missing_key: MissingKey) -> Self {
+        self.missing_key = missing_key;
+        self
+    }
+}
+
+impl<S, KF, CF, Key, C, St, O> Layer<S> for GcraHttpLayer<KF, CF, Key, C, St, O>
+where
+    KF: Clone,
+    CF: Clone,
+    Key: Eq + Hash,
+{
+    type Service = GcraHttpService<S, KF, CF, Key, C, St, O>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GcraHttpService {
+            inner,
+            limited: self.limited.clone(),
+            key: self.key.clone(),
+            cost: self.cost.clone(),
+            format: self.format,
+            missing_key: self.missing_key,
+        }
+    }
+}
+
+/// Rate limits requests before passing them on to the wrapped service, see [GcraHttpLayer].
+///
+/// Store errors are answered with `503 Service Unavailable`, other errors of the check with
+/// `500 Internal Server Error`.
+pub struct GcraHttpService<
+    S,
+    KF,
+    CF = UnitCost,
+    Key: Eq + Hash = String,
+    C = InstantClock,
+    St = DashMapStore<Key>,
+    O = NoopObserver,
+> {
+    inner: S,
+    limited: Limited<Key, C, St, O>,
+    key: KF,
+    cost: CF,
+    format: HeaderFormat,
+    missing_key: MissingKey,
+}
+
+impl<S, KF, CF, Key, C, St, O> Clone for GcraHttpService<S, KF, CF, Key, C, St, O>
+where
+    S: Clone,
+    KF: Clone,
+    CF: Clone,
+    Key: Eq + Hash,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            limited: self.limited.clone(),
+            key: self.key.clone(),
+            cost: self.cost.clone(),
+            format: self.format,
+            missing_key: self.missing_key,
+        }
+    }
+}
+
+impl<S, ReqBody, ResBody, KF, CF, Key, C, St, O> Service<Request<ReqBody>>
+    for GcraHttpService<S, KF, CF, Key, C, St, O>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    ResBody: Default + Send + 'static,
+    KF: KeyExtractor<Request<ReqBody>, Key = Option<Key>>,
+    CF: RequestCost<Request<ReqBody>>,
+    Key: Send + Clone + Hash + Eq + Display + 'static,
+    C: Clock,
+    St: GcraStore<Key>,
+    O: Observer<Key>,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<ResBody>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let Some(key) = self.key.extract(&request) else {
+            if self.missing_key == MissingKey::Allow {
+                return Box::pin(self.inner.call(request));
+            }
+            let response = status_response(StatusCode::BAD_REQUEST);
+            return Box::pin(async { Ok(response) });
+        };
+        let cost = self.cost.cost(&request);
+        let decision = match self.limited.decide(key, cost, self.limited.now()) {
+            Ok(decision) => decision,
+            Err(e) => {
+                let status = match e {
+                    RateLimiterError::Store(_) => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                let response = status_response(status);
+                return Box::pin(async { Ok(response) });
+            }
+        };
+
+        if !decision.allowed {
+            let mut response = status_response(StatusCode::TOO_MANY_REQUESTS);
+            insert_headers(response.headers_mut(), &decision, self.format);
+            return Box::pin(async { Ok(response) });
+        }
+        let response = self.inner.call(request);
+        let format = self.format;
+        Box::pin(async move {
+            let mut response = response.await?;
+            insert_headers(response.headers_mut(), &decision, format);
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    use crate::{clock::tests::FakeClock, headers::*};
+
+    use super::*;
+
+    fn app(key: KeyBy) -> (Router, FakeClock) {
+        let clock = FakeClock::new();
+        let limiter: Arc<RateLimiter<String, _>> = Arc::new(RateLimiter::with_clock(clock.clone()));
+        let router = Router::new()
+            .route("/users/{id}", get(|| async { "user" }))
+            .route("/cost", get(|| async { "costly" }))
+            .layer(GcraHttpLayer::new(limiter, RateLimit::per_sec(2), key));
+        (router, clock)
+    }
+
+    fn get_from(path: &str, ip: [u8; 4]) -> Request<Body> {
+        Request::get(path)
+            .extension(ConnectInfo(SocketAddr::from((ip, 1234))))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn header<'a>(response: &'a Response<Body>, name: &str) -> Option<&'a str> {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[tokio::test]
+    async fn limits_by_client_ip() {
+        let (app, clock) = app(KeyBy::ClientIp);
+
+        let response = app
+            .clone()
+            .oneshot(get_from("/users/1", [10, 0, 0, 1]))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(Some("2"), header(&response, RATELIMIT_LIMIT));
+        assert_eq!(Some("1"), header(&response, RATELIMIT_REMAINING));
+        assert_eq!(Some("1"), header(&response, RATELIMIT_RESET));
+        assert_eq!(None, header(&response, RETRY_AFTER));
+
+        app.clone()
+            .oneshot(get_from("/users/2", [10, 0, 0, 1]))
+            .await
+            .unwrap();
+        let denied = app
+            .clone()
+            .oneshot(get_from("/users/3", [10, 0, 0, 1]))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, denied.status());
+        assert_eq!(Some("0"), header(&denied, RATELIMIT_REMAINING));
+        assert_eq!(Some("1"), header(&denied, RETRY_AFTER));
+
+        let other = app
+            .clone()
+            .oneshot(get_from("/users/1", [10, 0, 0, 2]))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, other.status());
+
+        clock.advance_by(Duration::from_millis(500));
+        let response = app
+            .oneshot(get_from("/users/1", [10, 0, 0, 1]))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[tokio::test]
+    async fn limits_by_header() {
+        let (app, _) = app(KeyBy::Header(HeaderName::from_static("x-api-key")));
+        let with_key = |key: &str| {
+            Request::get("/users/1")
+                .header("x-api-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for _ in 0..2 {
+            app.clone().oneshot(with_key("a")).await.unwrap();
+        }
+        let denied = app.clone().oneshot(with_key("a")).await.unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, denied.status());
+        let other = app.clone().oneshot(with_key("b")).await.unwrap();
+        assert_eq!(StatusCode::OK, other.status());
+
+        let unkeyed = || Request::get("/users/1").body(Body::empty()).unwrap();
+        let rejected = app.oneshot(unkeyed()).await.unwrap();
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            rejected.status(),
+            "Leaving the key out must not get around the limit"
+        );
+
+        let limiter: Arc<RateLimiter<String>> = Arc::new(RateLimiter::new(16));
+        let allowing = Router::new()
+            .route("/users/{id}", get(|| async { "user" }))
+            .layer(
+                GcraHttpLayer::new(
+                    limiter,
+                    RateLimit::per_sec(2),
+                    KeyBy::Header(HeaderName::from_static("x-api-key")),
+                )
+                .with_missing_key(MissingKey::Allow),
+            );
+        let allowed = allowing.oneshot(unkeyed()).await.unwrap();
+        assert_eq!(StatusCode::OK, allowed.status());
+        assert_eq!(
+            None,
+            header(&allowed, RATELIMIT_LIMIT),
+            "Allowed requests without a key aren't limited"
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_by_route() {
+        let (app, _) = app(KeyBy::Route);
+
+        for id in 0..2 {
+            let path = format!("/users/{id}");
+            let response = app
+                .clone()
+                .oneshot(get_from(&path, [10, 0, 0, id]))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+        }
+        let denied = app
+            .clone()
+            .oneshot(get_from("/users/2", [10, 0, 0, 2]))
+            .await
+            .unwrap();
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS,
+            denied.status(),
+            "All users share the route's limit"
+        );
+        let other_route = app.oneshot(get_from("/cost", [10, 0, 0, 2])).await.unwrap();
+        assert_eq!(StatusCode::OK, other_route.status());
+    }
+
+    #[tokio::test]
+    async fn denies_indefinitely_without_retry_after() {
+        let clock = FakeClock::new();
+        let limiter: Arc<RateLimiter<String, _>> = Arc::new(RateLimiter::with_clock(clock));
+        let app = Router::new().route("/", get(|| async { "ok" })).layer(
+            GcraHttpLayer::new(limiter, RateLimit::per_sec(2), KeyBy::ClientIp)
+                .with_cost(|_: &Request<Body>| 3),
+        );
+
+        let denied = app.oneshot(get_from("/", [10, 0, 0, 1])).await.unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, denied.status());
+        assert_eq!(None, header(&denied, RETRY_AFTER));
+    }
+
+    #[tokio::test]
+    async fn limits_by_forwarded_client_prefix() {
+        use crate::ip::{ForwardedHeader, PrefixLengths};
+
+        let limiter: Arc<RateLimiter<IpPrefix, _>> =
+            Arc::new(RateLimiter::with_clock(FakeClock::new()));
+        let client_ip = ClientIp::new(PrefixLengths::new(24, 64).unwrap()).with_forwarded(
+            ForwardedHeader::XForwardedFor,
+            ["10.0.0.0/8".parse().unwrap()],
+        );
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(GcraHttpLayer::new(
+                limiter,
+                RateLimit::per_sec(1),
+                client_ip,
+            ));
+        let forwarded_for = |client: &str| {
+            let mut request = get_from("/", [10, 0, 0, 1]);
+            request
+                .headers_mut()
+                .insert("x-forwarded-for", client.parse().unwrap());
+            request
+        };
+
+        let response = app
+            .clone()
+            .oneshot(forwarded_for("203.0.113.1"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let denied = app
+            .clone()
+            .oneshot(forwarded_for("203.0.113.2"))
+            .await
+            .unwrap();
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS,
+            denied.status(),
+            "Clients of the same /24 share a limit"
+        );
+        let other = app.oneshot(forwarded_for("198.51.100.1")).await.unwrap();
+        assert_eq!(StatusCode::OK, other.status());
+    }
+}
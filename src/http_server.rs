@@ -0,0 +1,404 @@
+//! An HTTP/JSON front end for a [RateLimiter] checking keys against named policies, so services
+//! that aren't written in Rust can share the same limits.
+//!
+//! # Endpoints
+//! - `POST /check` with `{ "key": "...", "policy": "...", "cost": 1 }`, `cost` being optional.
+//!   Responds with a [DecisionResponse], as `200 OK` when allowed or `429 Too Many Requests`
+//!   with a `Retry-After` header when denied. Costs exceeding the limit are denied without a
+//!   `Retry-After` as retrying can never succeed.
+//! - `GET /keys/{key}` responds with a [KeyResponse], or `404 Not Found` for untracked keys.
+//! - `DELETE /keys/{key}` forgets the key, responding with `204 No Content` or `404 Not Found`.
+//!
+//! Errors are reported as `{ "error": "..." }`.
+
+use std::{
+    future::Future,
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+use crate::{clock::Clock, headers, Decision, GcraState, GcraStore, RateLimiter, RateLimiterError};
+
+/// Body of `POST /check`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckRequest {
+    pub key: String,
+    pub policy: String,
+    #[serde(default = "default_cost")]
+    pub cost: u64,
+}
+
+fn default_cost() -> u64 {
+    1
+}
+
+/// A [Decision] as returned by `POST /check`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DecisionResponse {
+    pub allowed: bool,
+    pub policy: String,
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset_after_ms: u64,
+    /// Unset if allowed, or if the cost exceeds the limit.
+    pub retry_after_ms: Option<u64>,
+}
+
+impl DecisionResponse {
+    fn new(policy: String, decision: &Decision) -> Self {
+        Self {
+            allowed: decision.allowed,
+            policy,
+            limit: decision.limit(),
+            remaining: decision.remaining,
+            reset_after_ms: millis(decision.reset_after),
+            retry_after_ms: decision.retry_after.map(millis),
+        }
+    }
+}
+
+/// State of a key as returned by `GET /keys/{key}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyResponse {
+    pub key: String,
+    /// Unset for keys checked against ad-hoc rate limits.
+    pub policy: Option<String>,
+    /// Unset if the key's policy is no longer registered.
+    pub remaining: Option<u64>,
+    pub reset_after_ms: u64,
+    pub expires_after_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+fn error(status: StatusCode, error: impl ToString) -> Response {
+    let body = ErrorResponse {
+        error: error.to_string(),
+    };
+    (status, Json(body)).into_response()
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Builds the routes serving `limiter`.
+pub fn router<C>(limiter: Arc<RateLimiter<String, C>>) -> Router
+where
+    C: Clock + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/check", post(check::<C>))
+        .route("/keys/{key}", get(get_key::<C>).delete(delete_key::<C>))
+        .with_state(limiter)
+}
+
+/// Serves `limiter` on `listener` until `shutdown` completes, then waits for in-flight requests
+/// to finish.
+pub async fn serve<C>(
+    listener: TcpListener,
+    limiter: Arc<RateLimiter<String, C>>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()>
+where
+    C: Clock + Send + Sync + 'static,
+{
+    axum::serve(listener, router(limiter))
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+async fn check<C>(
+    State(limiter): State<Arc<RateLimiter<String, C>>>,
+    Json(request): Json<CheckRequest>,
+) -> Response
+where
+    C: Clock + Send + Sync + 'static,
+{
+    let decision = limiter
+        .decide_policy(request.key, &request.policy, request.cost)
+        .await;
+    let decision = match decision {
+        Ok(decision) => decision,
+        Err(e @ RateLimiterError::UnknownPolicy { .. }) => {
+            return error(StatusCode::UNPROCESSABLE_ENTITY, e)
+        }
+        Err(e @ RateLimiterError::PolicyMismatch { .. }) => return error(StatusCode::CONFLICT, e),
+        Err(e @ RateLimiterError::Store(_)) => return error(StatusCode::SERVICE_UNAVAILABLE, e),
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+
+    let body = Json(DecisionResponse::new(request.policy, &decision));
+    if decision.allowed {
+        return (StatusCode::OK, body).into_response();
+    }
+    let mut response = (StatusCode::TOO_MANY_REQUESTS, body).into_response();
+    if let Some(retry_after) = decision.retry_after {
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(headers::delta_seconds(retry_after)),
+        );
+    }
+    response
+}
+
+async fn get_key<C>(
+    State(limiter): State<Arc<RateLimiter<String, C>>>,
+    Path(key): Path<String>,
+) -> Response
+where
+    C: Clock + Send + Sync + 'static,
+{
+    let entry = match limiter.store().get(&key) {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return error(StatusCode::NOT_FOUND, format!("Unknown key: {key}")),
+        Err(e) => return error(StatusCode::SERVICE_UNAVAILABLE, e),
+    };
+    let now = limiter.clock().now();
+    let millis_after = |instant: Instant| millis(instant.saturating_duration_since(now));
+    let remaining = entry
+        .policy
+        .as_deref()
+        .and_then(|policy| limiter.policy(policy))
+        .map(|rate_limit| {
+            let state = GcraState {
+                tat: Some(entry.tat),
+            };
+            state.remaining_resources_u64(&rate_limit, now)
+        });
+    Json(KeyResponse {
+        policy: entry.policy.as_deref().map(str::to_string),
+        remaining,
+        reset_after_ms: millis_after(entry.tat),
+        expires_after_ms: millis_after(entry.expires_at),
+        key,
+    })
+    .into_response()
+}
+
+async fn delete_key<C>(
+    State(limiter): State<Arc<RateLimiter<String, C>>>,
+    Path(key): Path<String>,
+) -> Response
+where
+    C: Clock + Send + Sync + 'static,
+{
+    match limiter.store().update(&key, Option::take) {
+        Ok(Some(_)) => StatusCode::NO_CONTENT.into_response(),
+        Ok(None) => error(StatusCode::NOT_FOUND, format!("Unknown key: {key}")),
+        Err(e) => error(StatusCode::SERVICE_UNAVAILABLE, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::oneshot,
+        task::JoinHandle,
+    };
+
+    use crate::{clock::tests::FakeClock, RateLimit};
+
+    use super::*;
+
+    struct TestServer {
+        addr: SocketAddr,
+        clock: FakeClock,
+        shutdown: oneshot::Sender<()>,
+        served: JoinHandle<io::Result<()>>,
+    }
+
+    async fn start() -> TestServer {
+        let clock = FakeClock::new();
+        let limiter = Arc::new(RateLimiter::with_clock(clock.clone()));
+        limiter.register_policy("login", RateLimit::per_sec(2));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, stopped) = oneshot::channel();
+        let served = tokio::spawn(serve(listener, limiter, async {
+            let _ = stopped.await;
+        }));
+        TestServer {
+            addr,
+            clock,
+            shutdown,
+            served,
+        }
+    }
+
+    struct TestResponse {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl TestResponse {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+
+        fn json<T: for<'de> Deserialize<'de>>(&self) -> T {
+            serde_json::from_str(&self.body).unwrap()
+        }
+    }
+
+    /// A bare bones HTTP/1.1 client, one connection per request.
+    async fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> TestResponse {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: {addr}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let mut lines = head.lines();
+        let status = lines.next().unwrap().split(' ').nth(1).unwrap();
+        TestResponse {
+            status: status.parse().unwrap(),
+            headers: lines
+                .filter_map(|line| line.split_once(": "))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.to_string(),
+        }
+    }
+
+    async fn check(addr: SocketAddr, key: &str, cost: u64) -> TestResponse {
+        let body = serde_json::to_string(&CheckRequest {
+            key: key.to_string(),
+            policy: "login".to_string(),
+            cost,
+        })
+        .unwrap();
+        request(addr, "POST", "/check", &body).await
+    }
+
+    #[tokio::test]
+    async fn check_until_denied() {
+        let server = start().await;
+
+        let allowed = check(server.addr, "user", 1).await;
+        assert_eq!(200, allowed.status);
+        assert_eq!(
+            DecisionResponse {
+                allowed: true,
+                policy: "login".to_string(),
+                limit: 2,
+                remaining: 1,
+                reset_after_ms: 500,
+                retry_after_ms: None,
+            },
+            allowed.json()
+        );
+
+        assert_eq!(200, check(server.addr, "user", 1).await.status);
+        let denied = check(server.addr, "user", 1).await;
+        assert_eq!(429, denied.status);
+        assert_eq!(Some("1"), denied.header("retry-after"));
+        assert_eq!(Some(500), denied.json::<DecisionResponse>().retry_after_ms);
+
+        server.clock.advance_by(Duration::from_millis(500));
+        assert_eq!(200, check(server.addr, "user", 1).await.status);
+
+        let indefinitely = check(server.addr, "other", 3).await;
+        assert_eq!(429, indefinitely.status);
+        assert_eq!(None, indefinitely.header("retry-after"));
+    }
+
+    #[tokio::test]
+    async fn check_errors() {
+        let server = start().await;
+
+        let unknown = request(
+            server.addr,
+            "POST",
+            "/check",
+            r#"{"key": "user", "policy": "nope"}"#,
+        )
+        .await;
+        assert_eq!(422, unknown.status);
+        assert_eq!(r#"{"error":"Unknown policy (nope)"}"#, unknown.body);
+
+        let malformed = request(server.addr, "POST", "/check", r#"{"key": "user"}"#).await;
+        assert_eq!(422, malformed.status);
+    }
+
+    #[tokio::test]
+    async fn keys() {
+        let server = start().await;
+        assert_eq!(
+            404,
+            request(server.addr, "GET", "/keys/user", "").await.status
+        );
+
+        check(server.addr, "user", 1).await;
+        let key = request(server.addr, "GET", "/keys/user", "").await;
+        assert_eq!(200, key.status);
+        assert_eq!(
+            KeyResponse {
+                key: "user".to_string(),
+                policy: Some("login".to_string()),
+                remaining: Some(1),
+                reset_after_ms: 500,
+                expires_after_ms: 1_500,
+            },
+            key.json()
+        );
+
+        assert_eq!(
+            204,
+            request(server.addr, "DELETE", "/keys/user", "")
+                .await
+                .status
+        );
+        assert_eq!(
+            404,
+            request(server.addr, "DELETE", "/keys/user", "")
+                .await
+                .status
+        );
+        assert_eq!(
+            404,
+            request(server.addr, "GET", "/keys/user", "").await.status
+        );
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        let server = start().await;
+        assert_eq!(200, check(server.addr, "user", 1).await.status);
+
+        server.shutdown.send(()).unwrap();
+        server.served.await.unwrap().unwrap();
+        assert!(
+            TcpStream::connect(server.addr).await.is_err(),
+            "Listener should be closed"
+        );
+    }
+}
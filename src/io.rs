@@ -0,0 +1,475 @@
+//! Limiting the bandwidth of tokio's [AsyncRead]/[AsyncWrite], and with `futures-io` the
+//! [futures_io] traits of the same name, with every byte costing 1.
+//!
+//! ```rust
+//! use gcra::{io::ThrottledWriter, RateLimit};
+//! use tokio::io::AsyncWriteExt;
+//!
+//! # #[tokio::main(flavor = "current_thread", start_paused = true)]
+//! # async fn main() -> std::io::Result<()> {
+//! // 64 KiB/s
+//! let mut upload = ThrottledWriter::new(Vec::new(), RateLimit::per_sec(64 * 1024));
+//! upload.write_all(&[0; 1024]).await?;
+//! assert_eq!(1024, upload.get_ref().len());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Bytes are charged up front, in chunks of at most the burst of the rate limit so that no
+//! chunk is denied indefinitely. A read or write moving fewer bytes than charged keeps the rest
+//! for the next one. Whatever is left is given back to the [Bandwidth] once a reader reaches its
+//! end, or when the reader or writer is dropped.
+
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+#[cfg(feature = "rate-limiter")]
+use std::{fmt::Display, hash::Hash, sync::Arc, time::Instant};
+
+use pin_project_lite::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep_until, Instant as TokioInstant, Sleep},
+};
+
+pub use crate::pacer::Bandwidth;
+#[cfg(feature = "rate-limiter")]
+use crate::{
+    clock::{Clock, InstantClock},
+    DashMapStore, GcraError, GcraStore, NoopObserver, Observer, RateLimiter,
+};
+use crate::{
+    pacer::{Allowance, Pacer},
+    RateLimit, RateLimitGuard,
+};
+
+/// Bandwidth shared by everything charging the same key of a [RateLimiter].
+#[cfg(feature = "rate-limiter")]
+pub struct SharedBandwidth<
+    Key: Eq + Hash,
+    C = InstantClock,
+    St = DashMapStore<Key>,
+    O = NoopObserver,
+> {
+    limiter: Arc<RateLimiter<Key, C, St, O>>,
+    key: Key,
+    rate_limit: RateLimit,
+}
+
+#[cfg(feature = "rate-limiter")]
+impl<Key: Eq + Hash, C, St, O> SharedBandwidth<Key, C, St, O> {
+    pub fn new(limiter: Arc<RateLimiter<Key, C, St, O>>, key: Key, rate_limit: RateLimit) -> Self {
+        Self {
+            limiter,
+            key,
+            rate_limit,
+        }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+}
+
+#[cfg(feature = "rate-limiter")]
+impl<Key, C, St, O> Bandwidth for SharedBandwidth<Key, C, St, O>
+where
+    Key: Send + Clone + Hash + Eq + Display + 'static,
+    C: Clock,
+    St: GcraStore<Key>,
+    O: Observer<Key>,
+{
+    fn burst(&self) -> u64 {
+        self.rate_limit.resource_limit_u64()
+    }
+
+    fn charge(&mut self, bytes: u64) -> io::Result<Option<Instant>> {
+        let now = self.limiter.clock().now();
+        let decision = self
+            .limiter
+            .decide_sync_at(self.key.clone(), &self.rate_limit, bytes, now)
+            .map_err(io::Error::other)?;
+        match decision.retry_after {
+            _ if decision.allowed => Ok(None),
+            Some(retry_after) => Ok(Some(now + retry_after)),
+            None => Err(io::Error::other(GcraError::denied_indefinitely(
+                bytes.into(),
+                decision.rate_limit,
+            ))),
+        }
+    }
+
+    fn refund(&mut self, bytes: u64) -> io::Result<()> {
+        let now = self.limiter.clock().now();
+        self.limiter
+            .revert_sync_at(self.key.clone(), &self.rate_limit, bytes, now)
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(feature = "rate-limiter")]
+impl<Key: Eq + Hash + fmt::Debug, C, St, O> fmt::Debug for SharedBandwidth<Key, C, St, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedBandwidth")
+            .field("key", &self.key)
+            .field("rate_limit", &self.rate_limit)
+            .finish()
+    }
+}
+
+/// A [Pacer] sleeping on tokio's timer while over the limit.
+struct AsyncPacer<B: Bandwidth> {
+    pacer: Pacer<B>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<B: Bandwidth> AsyncPacer<B> {
+    fn new(bandwidth: B) -> Self {
+        Self {
+            pacer: Pacer::new(bandwidth),
+            sleep: None,
+        }
+    }
+
+    /// How many of the `wanted` bytes may be moved, charging the next chunk if needed.
+    fn poll_allowance(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<io::Result<usize>> {
+        loop {
+            match self.pacer.allowance(wanted)? {
+                Allowance::Allowed(allowed) => return Poll::Ready(Ok(allowed)),
+                Allowance::DeniedUntil(next_allowed_at) => {
+                    let deadline = TokioInstant::from_std(next_allowed_at);
+                    let sleep = match &mut self.sleep {
+                        Some(sleep) => {
+                            sleep.as_mut().reset(deadline);
+                            sleep
+                        }
+                        None => self.sleep.insert(Box::pin(sleep_until(deadline))),
+                    };
+                    ready!(sleep.as_mut().poll(cx));
+                    // Already due, charge again
+                }
+            }
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.pacer.consume(bytes);
+    }
+
+    fn consume_read(&mut self, allowed: usize, read: usize) -> io::Result<()> {
+        self.pacer.consume_read(allowed, read)
+    }
+}
+
+impl<B: Bandwidth + fmt::Debug> fmt::Debug for AsyncPacer<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.pacer.fmt(f)
+    }
+}
+
+pin_project! {
+    /// Reader limiting the bytes read from the inner reader to its [Bandwidth].
+    #[derive(Debug)]
+    pub struct ThrottledReader<R, B: Bandwidth = RateLimitGuard> {
+        #[pin]
+        inner: R,
+        pacer: AsyncPacer<B>,
+    }
+}
+
+impl<R> ThrottledReader<R> {
+    /// Limits reads from `reader` to `rate_limit` bytes.
+    pub fn new(reader: R, rate_limit: RateLimit) -> Self {
+        Self::with_bandwidth(reader, RateLimitGuard::new_state(rate_limit))
+    }
+}
+
+impl<R, B: Bandwidth> ThrottledReader<R, B> {
+    /// Limits reads from `reader` to `bandwidth`, such as a [SharedBandwidth].
+    pub fn with_bandwidth(reader: R, bandwidth: B) -> Self {
+        Self {
+            inner: reader,
+            pacer: AsyncPacer::new(bandwidth),
+        }
+    }
+}
+
+impl<R, B: Bandwidth> ThrottledReader<R, B> {
+    pub fn bandwidth(&self) -> &B {
+        &self.pacer.pacer.bandwidth
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead, B: Bandwidth> AsyncRead for ThrottledReader<R, B> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let allowed = ready!(this.pacer.poll_allowance(cx, buf.remaining()))?;
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(allowed));
+        ready!(this.inner.poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        this.pacer.consume_read(allowed, read)?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<R: futures_io::AsyncRead, B: Bandwidth> futures_io::AsyncRead for ThrottledReader<R, B> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let allowed = ready!(this.pacer.poll_allowance(cx, buf.len()))?;
+        let read = ready!(this.inner.poll_read(cx, &mut buf[..allowed]))?;
+        this.pacer.consume_read(allowed, read)?;
+        Poll::Ready(Ok(read))
+    }
+}
+
+pin_project! {
+    /// Writer limiting the bytes written to the inner writer to its [Bandwidth].
+    #[derive(Debug)]
+    pub struct ThrottledWriter<W, B: Bandwidth = RateLimitGuard> {
+        #[pin]
+        inner: W,
+        pacer: AsyncPacer<B>,
+    }
+}
+
+impl<W> ThrottledWriter<W> {
+    /// Limits writes to `writer` to `rate_limit` bytes.
+    pub fn new(writer: W, rate_limit: RateLimit) -> Self {
+        Self::with_bandwidth(writer, RateLimitGuard::new_state(rate_limit))
+    }
+}
+
+impl<W, B: Bandwidth> ThrottledWriter<W, B> {
+    /// Limits writes to `writer` to `bandwidth`, such as a [SharedBandwidth].
+    pub fn with_bandwidth(writer: W, bandwidth: B) -> Self {
+        Self {
+            inner: writer,
+            pacer: AsyncPacer::new(bandwidth),
+        }
+    }
+}
+
+impl<W, B: Bandwidth> ThrottledWriter<W, B> {
+    pub fn bandwidth(&self) -> &B {
+        &self.pacer.pacer.bandwidth
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite, B: Bandwidth> AsyncWrite for ThrottledWriter<W, B> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let allowed = ready!(this.pacer.poll_allowance(cx, buf.len()))?;
+        let written = ready!(this.inner.poll_write(cx, &buf[..allowed]))?;
+        this.pacer.consume(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<W: futures_io::AsyncWrite, B: Bandwidth> futures_io::AsyncWrite for ThrottledWriter<W, B> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let allowed = ready!(this.pacer.poll_allowance(cx, buf.len()))?;
+        let written = ready!(this.inner.poll_write(cx, &buf[..allowed]))?;
+        this.pacer.consume(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::Instant,
+    };
+
+    use crate::{clock::TokioClock, GcraState};
+
+    use super::*;
+
+    /// 1000 bytes per second, with a burst of as many.
+    fn bandwidth() -> RateLimitGuard<TokioClock> {
+        RateLimitGuard::new(TokioClock, RateLimit::per_sec(1000), GcraState::default())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_reads() {
+        let data: Vec<u8> = (0..5000).map(|byte| byte as u8).collect();
+        let mut reader = ThrottledReader::with_bandwidth(data.as_slice(), bandwidth());
+
+        let start = Instant::now();
+        let mut read = vec![0; 5000];
+        reader.read_exact(&mut read).await.unwrap();
+
+        assert_eq!(data, read);
+        assert_eq!(Duration::from_secs(4), start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn splits_writes_into_chunks_within_the_burst() {
+        let mut writer = ThrottledWriter::with_bandwidth(Vec::new(), bandwidth());
+
+        let start = Instant::now();
+        let written = writer.write(&[1; 5000]).await.unwrap();
+        assert_eq!(1000, written, "A write moves at most the burst");
+
+        writer.write_all(&[1; 4000]).await.unwrap();
+        assert_eq!(5000, writer.get_ref().len());
+        assert_eq!(Duration::from_secs(4), start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_unused_charges_for_the_next_call() {
+        // The duplex only takes 300 bytes at a time
+        let (client, mut server) = tokio::io::duplex(300);
+        let mut writer = ThrottledWriter::with_bandwidth(client, bandwidth());
+        let reading = tokio::spawn(async move {
+            let mut read = Vec::new();
+            server.read_to_end(&mut read).await.unwrap();
+            read.len()
+        });
+
+        let start = Instant::now();
+        writer.write_all(&[1; 2000]).await.unwrap();
+        writer.shutdown().await.unwrap();
+        drop(writer);
+
+        assert_eq!(2000, reading.await.unwrap());
+        assert_eq!(Duration::from_secs(1), start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refunds_unused_charges_at_the_end_of_a_reader() {
+        let data = [1; 300];
+        let mut reader = ThrottledReader::with_bandwidth(data.as_slice(), bandwidth());
+
+        let mut read = [0; 1000];
+        assert_eq!(300, reader.read(&mut read).await.unwrap());
+        assert_eq!(0, reader.read(&mut read).await.unwrap());
+
+        assert_eq!(700, reader.bandwidth().remaining_resources());
+    }
+
+    #[cfg(feature = "rate-limiter")]
+    #[tokio::test(start_paused = true)]
+    async fn shares_bandwidth_by_key() {
+        let limiter: Arc<RateLimiter<String, _>> = Arc::new(RateLimiter::with_clock(TokioClock));
+        let upload = || {
+            let bandwidth = SharedBandwidth::new(
+                limiter.clone(),
+                "tenant".to_string(),
+                RateLimit::per_sec(1000),
+            );
+            let mut writer = ThrottledWriter::with_bandwidth(Vec::new(), bandwidth);
+            async move {
+                writer.write_all(&[1; 2000]).await.unwrap();
+                writer.into_inner().len()
+            }
+        };
+
+        let start = Instant::now();
+        let (first, second) = tokio::join!(upload(), upload());
+
+        assert_eq!((2000, 2000), (first, second));
+        assert_eq!(Duration::from_secs(3), start.elapsed());
+    }
+
+    #[cfg(feature = "rate-limiter")]
+    #[tokio::test(start_paused = true)]
+    async fn refunds_unused_charges_when_dropped() {
+        let limiter: Arc<RateLimiter<String, _>> = Arc::new(RateLimiter::with_clock(TokioClock));
+        let rate_limit = RateLimit::per_sec(1000);
+        let bandwidth =
+            SharedBandwidth::new(limiter.clone(), "tenant".to_string(), rate_limit.clone());
+        // The duplex only takes 300 bytes at a time
+        let (client, _server) = tokio::io::duplex(300);
+        let mut writer = ThrottledWriter::with_bandwidth(client, bandwidth);
+
+        assert_eq!(300, writer.write(&[1; 1000]).await.unwrap());
+        drop(writer);
+
+        let decision = limiter
+            .decide("tenant".to_string(), &rate_limit, 0)
+            .await
+            .unwrap();
+        assert_eq!(700, decision.remaining);
+    }
+
+    #[cfg(feature = "futures-io")]
+    #[tokio::test(start_paused = true)]
+    async fn paces_futures_io() {
+        use futures::{io::Cursor, AsyncWriteExt};
+
+        let mut writer = ThrottledWriter::with_bandwidth(Cursor::new(Vec::new()), bandwidth());
+
+        let start = Instant::now();
+        writer.write_all(&[1; 3000]).await.unwrap();
+
+        assert_eq!(3000, writer.get_ref().get_ref().len());
+        assert_eq!(Duration::from_secs(2), start.elapsed());
+    }
+}
@@ -0,0 +1,477 @@
+//! Keys limiting clients by IP address, independent of any web framework.
+//!
+//! Single IPv6 addresses make poor keys, as clients are usually handed a whole /64 or more.
+//! [IpPrefix] truncates addresses to the network prefix configured by [PrefixLengths], treating
+//! IPv4-mapped IPv6 addresses as the IPv4 address they stand for.
+//!
+//! Behind proxies, [ClientIp] finds the client's address in the `X-Forwarded-For` or `Forwarded`
+//! header, trusting only the hops added by the configured proxies:
+//!
+//! ```rust
+//! use std::net::IpAddr;
+//! use gcra::ip::{ClientIp, ForwardedHeader, PrefixLengths};
+//!
+//! let client_ip = ClientIp::new(PrefixLengths::new(24, 56).unwrap())
+//!     .with_forwarded(ForwardedHeader::XForwardedFor, ["10.0.0.0/8".parse().unwrap()]);
+//! let peer: IpAddr = "10.1.2.3".parse().unwrap();
+//! let key = client_ip.key(peer, [("X-Forwarded-For", "203.0.113.7, 10.4.5.6")]);
+//! assert_eq!("203.0.113.0/24", key.to_string());
+//! ```
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use thiserror::Error;
+
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
+pub const FORWARDED: &str = "forwarded";
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum IpPrefixError {
+    #[error("Invalid IP address: {0:?}")]
+    InvalidAddress(String),
+    #[error("Prefix length {len} exceeds the {max} bits of the address")]
+    InvalidLength { len: u8, max: u8 },
+}
+
+/// An IP network, i.e. an address with all bits past the prefix length cleared.
+///
+/// Displayed and parsed in CIDR notation, e.g. `2001:db8::/64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct IpPrefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl IpPrefix {
+    /// The network of `addr` with a prefix of `len` bits. IPv4-mapped IPv6 addresses are
+    /// converted to IPv4 first, with `len` still counted in IPv6 bits.
+    pub fn new(addr: IpAddr, len: u8) -> Result<Self, IpPrefixError> {
+        let (addr, len) = match addr {
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => (IpAddr::V4(v4), len.saturating_sub(96)),
+                None => (addr, len),
+            },
+            IpAddr::V4(_) => (addr, len),
+        };
+        let max = max_len(addr);
+        if len > max {
+            return Err(IpPrefixError::InvalidLength { len, max });
+        }
+        let addr = match addr {
+            IpAddr::V4(v4) => {
+                let mask = u32::MAX.checked_shl(u32::from(32 - len)).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+            }
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX.checked_shl(u32::from(128 - len)).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+        };
+        Ok(Self { addr, len })
+    }
+
+    /// The first address of the network.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn len(&self) -> u8 {
+        self.len
+    }
+
+    /// Whether the prefix is empty and matches every address of its family.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether `addr` is part of this network.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        addr.is_ipv4() == self.addr.is_ipv4()
+            && IpPrefix::new(addr, self.len).is_ok_and(|network| network == *self)
+    }
+}
+
+fn max_len(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+impl From<IpAddr> for IpPrefix {
+    /// The network of the single address `addr`.
+    fn from(addr: IpAddr) -> Self {
+        let addr = addr.to_canonical();
+        Self {
+            addr,
+            len: max_len(addr),
+        }
+    }
+}
+
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+impl FromStr for IpPrefix {
+    type Err = IpPrefixError;
+
+    /// Parses CIDR notation, or a single address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || IpPrefixError::InvalidAddress(s.to_string());
+        let Some((addr, len)) = s.split_once('/') else {
+            return s.parse::<IpAddr>().map(Self::from).map_err(|_| invalid());
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let len = len.parse().map_err(|_| invalid())?;
+        let max = max_len(addr);
+        if len > max {
+            return Err(IpPrefixError::InvalidLength { len, max });
+        }
+        Self::new(addr, len)
+    }
+}
+
+/// How many leading bits of IPv4 and IPv6 addresses identify a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixLengths {
+    v4: u8,
+    v6: u8,
+}
+
+impl Default for PrefixLengths {
+    /// Whole IPv4 addresses and IPv6 /64s, the smallest network usually assigned to a client.
+    fn default() -> Self {
+        Self { v4: 32, v6: 64 }
+    }
+}
+
+impl PrefixLengths {
+    pub fn new(v4: u8, v6: u8) -> Result<Self, IpPrefixError> {
+        if v4 > 32 {
+            return Err(IpPrefixError::InvalidLength { len: v4, max: 32 });
+        }
+        if v6 > 128 {
+            return Err(IpPrefixError::InvalidLength { len: v6, max: 128 });
+        }
+        Ok(Self { v4, v6 })
+    }
+
+    pub fn v4(&self) -> u8 {
+        self.v4
+    }
+
+    pub fn v6(&self) -> u8 {
+        self.v6
+    }
+
+    /// The network `addr` is keyed by.
+    pub fn prefix(&self, addr: IpAddr) -> IpPrefix {
+        let addr = addr.to_canonical();
+        let len = match addr {
+            IpAddr::V4(_) => self.v4,
+            IpAddr::V6(_) => self.v6,
+        };
+        IpPrefix::new(addr, len).expect("Prefix lengths are validated on construction")
+    }
+}
+
+/// Header proxies record the addresses they forward requests for in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For: <client>, <proxy1>, <proxy2>`
+    XForwardedFor,
+    /// `Forwarded: for=<client>, for=<proxy1>` of [RFC 7239](https://www.rfc-editor.org/rfc/rfc7239)
+    Forwarded,
+}
+
+impl ForwardedHeader {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ForwardedHeader::XForwardedFor => X_FORWARDED_FOR,
+            ForwardedHeader::Forwarded => FORWARDED,
+        }
+    }
+
+    /// The addresses of one header line, in the order the hops were added. Hops without a
+    /// usable address, e.g. `for=unknown`, are `None`.
+    fn hops<'a>(&self, value: &'a str) -> impl Iterator<Item = Option<IpAddr>> + 'a {
+        let forwarded = *self == ForwardedHeader::Forwarded;
+        value.split(',').map(move |element| {
+            if !forwarded {
+                return parse_node(element);
+            }
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_node(node))
+        })
+    }
+}
+
+/// Parses an address, optionally quoted and with a port, e.g. `"[2001:db8::1]:4711"`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(bracketed) = node.strip_prefix('[') {
+        let (addr, _port) = bracketed.split_once(']')?;
+        return addr.parse().ok();
+    }
+    node.parse()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+}
+
+/// Finds the address of the client behind a request and the [IpPrefix] it is keyed by.
+///
+/// Without a [ForwardedHeader], or when the peer isn't a trusted proxy, the client is the peer.
+/// Otherwise the forwarded hops are walked from the closest one, and the client is the first
+/// address not belonging to a trusted proxy, or the furthest one if all do.
+///
+/// Hops are only as trustworthy as the proxy which added them, so the walk stops at the last
+/// trusted proxy when the next hop is malformed, and headers should only be configured if every
+/// trusted proxy overwrites or appends to them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientIp {
+    lengths: PrefixLengths,
+    forwarded: Option<ForwardedHeader>,
+    trusted_proxies: Vec<IpPrefix>,
+}
+
+impl ClientIp {
+    pub fn new(lengths: PrefixLengths) -> Self {
+        Self {
+            lengths,
+            ..Self::default()
+        }
+    }
+
+    /// Reads the client's address from `header` when forwarded by one of `trusted_proxies`.
+    pub fn with_forwarded(
+        mut self,
+        header: ForwardedHeader,
+        trusted_proxies: impl IntoIterator<Item = IpPrefix>,
+    ) -> Self {
+        self.forwarded = Some(header);
+        self.trusted_proxies = trusted_proxies.into_iter().collect();
+        self
+    }
+
+    pub fn lengths(&self) -> PrefixLengths {
+        self.lengths
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|proxy| proxy.contains(addr))
+    }
+
+    /// The client's address, given the address of the `peer` the request came from and the
+    /// request's `headers` as name and value pairs.
+    pub fn client_addr<'a>(
+        &self,
+        peer: IpAddr,
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> IpAddr {
+        let Some(forwarded) = self.forwarded else {
+            return peer;
+        };
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let hops: Vec<_> = headers
+            .into_iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(forwarded.name()))
+            .flat_map(|(_, value)| forwarded.hops(value))
+            .collect();
+
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            let Some(hop) = hop else {
+                break;
+            };
+            client = hop;
+            if !self.is_trusted(hop) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// The [IpPrefix] of the client's address, see [ClientIp::client_addr].
+    pub fn key<'a>(
+        &self,
+        peer: IpAddr,
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> IpPrefix {
+        self.lengths.prefix(self.client_addr(peer, headers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn prefix(network: &str) -> IpPrefix {
+        network.parse().unwrap()
+    }
+
+    #[test]
+    fn prefixes() {
+        let lengths = PrefixLengths::new(24, 56).unwrap();
+        assert_eq!(prefix("203.0.113.0/24"), lengths.prefix(ip("203.0.113.77")));
+        assert_eq!(
+            prefix("2001:db8:aa:bb00::/56"),
+            lengths.prefix(ip("2001:db8:aa:bbcc:1:2:3:4"))
+        );
+        assert_eq!(
+            lengths.prefix(ip("203.0.113.77")),
+            lengths.prefix(ip("::ffff:203.0.113.1")),
+            "IPv4-mapped addresses are keyed as IPv4"
+        );
+
+        let defaults = PrefixLengths::default();
+        assert_eq!(
+            prefix("203.0.113.77/32"),
+            defaults.prefix(ip("203.0.113.77"))
+        );
+        assert_eq!(
+            defaults.prefix(ip("2001:db8::1")),
+            defaults.prefix(ip("2001:db8::ffff:2"))
+        );
+        assert_eq!(
+            "2001:db8::/64",
+            defaults.prefix(ip("2001:db8::1")).to_string()
+        );
+
+        assert_eq!(
+            Err(IpPrefixError::InvalidLength { len: 33, max: 32 }),
+            PrefixLengths::new(33, 64)
+        );
+    }
+
+    #[test]
+    fn parse_and_contains() {
+        let network = prefix("10.1.2.3/8");
+        assert_eq!(ip("10.0.0.0"), network.addr());
+        assert_eq!(8, network.len());
+        assert!(network.contains(ip("10.255.0.1")));
+        assert!(network.contains(ip("::ffff:10.0.0.1")));
+        assert!(!network.contains(ip("11.0.0.1")));
+        assert!(!network.contains(ip("::a00:1")), "Families don't mix");
+
+        assert_eq!(prefix("10.0.0.0/8"), prefix("::ffff:10.0.0.0/104"));
+        assert_eq!(prefix("2001:db8::1/128"), prefix("2001:db8::1"));
+        assert!(prefix("0.0.0.0/0").is_empty());
+        assert_eq!(
+            Err(IpPrefixError::InvalidLength { len: 33, max: 32 }),
+            "10.0.0.0/33".parse::<IpPrefix>()
+        );
+        assert_eq!(
+            Err(IpPrefixError::InvalidAddress("proxy/8".to_string())),
+            "proxy/8".parse::<IpPrefix>()
+        );
+    }
+
+    #[test]
+    fn client_addr_from_x_forwarded_for() {
+        let client_ip = ClientIp::default().with_forwarded(
+            ForwardedHeader::XForwardedFor,
+            [prefix("10.0.0.0/8"), prefix("2001:db8:ff::/48")],
+        );
+        let proxy = ip("10.0.0.1");
+
+        assert_eq!(
+            ip("203.0.113.7"),
+            client_ip.client_addr(proxy, [("X-Forwarded-For", "198.51.100.1, 203.0.113.7")]),
+            "The closest untrusted hop is the client, earlier ones may be spoofed"
+        );
+        assert_eq!(
+            ip("203.0.113.7"),
+            client_ip.client_addr(
+                proxy,
+                [
+                    ("x-forwarded-for", "203.0.113.7"),
+                    ("Content-Type", "text/plain"),
+                    ("x-forwarded-for", "10.9.9.9, [2001:db8:ff::1]:443"),
+                ]
+            ),
+            "Trusted hops are skipped across header lines"
+        );
+        assert_eq!(
+            ip("10.0.0.3"),
+            client_ip.client_addr(proxy, [("X-Forwarded-For", "10.0.0.3, 10.0.0.2")]),
+            "The furthest hop is the client when all are trusted"
+        );
+        assert_eq!(
+            ip("10.9.9.9"),
+            client_ip.client_addr(
+                proxy,
+                [("X-Forwarded-For", "203.0.113.7, garbage, 10.9.9.9")]
+            ),
+            "Stops at the last trusted hop before a malformed one"
+        );
+        assert_eq!(proxy, client_ip.client_addr(proxy, []));
+
+        let peer = ip("198.51.100.1");
+        assert_eq!(
+            peer,
+            client_ip.client_addr(peer, [("X-Forwarded-For", "203.0.113.7")]),
+            "Headers sent by untrusted peers are ignored"
+        );
+        assert_eq!(
+            ip("203.0.113.7"),
+            ClientIp::default().client_addr(ip("203.0.113.7"), [("X-Forwarded-For", "1.1.1.1")]),
+            "Headers are ignored unless configured"
+        );
+    }
+
+    #[test]
+    fn client_addr_from_forwarded() {
+        let client_ip = ClientIp::new(PrefixLengths::new(32, 64).unwrap())
+            .with_forwarded(ForwardedHeader::Forwarded, [prefix("10.0.0.0/8")]);
+        let proxy = ip("10.0.0.1");
+
+        assert_eq!(
+            ip("2001:db8:cafe::17"),
+            client_ip.client_addr(
+                proxy,
+                [(
+                    "Forwarded",
+                    r#"for=192.0.2.43, for="[2001:db8:cafe::17]:4711";proto=https, for=10.1.1.1"#
+                )]
+            )
+        );
+        assert_eq!(
+            ip("192.0.2.60"),
+            client_ip.client_addr(
+                proxy,
+                [(
+                    "Forwarded",
+                    "proto=http;For=\"192.0.2.60:8080\";by=10.0.0.1"
+                )]
+            )
+        );
+        assert_eq!(
+            proxy,
+            client_ip.client_addr(proxy, [("Forwarded", "for=unknown")]),
+            "Obfuscated hops can't be keyed"
+        );
+        assert_eq!(
+            prefix("2001:db8:cafe::/64"),
+            client_ip.key(proxy, [("Forwarded", r#"for="[2001:db8:cafe::17]""#)])
+        );
+    }
+}
@@ -4,6 +4,34 @@
 //! # Features
 //! - `rate-limiter` a LRU + expiring rate limiter. Implements `Send + Sync` so
 //!   can be used asynchronously.
+//! - `metrics` records what the rate limiter is doing through the
+//!   [metrics](https://docs.rs/metrics) facade.
+//! - `tracing` emits [tracing](https://docs.rs/tracing) spans and events explaining each decision.
+//!   Allowed checks are logged at `TRACE`, denials at `DEBUG`.
+//! - `config` loads named policies for the rate limiter from TOML or JSON files, with hot reloading.
+//! - `redis` adds `RedisStore`, sharing the rate limiter's state between processes through Redis.
+//!   Each check runs atomically in a Lua script on the server.
+//! - `server` adds the `resp` module and the `gcra-resp-server` binary, a drop-in replacement for
+//!   Redis with the redis-cell module serving `CL.THROTTLE`.
+//! - `http-server` adds the `http_server` module and the `gcra-http-server` binary, checking
+//!   keys against policies loaded from a config file over HTTP/JSON.
+//! - `tokio` adds `clock::TokioClock`, following tokio's (pausable) time.
+//! - `tower` adds the `tower` module with `GcraLayer`, rate limiting any tower `Service`.
+//! - `http` adds the `http` module with `GcraHttpLayer`, answering over the limit requests to
+//!   http services such as axum routers with `429 Too Many Requests` and setting `RateLimit-*`
+//!   headers.
+//! - `tonic` adds the `tonic` module with `GcraGrpcLayer`, denying over the limit gRPC calls with
+//!   `RESOURCE_EXHAUSTED` and retry info.
+//! - `actix` adds the `actix` module with `GcraMiddleware`, the same for actix-web apps, with
+//!   per-route policies.
+//! - `stream` adds the `stream` module with `ThrottleExt::throttle`, pacing the items of any
+//!   `Stream` by delaying or dropping them, and `ThrottleExt::throttle_by_key` doing the same per
+//!   key of the items.
+//! - `io` adds the `io` module with `ThrottledReader` and `ThrottledWriter`, limiting the
+//!   bandwidth of tokio's `AsyncRead` and `AsyncWrite`, alone or shared per key of a rate limiter.
+//!   `futures-io` implements the `futures-io` traits as well.
+//! - `envoy` adds the `envoy` module and the `gcra-envoy-rls` binary, implementing Envoy's global
+//!   rate limit service over gRPC with descriptors mapped to policies from a config file.
 //!
 //! # Usage
 //!
@@ -28,10 +56,10 @@
 //!
 //! ```rust
 //! use std::sync::Arc;
-//! use gcra::{GcraError, RateLimit, RateLimiter};
+//! use gcra::{GcraError, RateLimit, RateLimiter, RateLimiterError};
 //!
 //! #[tokio::main]
-//! async fn main() -> Result<(), GcraError> {
+//! async fn main() -> Result<(), RateLimiterError> {
 //!     let rate_limit = RateLimit::per_sec(2);
 //!     let rate_limiter = Arc::new(RateLimiter::new(4));
 //!
@@ -39,7 +67,7 @@
 //!     rate_limiter.check("key", &rate_limit, 1).await?;
 //!
 //!     match rate_limiter.check("key", &rate_limit, 1).await {
-//!         Err(GcraError::DeniedUntil { next_allowed_at }) => {
+//!         Err(RateLimiterError::Gcra(GcraError::DeniedUntil { next_allowed_at })) => {
 //!             print!("Denied: Request next at {:?}", next_allowed_at);
 //!             Ok(())
 //!         }
@@ -48,15 +76,49 @@
 //! }
 //! ```
 
+#[cfg(feature = "actix")]
+pub mod actix;
+pub mod blocking;
 pub mod clock;
+#[cfg(feature = "config")]
+pub mod config;
+mod cost;
+#[cfg(feature = "envoy")]
+pub mod envoy;
 mod gcra;
+pub mod headers;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "http-server")]
+pub mod http_server;
+#[cfg(feature = "io")]
+pub mod io;
+pub mod ip;
+mod pacer;
 mod rate_limit;
 mod rate_limit_guard;
 #[cfg(feature = "rate-limiter")]
 mod rate_limiter;
+#[cfg(feature = "server")]
+pub mod resp;
+#[cfg(feature = "stream")]
+pub mod stream;
+mod throttle;
+#[cfg(feature = "tonic")]
+pub mod tonic;
+#[cfg(feature = "tower")]
+pub mod tower;
 
-pub use crate::gcra::{GcraError, GcraState};
-pub use crate::rate_limit::RateLimit;
+pub use crate::cost::{Cost, IntoCost, ParseCostError};
+pub use crate::gcra::{GcraError, GcraState, RescaleMode};
+pub use crate::rate_limit::{ParseRateLimitError, RateLimit};
 pub use crate::rate_limit_guard::RateLimitGuard;
 #[cfg(feature = "rate-limiter")]
-pub use crate::rate_limiter::{RateLimitEntry, RateLimitRequest, RateLimiter};
+pub use crate::rate_limiter::{
+    CheckEvent, DashMapStore, Decision, GcraStore, MemoryStore, NoopObserver, Observer,
+    PolicyChange, RateLimitEntry, RateLimitRequest, RateLimiter, RateLimiterError,
+    RateLimiterStats, StoreError, StoredTat,
+};
+#[cfg(feature = "redis")]
+pub use crate::rate_limiter::{RedisStore, DEFAULT_REDIS_PREFIX};
+pub use crate::throttle::{InvalidThrottleError, ThrottleResult};
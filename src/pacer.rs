@@ -0,0 +1,114 @@
+use std::{io, time::Instant};
+
+use crate::{clock::Clock, GcraError, RateLimitGuard};
+
+/// Where the bytes moved by a throttled reader or writer are charged.
+pub trait Bandwidth {
+    /// The most bytes a single charge can be allowed for.
+    fn burst(&self) -> u64;
+
+    /// Charges `bytes`. While over the limit, returns when the charge can be retried instead.
+    fn charge(&mut self, bytes: u64) -> io::Result<Option<Instant>>;
+
+    /// Gives back `bytes` that were charged but never moved.
+    fn refund(&mut self, bytes: u64) -> io::Result<()>;
+}
+
+impl<C: Clock> Bandwidth for RateLimitGuard<C> {
+    fn burst(&self) -> u64 {
+        self.rate_limit().resource_limit_u64()
+    }
+
+    fn charge(&mut self, bytes: u64) -> io::Result<Option<Instant>> {
+        match self.check_and_modify(bytes) {
+            Ok(()) => Ok(None),
+            Err(GcraError::DeniedUntil { next_allowed_at }) => Ok(Some(next_allowed_at)),
+            Err(err) => Err(io::Error::other(err)),
+        }
+    }
+
+    fn refund(&mut self, bytes: u64) -> io::Result<()> {
+        self.revert(bytes).map_err(io::Error::other)
+    }
+}
+
+/// How many bytes a throttled reader or writer may move.
+pub(crate) enum Allowance {
+    Allowed(usize),
+    /// Over the limit, the next chunk can be charged after the [Instant].
+    DeniedUntil(Instant),
+}
+
+/// Charges chunks of bytes to a [Bandwidth], leaving the sleeping while over the limit to the
+/// blocking and async readers and writers.
+///
+/// Bytes are charged up front, in chunks of at most the burst so that no chunk is denied
+/// indefinitely. Charged bytes a read or write didn't move are kept for the next one, and given
+/// back once the reader reaches its end or the pacer is dropped.
+#[derive(Debug)]
+pub(crate) struct Pacer<B: Bandwidth> {
+    pub(crate) bandwidth: B,
+    // Bytes charged but not moved yet
+    credit: usize,
+}
+
+impl<B: Bandwidth> Pacer<B> {
+    pub(crate) fn new(bandwidth: B) -> Self {
+        Self {
+            bandwidth,
+            credit: 0,
+        }
+    }
+
+    /// How many of the `wanted` bytes may be moved now, charging the next chunk if needed.
+    pub(crate) fn allowance(&mut self, wanted: usize) -> io::Result<Allowance> {
+        if wanted == 0 || self.credit > 0 {
+            return Ok(Allowance::Allowed(self.credit.min(wanted)));
+        }
+
+        let burst = self.bandwidth.burst();
+        if burst == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rate limit allows no bytes",
+            ));
+        }
+        let chunk = u64::try_from(wanted).map_or(burst, |wanted| wanted.min(burst));
+        Ok(match self.bandwidth.charge(chunk)? {
+            None => {
+                self.credit = chunk as usize;
+                Allowance::Allowed(self.credit)
+            }
+            Some(next_allowed_at) => Allowance::DeniedUntil(next_allowed_at),
+        })
+    }
+
+    pub(crate) fn consume(&mut self, bytes: usize) {
+        self.credit -= bytes;
+    }
+
+    /// Gives the bytes charged but not moved back to the [Bandwidth].
+    pub(crate) fn refund(&mut self) -> io::Result<()> {
+        match std::mem::take(&mut self.credit) {
+            0 => Ok(()),
+            credit => self.bandwidth.refund(credit as u64),
+        }
+    }
+
+    /// Same as [Pacer::consume], refunding the rest of the credit if a read `allowed` some bytes
+    /// moved none, i.e. reached the end of the reader.
+    pub(crate) fn consume_read(&mut self, allowed: usize, read: usize) -> io::Result<()> {
+        self.consume(read);
+        if allowed > 0 && read == 0 {
+            self.refund()?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: Bandwidth> Drop for Pacer<B> {
+    fn drop(&mut self) {
+        // Nowhere to report a failure to
+        let _ = self.refund();
+    }
+}
@@ -1,35 +1,177 @@
-use std::time::Duration;
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use thiserror::Error;
+
+use crate::{Cost, IntoCost};
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 /// Defines the configuration for a GCRA rate limit.
 pub struct RateLimit {
-    // Amount of resources that are allowed in a given period.
+    // Amount of resources that are allowed in a given period, saturating at `u32::MAX` for
+    // limits made with [RateLimit::new_u64].
     pub resource_limit: u32,
     // The length of which to allow access to the resource.
     pub period: Duration,
 
     /// Incremental duration cost of a single resource check
     pub emission_interval: Duration,
+
+    /// The exact `resource_limit`, which checks go by.
+    limit: u64,
 }
 
 impl RateLimit {
+    #[inline]
     pub fn new(resource_limit: u32, period: Duration) -> Self {
-        let emission_interval = period / resource_limit;
+        Self::new_u64(u64::from(resource_limit), period)
+    }
+
+    /// Same as [RateLimit::new], for limits above `u32::MAX`.
+    pub fn new_u64(resource_limit: u64, period: Duration) -> Self {
+        let emission_interval = nanos_to_duration(period.as_nanos() / u128::from(resource_limit));
         Self {
-            resource_limit,
+            resource_limit: u32::try_from(resource_limit).unwrap_or(u32::MAX),
             period,
             emission_interval,
+            limit: resource_limit,
         }
     }
 
+    /// Amount of resources that are allowed in a given period, including limits above
+    /// `u32::MAX`.
+    #[inline]
+    pub fn resource_limit_u64(&self) -> u64 {
+        self.limit
+    }
+
     #[inline]
     pub fn per_sec(resource_limit: u32) -> Self {
         Self::new(resource_limit, Duration::from_secs(1))
     }
 
+    /// Same as [RateLimit::per_sec], for limits above `u32::MAX`.
+    #[inline]
+    pub fn per_sec_u64(resource_limit: u64) -> Self {
+        Self::new_u64(resource_limit, Duration::from_secs(1))
+    }
+
     /// Given a `cost`, calculates the increment interval.
-    pub fn increment_interval(&self, cost: u32) -> Duration {
-        self.emission_interval * cost
+    ///
+    /// Computed as `period * cost / resource_limit` without going through the truncated
+    /// `emission_interval`, so a cost of `resource_limit` takes exactly `period`. Saturates at
+    /// [Duration::MAX].
+    pub fn increment_interval(&self, cost: impl IntoCost) -> Duration {
+        let limit_parts = Cost::units(self.limit).parts();
+        match self.period.as_nanos().checked_mul(cost.into_cost().parts()) {
+            Some(nanos) if limit_parts > 0 => nanos_to_duration(nanos / limit_parts),
+            _ => Duration::MAX,
+        }
+    }
+}
+
+/// Converts nanoseconds to a [Duration], saturating at [Duration::MAX].
+pub(crate) fn nanos_to_duration(nanos: u128) -> Duration {
+    const NANOS_PER_SEC: u128 = 1_000_000_000;
+    match u64::try_from(nanos / NANOS_PER_SEC) {
+        Ok(secs) => Duration::new(secs, (nanos % NANOS_PER_SEC) as u32),
+        Err(_) => Duration::MAX,
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseRateLimitError {
+    /// Rate limits are written as `<resource_limit>/<period>`, e.g. `100/1m`
+    #[error("Expected `<resource_limit>/<period>` but found {0:?}")]
+    InvalidFormat(String),
+    #[error("Invalid resource limit {0:?}")]
+    InvalidResourceLimit(String),
+    #[error("Invalid period {0:?}")]
+    InvalidPeriod(String),
+    /// The period must allow at least 1ns between each resource
+    #[error("Period is too short for the resource limit")]
+    PeriodTooShort,
+}
+
+/// Supported period units, largest first so [Display] picks the most compact representation.
+const PERIOD_UNITS: [(&str, Duration); 7] = [
+    ("d", Duration::from_secs(24 * 60 * 60)),
+    ("h", Duration::from_secs(60 * 60)),
+    ("m", Duration::from_secs(60)),
+    ("s", Duration::from_secs(1)),
+    ("ms", Duration::from_millis(1)),
+    ("us", Duration::from_micros(1)),
+    ("ns", Duration::from_nanos(1)),
+];
+
+/// Parses the `<resource_limit>/<period>` grammar, e.g. `100/1m`, `5/s` or `10/500ms`.
+///
+/// The period is an optional count followed by one of `ns`, `us`, `ms`, `s`, `m`, `h` or `d`.
+impl FromStr for RateLimit {
+    type Err = ParseRateLimitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (resource_limit, period) = s
+            .split_once('/')
+            .ok_or_else(|| ParseRateLimitError::InvalidFormat(s.to_string()))?;
+
+        let resource_limit = resource_limit.trim();
+        let resource_limit = match resource_limit.parse::<u64>() {
+            Ok(resource_limit) if resource_limit > 0 => resource_limit,
+            _ => {
+                return Err(ParseRateLimitError::InvalidResourceLimit(
+                    resource_limit.to_string(),
+                ))
+            }
+        };
+
+        let period = period.trim();
+        let invalid_period = || ParseRateLimitError::InvalidPeriod(period.to_string());
+        let unit_start = period
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid_period)?;
+        let (count, unit) = period.split_at(unit_start);
+        let count = match count {
+            "" => 1,
+            count => count.parse::<u128>().map_err(|_| invalid_period())?,
+        };
+        let (_, unit) = PERIOD_UNITS
+            .iter()
+            .find(|(name, _)| *name == unit.trim())
+            .ok_or_else(invalid_period)?;
+        let period = unit
+            .as_nanos()
+            .checked_mul(count)
+            .and_then(|nanos| {
+                let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+                Some(Duration::new(secs, (nanos % 1_000_000_000) as u32))
+            })
+            .ok_or_else(invalid_period)?;
+        if period.is_zero() {
+            return Err(invalid_period());
+        }
+        if period < Duration::from_nanos(resource_limit) {
+            return Err(ParseRateLimitError::PeriodTooShort);
+        }
+
+        Ok(RateLimit::new_u64(resource_limit, period))
+    }
+}
+
+/// Formats using the same grammar accepted by [FromStr], in the largest unit dividing the period.
+impl Display for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let period_nanos = self.period.as_nanos();
+        let (name, unit) = PERIOD_UNITS
+            .iter()
+            .find(|(_, unit)| period_nanos % unit.as_nanos() == 0)
+            .unwrap_or(&PERIOD_UNITS[PERIOD_UNITS.len() - 1]);
+        write!(
+            f,
+            "{}/{}{}",
+            self.limit,
+            period_nanos / unit.as_nanos(),
+            name
+        )
     }
 }
 
@@ -42,4 +184,105 @@ mod tests {
         let rate_limit = RateLimit::new(10, Duration::from_secs(20));
         assert_eq!(Duration::from_secs(2), rate_limit.emission_interval)
     }
+
+    #[test]
+    fn rate_limit_increment_interval_is_exact() {
+        let rate_limit = RateLimit::per_sec(3);
+        assert_eq!(
+            Duration::from_nanos(333_333_333),
+            rate_limit.emission_interval
+        );
+        assert_eq!(Duration::from_secs(1), rate_limit.increment_interval(3));
+
+        // 50 GB per day
+        let rate_limit = RateLimit::new_u64(50_000_000_000, Duration::from_secs(24 * 60 * 60));
+        assert_eq!(u32::MAX, rate_limit.resource_limit, "Saturates as a u32");
+        assert_eq!(50_000_000_000, rate_limit.resource_limit_u64());
+        assert_eq!(
+            Duration::from_secs(12 * 60 * 60),
+            rate_limit.increment_interval(Cost::units(25_000_000_000))
+        );
+        assert_eq!(
+            Duration::from_millis(250),
+            RateLimit::per_sec(10).increment_interval(Cost::from_parts(2_500_000))
+        );
+        assert_eq!(
+            Duration::MAX,
+            RateLimit::new(1, Duration::MAX).increment_interval(2),
+            "Saturates instead of overflowing"
+        );
+    }
+
+    #[test]
+    fn rate_limit_from_str() {
+        assert_eq!(Ok(RateLimit::per_sec(5)), "5/s".parse());
+        assert_eq!(
+            Ok(RateLimit::new(100, Duration::from_secs(60))),
+            "100/1m".parse()
+        );
+        assert_eq!(
+            Ok(RateLimit::new(10, Duration::from_millis(500))),
+            " 10 / 500ms ".parse()
+        );
+        assert_eq!(
+            Ok(RateLimit::new(1, Duration::from_nanos(1_500))),
+            "1/1500ns".parse()
+        );
+        assert_eq!(
+            Ok(RateLimit::new(1000, Duration::from_secs(2 * 24 * 60 * 60))),
+            "1000/2d".parse()
+        );
+
+        assert_eq!(
+            Ok(RateLimit::new_u64(
+                50_000_000_000,
+                Duration::from_secs(24 * 60 * 60)
+            )),
+            "50000000000/1d".parse()
+        );
+
+        assert_eq!(
+            Err(ParseRateLimitError::InvalidFormat("100".to_string())),
+            "100".parse::<RateLimit>()
+        );
+        assert_eq!(
+            Err(ParseRateLimitError::InvalidResourceLimit("0".to_string())),
+            "0/1s".parse::<RateLimit>()
+        );
+        assert_eq!(
+            Err(ParseRateLimitError::InvalidPeriod("1w".to_string())),
+            "10/1w".parse::<RateLimit>()
+        );
+        assert_eq!(
+            Err(ParseRateLimitError::InvalidPeriod("0s".to_string())),
+            "10/0s".parse::<RateLimit>()
+        );
+    }
+
+    #[test]
+    fn rate_limit_display_round_trips() {
+        for rate_limit in [
+            "5/1s",
+            "100/1m",
+            "10/500ms",
+            "7/90s",
+            "3/36h",
+            "10/1500us",
+            "1/7ns",
+        ] {
+            assert_eq!(
+                rate_limit,
+                rate_limit.parse::<RateLimit>().unwrap().to_string()
+            );
+        }
+
+        for period in [
+            Duration::from_nanos(1_500_001),
+            Duration::from_secs(36 * 60 * 60) + Duration::from_nanos(1),
+            Duration::MAX,
+        ] {
+            let rate_limit = RateLimit::new(1, period);
+            assert_eq!(Ok(rate_limit.clone()), rate_limit.to_string().parse());
+        }
+    }
 }
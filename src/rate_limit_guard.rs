@@ -2,7 +2,7 @@ use std::ops::Deref;
 
 use crate::{
     clock::{Clock, InstantClock},
-    GcraError, GcraState, RateLimit,
+    Cost, GcraError, GcraState, IntoCost, RateLimit,
 };
 
 /// A simple wrapper to help make using [RateLimit]s with [GcraState]s easier for basic cases.
@@ -31,8 +31,25 @@ impl<C: Clock> RateLimitGuard<C> {
         }
     }
 
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn rate_limit(&self) -> &RateLimit {
+        &self.rate_limit
+    }
+
     /// Check if we are allowed to proceed. If so updated our internal state and return true.
-    pub fn check_and_modify(&mut self, cost: u32) -> Result<(), GcraError> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "rate_limit_guard",
+            level = "debug",
+            skip(self, cost),
+            fields(rate_limit = %self.rate_limit, cost = %cost.into_cost())
+        )
+    )]
+    pub fn check_and_modify(&mut self, cost: impl IntoCost) -> Result<(), GcraError> {
         let RateLimitGuard {
             clock,
             rate_limit,
@@ -48,8 +65,31 @@ impl<C: Clock> RateLimitGuard<C> {
             .remaining_resources(&self.rate_limit, self.clock.now())
     }
 
+    /// Get the remaining whole resources at the current moment in time, for limits above
+    /// `u32::MAX`.
+    pub fn remaining_resources_u64(&self) -> u64 {
+        self.state
+            .remaining_resources_u64(&self.rate_limit, self.clock.now())
+    }
+
+    /// Get the remaining resources, including fractions of a resource, at the current moment in
+    /// time.
+    pub fn remaining_cost(&self) -> Cost {
+        self.state
+            .remaining_cost(&self.rate_limit, self.clock.now())
+    }
+
     /// Reverts rate_limit by cost, and update our internal state.
-    pub fn revert(&mut self, cost: u32) -> Result<(), GcraError> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "rate_limit_guard_revert",
+            level = "debug",
+            skip(self, cost),
+            fields(rate_limit = %self.rate_limit, cost = %cost.into_cost())
+        )
+    )]
+    pub fn revert(&mut self, cost: impl IntoCost) -> Result<(), GcraError> {
         let RateLimitGuard {
             clock,
             rate_limit,
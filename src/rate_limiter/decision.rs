@@ -0,0 +1,65 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{rate_limiter::RateLimiterError, GcraError, GcraState, RateLimit};
+
+/// Outcome of a [RateLimiter::decide](crate::RateLimiter::decide) along with what is left of the
+/// key's limit, e.g. to fill in rate limit headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    /// The policy the key was checked under, unset for ad-hoc [RateLimit] checks.
+    pub policy: Option<Arc<str>>,
+    pub rate_limit: RateLimit,
+    /// Resources left right after the check.
+    pub remaining: u64,
+    /// Time until the key's limit is fully replenished.
+    pub reset_after: Duration,
+    /// Time until the check can succeed when denied. Unset if allowed, or if the cost exceeds the
+    /// limit and will never succeed.
+    pub retry_after: Option<Duration>,
+}
+
+impl Decision {
+    /// Builds the decision from the `state` left after a check, turning denials into decisions.
+    pub(super) fn from_check(
+        policy: Option<Arc<str>>,
+        rate_limit: RateLimit,
+        state: &GcraState,
+        now: Instant,
+        result: Result<Instant, RateLimiterError>,
+    ) -> Result<Self, RateLimiterError> {
+        let (allowed, retry_after) = match result {
+            Ok(_) => (true, None),
+            Err(RateLimiterError::Gcra(GcraError::DeniedUntil { next_allowed_at })) => {
+                (false, Some(next_allowed_at.saturating_duration_since(now)))
+            }
+            Err(RateLimiterError::Gcra(GcraError::DeniedIndefinitely { .. })) => (false, None),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            allowed,
+            remaining: state.remaining_resources_u64(&rate_limit, now),
+            reset_after: state
+                .tat
+                .map_or(Duration::ZERO, |tat| tat.saturating_duration_since(now)),
+            policy,
+            rate_limit,
+            retry_after,
+        })
+    }
+
+    /// Total resources of the limit.
+    #[inline]
+    pub fn limit(&self) -> u64 {
+        self.rate_limit.resource_limit_u64()
+    }
+
+    /// Whether the cost exceeds the limit, so retrying can never succeed.
+    #[inline]
+    pub fn is_denied_indefinitely(&self) -> bool {
+        !self.allowed && self.retry_after.is_none()
+    }
+}
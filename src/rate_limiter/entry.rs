@@ -1,14 +1,17 @@
 use std::{
     ops::{Deref, DerefMut},
+    sync::Arc,
     time::Instant,
 };
 
 use crate::{GcraState, RateLimit};
 
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct RateLimitEntry {
     pub gcra_state: GcraState,
     pub expires_at: Option<Instant>,
+    /// Name of the policy this entry was created under. Unset for ad-hoc [RateLimit] checks.
+    pub policy: Option<Arc<str>>,
 }
 
 impl Deref for RateLimitEntry {
@@ -0,0 +1,28 @@
+use thiserror::Error;
+
+use crate::{rate_limiter::StoreError, GcraError, InvalidThrottleError};
+
+/// Errors of a [RateLimiter](crate::RateLimiter): the [GcraError] denials of its checks along
+/// with what can go wrong with its policies and store.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RateLimiterError {
+    /// The check was denied
+    #[error(transparent)]
+    Gcra(#[from] GcraError),
+    /// The requested policy has not been registered
+    #[error("Unknown policy ({policy})")]
+    UnknownPolicy { policy: String },
+    /// The key is still being tracked under a different policy than the one requested
+    #[error("Key is tracked under policy {recorded:?} but was checked with {requested:?}")]
+    PolicyMismatch {
+        requested: Option<String>,
+        recorded: Option<String>,
+    },
+    /// The parameters of a [RateLimiter::throttle](crate::RateLimiter::throttle) don't describe a
+    /// usable rate
+    #[error(transparent)]
+    InvalidThrottle(#[from] InvalidThrottleError),
+    /// The rate limiter's store failed, so no decision could be made
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
@@ -1,5 +1,22 @@
+mod decision;
 mod entry;
+mod error;
+mod observer;
+mod policy;
+#[allow(clippy::module_inception)]
 mod rate_limiter;
+#[cfg(feature = "redis")]
+mod redis_store;
+mod stats;
+mod store;
 
+pub use decision::Decision;
 pub use entry::*;
+pub use error::RateLimiterError;
+pub use observer::*;
+pub use policy::PolicyChange;
 pub use rate_limiter::*;
+#[cfg(feature = "redis")]
+pub use redis_store::*;
+pub use stats::RateLimiterStats;
+pub use store::*;
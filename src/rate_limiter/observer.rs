@@ -0,0 +1,85 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{rate_limiter::RateLimitEntry, Cost, GcraState, RateLimit};
+
+/// Describes a single check made against a [RateLimiter](crate::RateLimiter).
+#[derive(Debug, Clone, Copy)]
+pub struct CheckEvent<'a, K> {
+    pub key: &'a K,
+    /// Name of the policy checked, unset for ad-hoc [RateLimit] checks
+    pub policy: Option<&'a str>,
+    pub rate_limit: &'a RateLimit,
+    pub cost: Cost,
+    pub arrived_at: Instant,
+    /// State of the key after the check
+    pub state: &'a GcraState,
+}
+
+/// Hooks called by a [RateLimiter](crate::RateLimiter) as it makes decisions, e.g. to audit
+/// denials or feed abuse detection.
+///
+/// All methods default to doing nothing. Since the limiter is generic over its observer, the
+/// default [NoopObserver] is compiled away entirely and adds no cost to the hot path.
+///
+/// Check hooks are called after the entry lock has been released. Eviction hooks are called
+/// while the shard is locked, so they must not call back into the limiter.
+pub trait Observer<K> {
+    /// The check was allowed and `cost` was consumed.
+    #[inline]
+    fn on_allowed(&self, _event: &CheckEvent<'_, K>) {}
+
+    /// The check was denied until `next_allowed_at`.
+    #[inline]
+    fn on_denied(&self, _event: &CheckEvent<'_, K>, _next_allowed_at: Instant) {}
+
+    /// The check was denied because `cost` exceeds the [RateLimit] and can never succeed.
+    #[inline]
+    fn on_denied_indefinitely(&self, _event: &CheckEvent<'_, K>) {}
+
+    /// The entry for `key` was removed, either because it expired or its policy was reset.
+    #[inline]
+    fn on_evicted(&self, _key: &K, _entry: &RateLimitEntry) {}
+
+    /// [RateLimiter::prune_expired](crate::RateLimiter::prune_expired) evicted `evicted` entries
+    /// and took `duration`.
+    #[inline]
+    fn on_pruned(&self, _evicted: u64, _duration: Duration) {}
+}
+
+/// An [Observer] that ignores everything.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoopObserver;
+impl<K> Observer<K> for NoopObserver {}
+
+impl<K, O> Observer<K> for Arc<O>
+where
+    O: Observer<K> + ?Sized,
+{
+    #[inline]
+    fn on_allowed(&self, event: &CheckEvent<'_, K>) {
+        (**self).on_allowed(event)
+    }
+
+    #[inline]
+    fn on_denied(&self, event: &CheckEvent<'_, K>, next_allowed_at: Instant) {
+        (**self).on_denied(event, next_allowed_at)
+    }
+
+    #[inline]
+    fn on_denied_indefinitely(&self, event: &CheckEvent<'_, K>) {
+        (**self).on_denied_indefinitely(event)
+    }
+
+    #[inline]
+    fn on_evicted(&self, key: &K, entry: &RateLimitEntry) {
+        (**self).on_evicted(key, entry)
+    }
+
+    #[inline]
+    fn on_pruned(&self, evicted: u64, duration: Duration) {
+        (**self).on_pruned(evicted, duration)
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::{rate_limiter::FxBuildHasher, RateLimit, RescaleMode};
+
+/// Defines what happens to existing [GcraState](crate::GcraState)s when the [RateLimit] of the
+/// policy they were created under changes or is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyChange {
+    /// Keep the existing state and interpret its TAT under the new [RateLimit].
+    ///
+    /// Since the TAT encodes time rather than resources, lowering the rate makes the already
+    /// consumed resources weigh more and raising it lets them weigh less.
+    Preserve,
+    /// Drop the existing state so every key starts with a full burst under the new [RateLimit].
+    Reset,
+    /// Rescale the existing state to the new [RateLimit] with
+    /// [GcraState::rescale](crate::GcraState::rescale). States of removed policies are kept.
+    Rescale(RescaleMode),
+}
+
+impl Default for PolicyChange {
+    fn default() -> Self {
+        PolicyChange::Rescale(RescaleMode::default())
+    }
+}
+
+type Policies = HashMap<Arc<str>, RateLimit, FxBuildHasher>;
+
+/// Holds off changes of the policies while alive.
+pub(super) type PolicyReadGuard<'a> = RwLockReadGuard<'a, Policies>;
+
+/// Holds off checks of the policies while alive, so their entries can be updated first.
+pub(super) type PolicyWriteGuard<'a> = RwLockWriteGuard<'a, Policies>;
+
+/// A policy whose [RateLimit] was swapped from `.1` to `.2`, unset if the policy was removed.
+pub(super) type PolicyUpdate = (Arc<str>, RateLimit, Option<RateLimit>);
+
+/// Named [RateLimit]s registered on a [RateLimiter](crate::RateLimiter).
+///
+/// Writes hand back their [PolicyWriteGuard], so the entries of the changed policies can be
+/// updated before any check goes by the new [RateLimit]s.
+#[derive(Default, Debug)]
+pub(super) struct PolicyRegistry {
+    policies: RwLock<Policies>,
+    pub(super) on_change: PolicyChange,
+}
+
+impl PolicyRegistry {
+    fn read(&self) -> PolicyReadGuard<'_> {
+        self.policies.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> PolicyWriteGuard<'_> {
+        self.policies
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the registered name and [RateLimit] of `policy`.
+    pub(super) fn get(&self, policy: &str) -> Option<(Arc<str>, RateLimit)> {
+        self.get_locked(policy)
+            .map(|(_, policy, rate_limit)| (policy, rate_limit))
+    }
+
+    /// Same as [PolicyRegistry::get], along with a guard to hold on to until the entry checked
+    /// under the policy is updated.
+    pub(super) fn get_locked(
+        &self,
+        policy: &str,
+    ) -> Option<(PolicyReadGuard<'_>, Arc<str>, RateLimit)> {
+        let policies = self.read();
+        let (policy, rate_limit) = policies
+            .get_key_value(policy)
+            .map(|(name, rate_limit)| (name.clone(), rate_limit.clone()))?;
+        Some((policies, policy, rate_limit))
+    }
+
+    /// Registers `policy`, returning its previous [RateLimit] and the update if it changed.
+    pub(super) fn insert(
+        &self,
+        policy: Arc<str>,
+        rate_limit: RateLimit,
+    ) -> (
+        PolicyWriteGuard<'_>,
+        Option<RateLimit>,
+        Option<PolicyUpdate>,
+    ) {
+        let mut policies = self.write();
+        let previous = policies.insert(policy.clone(), rate_limit.clone());
+        let update = previous
+            .clone()
+            .filter(|previous| *previous != rate_limit)
+            .map(|previous| (policy, previous, Some(rate_limit)));
+        (policies, previous, update)
+    }
+
+    /// Removes `policy`, returning the update if it was registered.
+    pub(super) fn remove(&self, policy: &str) -> (PolicyWriteGuard<'_>, Option<PolicyUpdate>) {
+        let mut policies = self.write();
+        let update = policies
+            .remove_entry(policy)
+            .map(|(policy, previous)| (policy, previous, None));
+        (policies, update)
+    }
+
+    /// Atomically swaps in a new set of policies.
+    ///
+    /// Returns the update of every policy that was changed or removed.
+    pub(super) fn replace_all(
+        &self,
+        new_policies: Policies,
+    ) -> (PolicyWriteGuard<'_>, Vec<PolicyUpdate>) {
+        let mut policies = self.write();
+        let old_policies = std::mem::replace(&mut *policies, new_policies);
+
+        let updates = old_policies
+            .into_iter()
+            .filter_map(|(name, old)| {
+                let new = policies.get(&name);
+                (new != Some(&old)).then(|| (name, old, new.cloned()))
+            })
+            .collect();
+        (policies, updates)
+    }
+}
+
+impl Clone for PolicyRegistry {
+    fn clone(&self) -> Self {
+        Self {
+            policies: RwLock::new(self.read().clone()),
+            on_change: self.on_change,
+        }
+    }
+}
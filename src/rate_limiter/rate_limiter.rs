@@ -1,22 +1,40 @@
-use dashmap::DashMap;
 use rustc_hash::FxHasher;
 use std::{
+    borrow::Borrow,
+    collections::HashMap,
     fmt::Display,
-    hash::{BuildHasher, BuildHasherDefault, Hash},
-    time::Instant,
+    hash::{BuildHasherDefault, Hash},
+    marker::PhantomData,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use crate::{
     clock::{Clock, InstantClock},
-    rate_limiter::entry::RateLimitEntry,
-    GcraError, RateLimit,
+    rate_limiter::{
+        decision::Decision,
+        entry::RateLimitEntry,
+        observer::{CheckEvent, NoopObserver, Observer},
+        policy::{PolicyChange, PolicyReadGuard, PolicyRegistry, PolicyUpdate},
+        stats::{LimiterStats, RateLimiterStats},
+        store::{DashMapStore, GcraStore},
+        RateLimiterError,
+    },
+    Cost, GcraError, GcraState, IntoCost, RateLimit, ThrottleResult,
 };
 
 pub type FxBuildHasher = BuildHasherDefault<FxHasher>;
 
 #[derive(Clone, Hash, PartialEq, Eq, Debug)]
 pub struct RateLimitRequest<T: Eq + Hash> {
-    key: T,
+    pub(super) key: T,
+}
+
+/// Hashes and compares exactly like the wrapped key, so maps can be queried without cloning it.
+impl<T: Eq + Hash> Borrow<T> for RateLimitRequest<T> {
+    fn borrow(&self) -> &T {
+        &self.key
+    }
 }
 
 impl<T> Display for RateLimitRequest<T>
@@ -28,52 +46,172 @@ where
     }
 }
 
-/// A sharded rate limiter implementation using an internal [GcraState] per entry.
+/// A sharded rate limiter implementation using an internal [GcraState](crate::GcraState) per entry.
 /// It is `Send + Sync + Clone` and manages an internal LRU with expiration.
+///
+/// Keys can either be checked against an ad-hoc [RateLimit] with [RateLimiter::check], or against
+/// a named policy registered with [RateLimiter::register_policy] and checked with
+/// [RateLimiter::check_policy]. Each entry records the policy it was created under, so checking
+/// a key with a different policy while its state is still active is reported as
+/// [RateLimiterError::PolicyMismatch] instead of silently mixing limits.
+///
+/// Denials are the [GcraError] of the check, wrapped in [RateLimiterError::Gcra].
+///
+/// Decisions can be observed by installing an [Observer] with [RateLimiter::with_observer].
+///
+/// State is kept in a [GcraStore], by default the in-process [DashMapStore].
 #[derive(Clone)]
-pub struct RateLimiter<T: Eq + Hash, C = InstantClock, S = FxBuildHasher> {
+pub struct RateLimiter<T: Eq + Hash, C = InstantClock, St = DashMapStore<T>, O = NoopObserver> {
     clock: C,
-    map: DashMap<RateLimitRequest<T>, RateLimitEntry, S>,
+    store: St,
+    key: PhantomData<fn(T)>,
+    policies: PolicyRegistry,
+    stats: LimiterStats,
+    observer: O,
 }
 
-impl<Key> RateLimiter<Key, InstantClock, FxBuildHasher>
+impl<Key> RateLimiter<Key, InstantClock, DashMapStore<Key>>
 where
     Key: Send + Clone + Hash + Eq + Display + 'static,
 {
     /// Constructs an sharded instance of a rate limiter.
     pub fn new(max_data_capacity: usize) -> Self {
-        Self {
-            clock: InstantClock,
-            map: DashMap::with_capacity_and_hasher(max_data_capacity, FxBuildHasher::default()),
-        }
+        Self::with_clock_and_store(InstantClock, DashMapStore::new(max_data_capacity))
     }
 
     /// Constructs an sharded instance of a rate limiter with a specific amount of shards.
     pub fn with_shards(max_data_capacity: usize, num_shards: usize) -> Self {
-        Self {
-            clock: InstantClock,
-            map: DashMap::with_capacity_and_hasher_and_shard_amount(
-                max_data_capacity,
-                FxBuildHasher::default(),
-                num_shards,
-            ),
-        }
+        Self::with_clock_and_store(
+            InstantClock,
+            DashMapStore::with_shards(max_data_capacity, num_shards),
+        )
     }
 }
 
-impl<Key, C, S> RateLimiter<Key, C, S>
+impl<Key, St> RateLimiter<Key, InstantClock, St>
+where
+    Key: Send + Clone + Hash + Eq + Display + 'static,
+    St: GcraStore<Key>,
+{
+    /// Constructs a rate limiter keeping its state in `store`.
+    pub fn with_store(store: St) -> Self {
+        Self::with_clock_and_store(InstantClock, store)
+    }
+}
+
+impl<Key, C, St> RateLimiter<Key, C, St>
 where
     Key: Send + Clone + Hash + Eq + Display + 'static,
     C: Clock,
-    S: Default + BuildHasher + Clone,
+    St: GcraStore<Key>,
 {
-    pub fn with_clock(clock: C) -> Self {
+    pub fn with_clock(clock: C) -> Self
+    where
+        St: Default,
+    {
+        Self::with_clock_and_store(clock, St::default())
+    }
+
+    pub fn with_clock_and_store(clock: C, store: St) -> Self {
         Self {
             clock,
-            map: DashMap::default(),
+            store,
+            key: PhantomData,
+            policies: PolicyRegistry::default(),
+            stats: LimiterStats::default(),
+            observer: NoopObserver,
+        }
+    }
+}
+
+impl<Key, C, St, O> RateLimiter<Key, C, St, O>
+where
+    Key: Send + Clone + Hash + Eq + Display + 'static,
+    C: Clock,
+    St: GcraStore<Key>,
+    O: Observer<Key>,
+{
+    /// Replaces the [Observer] notified of every decision the limiter makes.
+    pub fn with_observer<O2: Observer<Key>>(self, observer: O2) -> RateLimiter<Key, C, St, O2> {
+        RateLimiter {
+            clock: self.clock,
+            store: self.store,
+            key: PhantomData,
+            policies: self.policies,
+            stats: self.stats,
+            observer,
         }
     }
 
+    /// Names the limiter, used as the `limiter` label when the `metrics` feature is enabled.
+    ///
+    /// The `gcra_entries` gauge is only sampled by [RateLimiter::prune_expired], so it lags
+    /// behind inserts and removals in between prunes.
+    pub fn with_name(mut self, name: impl Into<Arc<str>>) -> Self {
+        self.stats.name = Some(name.into());
+        self
+    }
+
+    /// Sets what happens to the state of existing keys when their policy changes.
+    /// Defaults to [PolicyChange::Rescale] preserving the consumed fraction.
+    pub fn with_policy_change(mut self, on_change: PolicyChange) -> Self {
+        self.policies.on_change = on_change;
+        self
+    }
+
+    /// Registers a named [RateLimit] to be used with [RateLimiter::check_policy].
+    ///
+    /// Returns the previously registered [RateLimit] if the policy already existed, in which case
+    /// existing keys are updated according to the configured [PolicyChange].
+    /// Checks of the policy wait until its keys are updated, so none of them goes by the new
+    /// [RateLimit] before that.
+    pub fn register_policy(
+        &self,
+        policy: impl Into<Arc<str>>,
+        rate_limit: RateLimit,
+    ) -> Option<RateLimit> {
+        // Checks of the policy wait for its entries to be updated
+        let (_policies, previous, update) = self.policies.insert(policy.into(), rate_limit);
+        if let Some(update) = update {
+            self.apply_policy_changes(vec![update]);
+        }
+        previous
+    }
+
+    /// Atomically replaces all registered policies.
+    ///
+    /// Keys of policies that were changed or removed are updated according to the configured
+    /// [PolicyChange].
+    pub fn replace_policies<P>(&self, policies: impl IntoIterator<Item = (P, RateLimit)>)
+    where
+        P: Into<Arc<str>>,
+    {
+        let policies: HashMap<_, _, FxBuildHasher> = policies
+            .into_iter()
+            .map(|(policy, rate_limit)| (policy.into(), rate_limit))
+            .collect();
+        let (_policies, updates) = self.policies.replace_all(policies);
+        if !updates.is_empty() {
+            self.apply_policy_changes(updates);
+        }
+    }
+
+    /// Removes a named policy, returning its [RateLimit] if it was registered.
+    ///
+    /// Keys of the policy are updated according to the configured [PolicyChange], as for
+    /// [RateLimiter::replace_policies].
+    pub fn unregister_policy(&self, policy: &str) -> Option<RateLimit> {
+        let (_policies, update) = self.policies.remove(policy);
+        let (policy, previous, new) = update?;
+        self.apply_policy_changes(vec![(policy, previous.clone(), new)]);
+        Some(previous)
+    }
+
+    /// Returns the [RateLimit] registered for `policy`.
+    pub fn policy(&self, policy: &str) -> Option<RateLimit> {
+        self.policies.get(policy).map(|(_, rate_limit)| rate_limit)
+    }
+
     /// Check to see if [key] is rate limited.
     /// # Errors
     /// - [GcraError::DeniedUntil] if the request can succeed after the [Instant] returned.
@@ -83,8 +221,8 @@ where
         &self,
         key: Key,
         rate_limit: &RateLimit,
-        cost: u32,
-    ) -> Result<Instant, GcraError> {
+        cost: impl IntoCost,
+    ) -> Result<Instant, RateLimiterError> {
         self.check_at(key, rate_limit, cost, self.clock.now()).await
     }
 
@@ -97,38 +235,483 @@ where
         &self,
         key: Key,
         rate_limit: &RateLimit,
-        cost: u32,
+        cost: impl IntoCost,
+        arrived_at: Instant,
+    ) -> Result<Instant, RateLimiterError> {
+        self.check_entry_at(key, None, rate_limit, cost.into_cost(), arrived_at)
+            .1
+    }
+
+    /// Check to see if [key] is rate limited by the registered `policy`.
+    ///
+    /// # Errors
+    /// - [GcraError::DeniedUntil] if the request can succeed after the [Instant] returned.
+    /// - [GcraError::DeniedIndefinitely] if the request can never succeed
+    /// - [RateLimiterError::UnknownPolicy] if `policy` has not been registered
+    /// - [RateLimiterError::PolicyMismatch] if [key] is still tracked under another policy
+    #[inline]
+    pub async fn check_policy(
+        &self,
+        key: Key,
+        policy: &str,
+        cost: impl IntoCost,
+    ) -> Result<Instant, RateLimiterError> {
+        self.check_policy_at(key, policy, cost, self.clock.now())
+            .await
+    }
+
+    /// Check to see if [key] is rate limited by the registered `policy`.
+    ///
+    /// # Errors
+    /// - [GcraError::DeniedUntil] if the request can succeed after the [Instant] returned.
+    /// - [GcraError::DeniedIndefinitely] if the request can never succeed
+    /// - [RateLimiterError::UnknownPolicy] if `policy` has not been registered
+    /// - [RateLimiterError::PolicyMismatch] if [key] is still tracked under another policy
+    pub async fn check_policy_at(
+        &self,
+        key: Key,
+        policy: &str,
+        cost: impl IntoCost,
+        arrived_at: Instant,
+    ) -> Result<Instant, RateLimiterError> {
+        let (policies, policy, rate_limit) =
+            self.policies
+                .get_locked(policy)
+                .ok_or_else(|| RateLimiterError::UnknownPolicy {
+                    policy: policy.to_string(),
+                })?;
+        self.check_entry_at(
+            key,
+            Some((policies, policy)),
+            &rate_limit,
+            cost.into_cost(),
+            arrived_at,
+        )
+        .1
+    }
+
+    /// Same as [RateLimiter::check], but denials are reported as a [Decision] describing what is
+    /// left of the key's limit.
+    ///
+    /// # Errors
+    /// - [RateLimiterError::PolicyMismatch] if [key] is still tracked under a policy
+    #[inline]
+    pub async fn decide(
+        &self,
+        key: Key,
+        rate_limit: &RateLimit,
+        cost: impl IntoCost,
+    ) -> Result<Decision, RateLimiterError> {
+        self.decide_at(key, rate_limit, cost, self.clock.now())
+            .await
+    }
+
+    /// Same as [RateLimiter::check_at], but denials are reported as a [Decision] describing what
+    /// is left of the key's limit.
+    ///
+    /// # Errors
+    /// - [RateLimiterError::PolicyMismatch] if [key] is still tracked under a policy
+    pub async fn decide_at(
+        &self,
+        key: Key,
+        rate_limit: &RateLimit,
+        cost: impl IntoCost,
+        arrived_at: Instant,
+    ) -> Result<Decision, RateLimiterError> {
+        self.decide_sync_at(key, rate_limit, cost, arrived_at)
+    }
+
+    /// Same as [RateLimiter::check_policy], but denials are reported as a [Decision] describing
+    /// what is left of the key's limit.
+    ///
+    /// # Errors
+    /// - [RateLimiterError::UnknownPolicy] if `policy` has not been registered
+    /// - [RateLimiterError::PolicyMismatch] if [key] is still tracked under another policy
+    #[inline]
+    pub async fn decide_policy(
+        &self,
+        key: Key,
+        policy: &str,
+        cost: impl IntoCost,
+    ) -> Result<Decision, RateLimiterError> {
+        self.decide_policy_at(key, policy, cost, self.clock.now())
+            .await
+    }
+
+    /// Same as [RateLimiter::check_policy_at], but denials are reported as a [Decision]
+    /// describing what is left of the key's limit.
+    ///
+    /// # Errors
+    /// - [RateLimiterError::UnknownPolicy] if `policy` has not been registered
+    /// - [RateLimiterError::PolicyMismatch] if [key] is still tracked under another policy
+    pub async fn decide_policy_at(
+        &self,
+        key: Key,
+        policy: &str,
+        cost: impl IntoCost,
+        arrived_at: Instant,
+    ) -> Result<Decision, RateLimiterError> {
+        self.decide_policy_sync_at(key, policy, cost, arrived_at)
+    }
+
+    /// Synchronous [RateLimiter::decide_at], for callers that can't await such as tower's
+    /// `Service::call`.
+    pub(crate) fn decide_sync_at(
+        &self,
+        key: Key,
+        rate_limit: &RateLimit,
+        cost: impl IntoCost,
+        arrived_at: Instant,
+    ) -> Result<Decision, RateLimiterError> {
+        let (state, result) =
+            self.check_entry_at(key, None, rate_limit, cost.into_cost(), arrived_at);
+        Decision::from_check(None, rate_limit.clone(), &state, arrived_at, result)
+    }
+
+    /// Synchronous [RateLimiter::decide_policy_at].
+    pub(crate) fn decide_policy_sync_at(
+        &self,
+        key: Key,
+        policy: &str,
+        cost: impl IntoCost,
+        arrived_at: Instant,
+    ) -> Result<Decision, RateLimiterError> {
+        let (policies, policy, rate_limit) =
+            self.policies
+                .get_locked(policy)
+                .ok_or_else(|| RateLimiterError::UnknownPolicy {
+                    policy: policy.to_string(),
+                })?;
+        let (state, result) = self.check_entry_at(
+            key,
+            Some((policies, policy.clone())),
+            &rate_limit,
+            cost.into_cost(),
+            arrived_at,
+        );
+        Decision::from_check(Some(policy), rate_limit, &state, arrived_at, result)
+    }
+
+    /// Gives `cost` back to `key` after an ad-hoc check against `rate_limit`, e.g. when charged
+    /// resources went unused. See [GcraState::revert].
+    ///
+    /// # Errors
+    /// - [RateLimiterError::PolicyMismatch] if `key` is still tracked under a policy
+    #[inline]
+    pub async fn revert(
+        &self,
+        key: Key,
+        rate_limit: &RateLimit,
+        cost: impl IntoCost,
+    ) -> Result<(), RateLimiterError> {
+        self.revert_at(key, rate_limit, cost, self.clock.now())
+            .await
+    }
+
+    /// Gives `cost` back to `key` at the given time, see [RateLimiter::revert].
+    ///
+    /// # Errors
+    /// - [RateLimiterError::PolicyMismatch] if `key` is still tracked under a policy
+    pub async fn revert_at(
+        &self,
+        key: Key,
+        rate_limit: &RateLimit,
+        cost: impl IntoCost,
+        arrived_at: Instant,
+    ) -> Result<(), RateLimiterError> {
+        self.revert_sync_at(key, rate_limit, cost, arrived_at)
+    }
+
+    /// Synchronous [RateLimiter::revert_at].
+    pub(crate) fn revert_sync_at(
+        &self,
+        key: Key,
+        rate_limit: &RateLimit,
+        cost: impl IntoCost,
+        arrived_at: Instant,
+    ) -> Result<(), RateLimiterError> {
+        let cost = cost.into_cost();
+        self.store.update(&key, |entry| {
+            let Some(entry_ref) = entry else {
+                // Nothing charged, nothing to give back
+                return Ok(());
+            };
+            if entry_ref.policy.is_some() && entry_ref.tat.is_some_and(|tat| tat > arrived_at) {
+                return Err(RateLimiterError::PolicyMismatch {
+                    requested: None,
+                    recorded: entry_ref.policy.as_ref().map(|policy| policy.to_string()),
+                });
+            }
+            entry_ref.revert_at(rate_limit, arrived_at, cost)?;
+            if !entry_ref.tat.is_some_and(|tat| tat > arrived_at) {
+                // Fully replenished, nothing worth keeping
+                *entry = None;
+            }
+            Ok(())
+        })?
+    }
+
+    /// Same as redis-cell's `CL.THROTTLE key max_burst count period quantity`, see
+    /// [GcraState::throttle].
+    ///
+    /// The key is tracked as an ad-hoc check, so it can't be throttled while still active under
+    /// a named policy.
+    ///
+    /// # Errors
+    /// - [RateLimiterError::InvalidThrottle] if `count` and `period` don't make for a usable rate.
+    /// - [RateLimiterError::PolicyMismatch] if [key] is still tracked under a policy
+    #[inline]
+    pub async fn throttle(
+        &self,
+        key: Key,
+        max_burst: u32,
+        count: u32,
+        period: Duration,
+        quantity: u32,
+    ) -> Result<ThrottleResult, RateLimiterError> {
+        self.throttle_at(key, max_burst, count, period, quantity, self.clock.now())
+            .await
+    }
+
+    /// Same as redis-cell's `CL.THROTTLE key max_burst count period quantity` at the given
+    /// arrival time, see [GcraState::throttle_at].
+    ///
+    /// # Errors
+    /// - [RateLimiterError::InvalidThrottle] if `count` and `period` don't make for a usable rate.
+    /// - [RateLimiterError::PolicyMismatch] if [key] is still tracked under a policy
+    pub async fn throttle_at(
+        &self,
+        key: Key,
+        max_burst: u32,
+        count: u32,
+        period: Duration,
+        quantity: u32,
+        arrived_at: Instant,
+    ) -> Result<ThrottleResult, RateLimiterError> {
+        let rate_limit = RateLimit::with_max_burst(max_burst, count, period)?;
+
+        // Unlike checks, a throttle that can never succeed keeps the key's state like redis-cell
+        let decision = self.store.update(&key, |entry| {
+            let entry_ref = entry.get_or_insert_with(RateLimitEntry::default);
+            if entry_ref.policy.is_some() && entry_ref.tat.is_some_and(|tat| tat > arrived_at) {
+                return Err(RateLimiterError::PolicyMismatch {
+                    requested: None,
+                    recorded: entry_ref.policy.as_ref().map(|policy| policy.to_string()),
+                });
+            }
+            entry_ref.policy = None;
+
+            let checked = entry_ref.check_and_modify_at(&rate_limit, arrived_at, quantity);
+            let throttled = ThrottleResult::new(entry_ref, &rate_limit, arrived_at, &checked);
+            let checked = checked
+                .map(|_| {
+                    entry_ref.update_expiration(&rate_limit);
+                    // Guaranteed to be set from update_expiration
+                    entry_ref.expires_at.unwrap()
+                })
+                .map_err(RateLimiterError::from);
+            let state = entry_ref.gcra_state;
+            if state.tat.is_none() {
+                // Nothing worth keeping
+                *entry = None;
+            }
+            Ok((state, checked, throttled))
+        })?;
+        let (state, checked, throttled) = match decision {
+            Ok(decision) => decision,
+            Err(e) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(key = %key, error = %e, "throttle failed");
+                return Err(e);
+            }
+        };
+
+        let event = CheckEvent {
+            key: &key,
+            policy: None,
+            rate_limit: &rate_limit,
+            cost: Cost::units(quantity.into()),
+            arrived_at,
+            state: &state,
+        };
+        self.record_decision(&event, None, &checked);
+        Ok(throttled)
+    }
+
+    /// Checks `key` against `rate_limit`, or against a registered policy whose guard holds off
+    /// policy changes until the key's entry is updated.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "rate_limiter",
+            level = "debug",
+            skip_all,
+            fields(key = %key, policy = policy.as_ref().map(|(_, policy)| &**policy), cost = %cost)
+        )
+    )]
+    fn check_entry_at(
+        &self,
+        key: Key,
+        policy: Option<(PolicyReadGuard<'_>, Arc<str>)>,
+        rate_limit: &RateLimit,
+        cost: Cost,
         arrived_at: Instant,
-    ) -> Result<Instant, GcraError> {
-        let request_key = RateLimitRequest { key };
+    ) -> (GcraState, Result<Instant, RateLimiterError>) {
+        let (policies, policy) = policy.unzip();
+        let (state, result) =
+            self.store
+                .check_and_modify_at(&key, policy.as_ref(), rate_limit, arrived_at, cost);
+        // The observer may change policies itself
+        drop(policies);
 
-        let mut entry = self.map.entry(request_key.clone()).or_default();
-        match entry.check_and_modify_at(rate_limit, arrived_at, cost) {
+        let event = CheckEvent {
+            key: &key,
+            policy: policy.as_deref(),
+            rate_limit,
+            cost,
+            arrived_at,
+            state: &state,
+        };
+        self.record_decision(&event, policy.as_ref(), &result);
+        (state, result)
+    }
+
+    /// Updates the stats and notifies the observer of a decision.
+    fn record_decision(
+        &self,
+        event: &CheckEvent<'_, Key>,
+        policy: Option<&Arc<str>>,
+        result: &Result<Instant, RateLimiterError>,
+    ) {
+        match result {
             Ok(_) => {
-                entry.update_expiration(rate_limit);
-                // Guaranteed to be set from update_expiration
-                let expires_at = entry.expires_at.unwrap();
-                Ok(expires_at)
+                self.stats.record_allowed(policy, event.cost);
+                self.observer.on_allowed(event);
             }
-            Err(e @ GcraError::DeniedUntil { .. }) => Err(e),
-            Err(e @ GcraError::DeniedIndefinitely { .. }) => {
-                // Free the lock so we can remove the entry
-                drop(entry);
-                // No need to keep this in the map
-                self.map.remove(&request_key);
-                Err(e)
+            Err(RateLimiterError::Gcra(GcraError::DeniedUntil { next_allowed_at })) => {
+                self.stats.record_denied(policy);
+                self.observer.on_denied(event, *next_allowed_at);
+            }
+            Err(RateLimiterError::Gcra(GcraError::DeniedIndefinitely { .. })) => {
+                self.stats.record_denied_indefinitely(policy);
+                self.observer.on_denied_indefinitely(event);
+            }
+            #[cfg(feature = "tracing")]
+            Err(RateLimiterError::PolicyMismatch { recorded, .. }) => {
+                tracing::debug!(recorded, "policy mismatch");
+            }
+            #[cfg(feature = "tracing")]
+            Err(RateLimiterError::Store(e)) => {
+                tracing::warn!(error = %e, "store failed");
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Updates the entries of the swapped policies, called while still holding the
+    /// [PolicyWriteGuard](super::policy::PolicyWriteGuard) of the swap so no check goes by a new
+    /// [RateLimit] before its entries are updated.
+    fn apply_policy_changes(&self, updates: Vec<PolicyUpdate>) {
+        match self.policies.on_change {
+            PolicyChange::Preserve => {}
+            PolicyChange::Reset => {
+                self.retain_counting(|entry| match &entry.policy {
+                    Some(policy) => !updates.iter().any(|(updated, _, _)| updated == policy),
+                    None => true,
+                });
+            }
+            PolicyChange::Rescale(mode) => {
+                let now = self.clock.now();
+                // States of removed policies are kept as they are
+                let changed: Vec<_> = updates
+                    .into_iter()
+                    .filter_map(|(policy, old, new)| Some((policy, old, new?)))
+                    .collect();
+
+                let rescaled = self.store.retain(|_key, entry| {
+                    let Some(policy) = &entry.policy else {
+                        return true;
+                    };
+                    if let Some((_, old, new)) =
+                        changed.iter().find(|(changed, _, _)| changed == policy)
+                    {
+                        entry.rescale(old, new, now, mode);
+                        entry.update_expiration(new);
+                    }
+                    true
+                });
+                #[cfg(feature = "tracing")]
+                if let Err(e) = rescaled {
+                    tracing::warn!(error = %e, "failed to rescale entries");
+                }
+                #[cfg(not(feature = "tracing"))]
+                let _ = rescaled;
             }
         }
     }
 
     /// Removes entries that have expired
     pub fn prune_expired(&self) {
+        let started_at = Instant::now();
         let now = self.clock.now();
 
-        self.map.retain(|_key, entry| match entry.expires_at {
+        let evicted = self.retain_counting(|entry| match entry.expires_at {
             Some(expires_at) => expires_at > now,
             None => true,
-        })
+        });
+        let duration = started_at.elapsed();
+        #[cfg(feature = "tracing")]
+        tracing::debug!(evicted, ?duration, "pruned expired entries");
+        self.stats.record_prune(duration, self.store.len().ok());
+        self.observer.on_pruned(evicted, duration);
+    }
+
+    /// The clock used when checks don't provide an arrival time.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// The store holding this limiter's entries.
+    pub fn store(&self) -> &St {
+        &self.store
+    }
+
+    /// Returns a snapshot of what the limiter has been doing since it was created.
+    pub fn stats(&self) -> RateLimiterStats {
+        self.stats.snapshot(self.store.len().unwrap_or_default())
+    }
+
+    /// Same as [GcraStore::retain], notifying the observer, recording the evictions of each
+    /// policy and returning how many entries were removed.
+    ///
+    /// A failing store is skipped over, its entries will be retried on the next pass.
+    fn retain_counting(&self, mut keep: impl FnMut(&RateLimitEntry) -> bool) -> u64 {
+        let mut removed: Vec<(Option<Arc<str>>, u64)> = Vec::new();
+        let retained = self.store.retain(|key, entry| {
+            let keep = keep(entry);
+            if !keep {
+                match removed
+                    .iter_mut()
+                    .find(|(policy, _)| *policy == entry.policy)
+                {
+                    Some((_, count)) => *count += 1,
+                    None => removed.push((entry.policy.clone(), 1)),
+                }
+                self.observer.on_evicted(key, entry);
+            }
+            keep
+        });
+        #[cfg(feature = "tracing")]
+        if let Err(e) = retained {
+            tracing::warn!(error = %e, "failed to retain entries");
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = retained;
+
+        for (policy, count) in &removed {
+            self.stats.record_evicted(policy.as_ref(), *count);
+        }
+        removed.iter().map(|(_, count)| count).sum()
     }
 }
 
@@ -144,6 +727,7 @@ mod tests {
     };
 
     use super::*;
+    use crate::rate_limiter::MemoryStore;
 
     #[tokio::test]
     async fn rate_limiter_run_until_denied() {
@@ -159,7 +743,7 @@ mod tests {
 
         match rl.check("key", &rate_limit, 1).await {
             Ok(_) => panic!("We should be rate limited"),
-            Err(GcraError::DeniedUntil { next_allowed_at }) => {
+            Err(RateLimiterError::Gcra(GcraError::DeniedUntil { next_allowed_at })) => {
                 assert!(next_allowed_at > Instant::now())
             }
             Err(_) => panic!("Unexpected error"),
@@ -186,13 +770,30 @@ mod tests {
 
         match rate_limiter.check("key", &rate_limit, 1).await {
             Ok(_) => panic!("We should be rate limited"),
-            Err(GcraError::DeniedUntil { next_allowed_at }) => {
+            Err(RateLimiterError::Gcra(GcraError::DeniedUntil { next_allowed_at })) => {
                 assert!(next_allowed_at > Instant::now())
             }
             Err(_) => panic!("Unexpected error"),
         }
     }
 
+    #[tokio::test]
+    async fn rate_limiter_charges_u64_costs() {
+        const GIB: u64 = 1 << 30;
+        let rate_limit = RateLimit::new_u64(16 * GIB, Duration::from_secs(60));
+        let rl = RateLimiter::new(4);
+
+        let bytes: u64 = 5 * GIB;
+        for _ in 0..3 {
+            assert!(rl.check("key", &rate_limit, bytes).await.is_ok());
+        }
+        assert!(
+            rl.check("key", &rate_limit, bytes).await.is_err(),
+            "20 GiB exceed the 16 GiB limit"
+        );
+        assert!(rl.check("key", &rate_limit, GIB).await.is_ok());
+    }
+
     #[tokio::test]
     async fn rate_limiter_indefinitly_denied() {
         let rate_limit = RateLimit::new(3, Duration::from_secs(3));
@@ -200,10 +801,11 @@ mod tests {
 
         match rl.check("key", &rate_limit, 9).await {
             Ok(_) => panic!("We should be rate limited"),
-            Err(GcraError::DeniedIndefinitely {
+            Err(RateLimiterError::Gcra(GcraError::DeniedIndefinitely {
                 cost,
                 rate_limit: err_rate_limit,
-            }) => {
+                ..
+            })) => {
                 assert_eq!(cost, 9);
                 assert_eq!(err_rate_limit, rate_limit);
             }
@@ -249,7 +851,7 @@ mod tests {
         let clock = FakeClock::new();
 
         let rate_limit = RateLimit::per_sec(3);
-        let rl: RateLimiter<_, _, FxBuildHasher> = RateLimiter::with_clock(clock.clone());
+        let rl: RateLimiter<_, _> = RateLimiter::with_clock(clock.clone());
 
         for index in 0..rate_limit.resource_limit {
             assert!(
@@ -258,9 +860,9 @@ mod tests {
             );
         }
 
-        let before_len = rl.map.len();
+        let before_len = rl.store.len().unwrap();
         rl.prune_expired();
-        let after_len = rl.map.len();
+        let after_len = rl.store.len().unwrap();
         assert_eq!(
             before_len, after_len,
             "Nothing has expired, no elements should be removed"
@@ -268,10 +870,580 @@ mod tests {
 
         clock.advance_by(Duration::from_secs(10));
         rl.prune_expired();
-        let after_len = rl.map.len();
+        let after_len = rl.store.len().unwrap();
         assert_eq!(
             0, after_len,
             "All entries have expired, no elements expected"
         );
     }
+
+    #[tokio::test]
+    async fn rate_limiter_check_policy() {
+        let rl = RateLimiter::with_shards(4, 2);
+        assert_eq!(None, rl.register_policy("login", RateLimit::per_sec(2)));
+        assert_eq!(Some(RateLimit::per_sec(2)), rl.policy("login"));
+
+        let now = Instant::now();
+        for _ in 0..2 {
+            assert!(
+                rl.check_policy_at("key", "login", 1, now).await.is_ok(),
+                "Shouldn't be rate limited yet"
+            );
+        }
+        assert!(
+            matches!(
+                rl.check_policy_at("key", "login", 1, now).await,
+                Err(RateLimiterError::Gcra(GcraError::DeniedUntil { .. }))
+            ),
+            "We should be rate limited"
+        );
+
+        assert_eq!(
+            Some(RateLimit::per_sec(2)),
+            rl.unregister_policy("login"),
+            "Previously registered policy should be returned"
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_check_unknown_policy() {
+        let rl = RateLimiter::with_shards(4, 2);
+
+        assert_eq!(
+            Err(RateLimiterError::UnknownPolicy {
+                policy: "login".to_string()
+            }),
+            rl.check_policy("key", "login", 1).await,
+        );
+        assert_eq!(0, rl.store.len().unwrap(), "No entry should be created");
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_check_policy_mismatch() {
+        let rl = RateLimiter::with_shards(4, 2);
+        rl.register_policy("login", RateLimit::per_sec(2));
+        rl.register_policy("api", RateLimit::per_sec(10));
+
+        let now = Instant::now();
+        assert!(rl.check_policy_at("key", "login", 1, now).await.is_ok());
+
+        assert_eq!(
+            Err(RateLimiterError::PolicyMismatch {
+                requested: Some("api".to_string()),
+                recorded: Some("login".to_string()),
+            }),
+            rl.check_policy_at("key", "api", 1, now).await,
+            "Key is still tracked under the login policy"
+        );
+        assert_eq!(
+            Err(RateLimiterError::PolicyMismatch {
+                requested: None,
+                recorded: Some("login".to_string()),
+            }),
+            rl.check_at("key", &RateLimit::per_sec(10), 1, now).await,
+            "Ad-hoc checks should not mix with policies"
+        );
+
+        let replenished_at = now + Duration::from_secs(1);
+        assert!(
+            rl.check_policy_at("key", "api", 1, replenished_at)
+                .await
+                .is_ok(),
+            "Fully replenished entries can switch policies"
+        );
+        assert_eq!(
+            Some("api"),
+            rl.store
+                .get(&"key")
+                .unwrap()
+                .and_then(|entry| entry.policy)
+                .as_deref(),
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_decide() {
+        let rl = RateLimiter::new(4);
+        rl.register_policy("login", RateLimit::per_sec(2));
+        let now = Instant::now();
+
+        let allowed = rl.decide_policy_at("key", "login", 1, now).await.unwrap();
+        assert_eq!(
+            Decision {
+                allowed: true,
+                policy: Some("login".into()),
+                rate_limit: RateLimit::per_sec(2),
+                remaining: 1,
+                reset_after: Duration::from_millis(500),
+                retry_after: None,
+            },
+            allowed
+        );
+
+        rl.decide_policy_at("key", "login", 1, now).await.unwrap();
+        let denied = rl.decide_policy_at("key", "login", 1, now).await.unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(0, denied.remaining);
+        assert_eq!(Duration::from_secs(1), denied.reset_after);
+        assert_eq!(Some(Duration::from_millis(500)), denied.retry_after);
+
+        let indefinitely = rl
+            .decide_at("other", &RateLimit::per_sec(2), 3, now)
+            .await
+            .unwrap();
+        assert!(indefinitely.is_denied_indefinitely());
+        assert_eq!(
+            Err(RateLimiterError::UnknownPolicy {
+                policy: "unknown".to_string()
+            }),
+            rl.decide_policy_at("key", "unknown", 1, now).await
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_throttle() {
+        let rl = RateLimiter::new(4);
+        rl.register_policy("login", RateLimit::per_sec(1));
+        let period = Duration::from_secs(10);
+        let now = Instant::now();
+
+        let throttle = |key, quantity| rl.throttle_at(key, 4, 5, period, quantity, now);
+        assert_eq!(
+            Ok([0, 5, 1, -1, 8]),
+            throttle("key", 4).await.map(<[i64; 5]>::from)
+        );
+        assert_eq!(
+            Ok([1, 5, 1, -1, 8]),
+            throttle("key", 6).await.map(<[i64; 5]>::from),
+            "Throttles that can never succeed should leave the key alone"
+        );
+        assert_eq!(
+            Ok([0, 5, 0, -1, 10]),
+            throttle("key", 1).await.map(<[i64; 5]>::from)
+        );
+        assert_eq!(
+            Ok([1, 5, 5, -1, 0]),
+            throttle("other", 6).await.map(<[i64; 5]>::from)
+        );
+        assert_eq!(Ok(1), rl.store.len(), "Untouched keys should not be stored");
+
+        rl.check_policy_at("key", "login", 1, now + period)
+            .await
+            .unwrap();
+        assert!(matches!(
+            rl.throttle_at("key", 4, 5, period, 1, now + period).await,
+            Err(RateLimiterError::PolicyMismatch {
+                requested: None,
+                ..
+            })
+        ));
+
+        let stats = rl.stats();
+        assert_eq!(
+            (3, 0, 2),
+            (stats.allowed, stats.denied, stats.denied_indefinitely)
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_revert() {
+        let clock = FakeClock::new();
+        let rl: RateLimiter<_, _> = RateLimiter::with_clock(clock.clone());
+        let rate_limit = RateLimit::per_sec(3);
+        let now = clock.now();
+
+        assert!(rl.check_at("key", &rate_limit, 3, now).await.is_ok());
+        assert_eq!(Ok(()), rl.revert_at("key", &rate_limit, 2, now).await);
+        assert!(rl.check_at("key", &rate_limit, 2, now).await.is_ok());
+        assert!(rl.check_at("key", &rate_limit, 1, now).await.is_err());
+
+        assert_eq!(Ok(()), rl.revert_at("key", &rate_limit, 3, now).await);
+        assert_eq!(
+            Ok(None),
+            rl.store.get(&"key"),
+            "Fully reverted keys are removed"
+        );
+
+        rl.register_policy("login", rate_limit.clone());
+        assert!(rl.check_policy_at("user", "login", 1, now).await.is_ok());
+        assert!(matches!(
+            rl.revert_at("user", &rate_limit, 1, now).await,
+            Err(RateLimiterError::PolicyMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_replace_policies() {
+        let rl = RateLimiter::with_shards(4, 2).with_policy_change(PolicyChange::Reset);
+        rl.replace_policies([
+            ("login", RateLimit::per_sec(1)),
+            ("api", RateLimit::per_sec(1)),
+        ]);
+
+        let now = Instant::now();
+        assert!(rl.check_policy_at("user", "login", 1, now).await.is_ok());
+        assert!(rl.check_policy_at("user", "login", 1, now).await.is_err());
+        assert!(rl.check_policy_at("app", "api", 1, now).await.is_ok());
+        assert!(rl.check_policy_at("app", "api", 1, now).await.is_err());
+
+        rl.replace_policies([
+            ("login", RateLimit::per_sec(2)),
+            ("api", RateLimit::per_sec(1)),
+        ]);
+        assert_eq!(Some(RateLimit::per_sec(2)), rl.policy("login"));
+        assert!(
+            rl.check_policy_at("user", "login", 1, now).await.is_ok(),
+            "Changed policy should have been reset"
+        );
+        assert!(
+            rl.check_policy_at("app", "api", 1, now).await.is_err(),
+            "Unchanged policy should keep its state"
+        );
+
+        rl.replace_policies([("api", RateLimit::per_sec(1))]);
+        assert_eq!(None, rl.policy("login"), "Removed policy should be gone");
+        assert_eq!(
+            1,
+            rl.store.len().unwrap(),
+            "Removed policy should have been reset"
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_unregister_policy_resets_state() {
+        let rl = RateLimiter::with_shards(4, 2).with_policy_change(PolicyChange::Reset);
+        rl.register_policy("login", RateLimit::per_sec(1));
+        rl.register_policy("api", RateLimit::per_sec(1));
+
+        let now = Instant::now();
+        assert!(rl.check_policy_at("user", "login", 1, now).await.is_ok());
+        assert!(rl.check_policy_at("app", "api", 1, now).await.is_ok());
+
+        assert_eq!(Some(RateLimit::per_sec(1)), rl.unregister_policy("login"));
+        assert_eq!(None, rl.unregister_policy("login"));
+        assert_eq!(None, rl.policy("login"));
+        assert_eq!(
+            1,
+            rl.store.len().unwrap(),
+            "Removed policy should have been reset"
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_register_policy_preserves_state() {
+        let rl = RateLimiter::with_shards(4, 2).with_policy_change(PolicyChange::Preserve);
+        rl.register_policy("login", RateLimit::per_sec(1));
+
+        let now = Instant::now();
+        assert!(rl.check_policy_at("user", "login", 1, now).await.is_ok());
+
+        rl.register_policy("login", RateLimit::per_sec(2));
+        assert!(
+            rl.check_policy_at("user", "login", 1, now).await.is_err(),
+            "TAT is preserved and now consumes the whole burst"
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_register_policy_rescales_state() {
+        let clock = FakeClock::new();
+        let rl: RateLimiter<_, _> = RateLimiter::with_clock(clock.clone());
+        rl.register_policy("login", RateLimit::per_sec(10));
+
+        let now = clock.now();
+        assert!(rl.check_policy_at("user", "login", 5, now).await.is_ok());
+
+        rl.register_policy("login", RateLimit::per_sec(100));
+        let entry = RateLimitEntry::from(rl.store.get(&"user").unwrap().unwrap());
+        assert_eq!(
+            50,
+            entry.remaining_resources(&RateLimit::per_sec(100), now),
+            "Consumed fraction should be preserved"
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_concurrent_policy_updates_rescale_in_order() {
+        let clock = FakeClock::new();
+        let rl: RateLimiter<_, _> = RateLimiter::with_clock(clock.clone());
+        let fast = RateLimit::per_sec(10);
+        let slow = RateLimit::new(10, Duration::from_secs(2));
+        rl.register_policy("login", fast.clone());
+
+        let now = clock.now();
+        assert!(rl.check_policy_at("user", "login", 5, now).await.is_ok());
+
+        // Each update rescales from the limit it replaced to the one it registered, so however
+        // they interleave the key ends up half used
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..500 {
+                        let rate_limit = if i % 2 == 0 { &slow } else { &fast };
+                        rl.register_policy("login", rate_limit.clone());
+                    }
+                });
+            }
+        });
+        rl.register_policy("login", fast.clone());
+
+        let entry = RateLimitEntry::from(rl.store.get(&"user").unwrap().unwrap());
+        assert_eq!(Some(now + Duration::from_millis(500)), entry.tat);
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_fractional_costs() {
+        let clock = FakeClock::new();
+        let rl: RateLimiter<_, _> = RateLimiter::with_clock(clock.clone());
+        let rate_limit = RateLimit::per_sec(1);
+        let quarter = Cost::from_parts(250_000);
+
+        for _ in 0..4 {
+            assert!(rl.check("key", &rate_limit, quarter).await.is_ok());
+        }
+        assert!(rl.check("key", &rate_limit, quarter).await.is_err());
+        assert_eq!(
+            1,
+            rl.stats().cost_consumed,
+            "Fractions add up to whole units"
+        );
+
+        clock.advance_by(Duration::from_millis(500));
+        let decision = rl.decide("key", &rate_limit, quarter).await.unwrap();
+        assert!(decision.allowed);
+        assert_eq!(0, decision.remaining, "0.25 remains, rounded down");
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_stats() {
+        let clock = FakeClock::new();
+        let rl: RateLimiter<_, _> = RateLimiter::with_clock(clock.clone());
+        rl.register_policy("login", RateLimit::per_sec(2));
+
+        assert!(rl.check_policy("a", "login", 2).await.is_ok());
+        assert!(rl.check_policy("a", "login", 1).await.is_err());
+        assert!(rl.check("b", &RateLimit::per_sec(2), 1).await.is_ok());
+        assert!(rl.check("c", &RateLimit::per_sec(2), 3).await.is_err());
+
+        clock.advance_by(Duration::from_secs(10));
+        rl.prune_expired();
+
+        let stats = rl.stats();
+        assert_eq!(2, stats.allowed);
+        assert_eq!(1, stats.denied);
+        assert_eq!(1, stats.denied_indefinitely);
+        assert_eq!(3, stats.cost_consumed);
+        assert_eq!(0, stats.entries);
+        assert_eq!(2, stats.evicted);
+        assert_eq!(1, stats.prunes);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn rate_limiter_metrics() {
+        use metrics_util::{
+            debugging::{DebugValue, DebuggingRecorder},
+            CompositeKey, MetricKind,
+        };
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let rl = RateLimiter::with_shards(4, 2)
+            .with_name("users")
+            .with_policy_change(PolicyChange::Reset);
+        rl.register_policy("login", RateLimit::per_sec(2));
+
+        metrics::with_local_recorder(&recorder, || {
+            futures::executor::block_on(async {
+                assert!(rl.check_policy("a", "login", 2).await.is_ok());
+                assert!(rl.check_policy("a", "login", 1).await.is_err());
+            });
+            rl.unregister_policy("login");
+        });
+
+        let counters: HashMap<_, _> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| (key, value))
+            .collect();
+        let counter = |name: &'static str| {
+            let key = CompositeKey::new(
+                MetricKind::Counter,
+                metrics::Key::from_parts(
+                    name,
+                    vec![
+                        metrics::Label::new("limiter", "users"),
+                        metrics::Label::new("policy", "login"),
+                    ],
+                ),
+            );
+            counters.get(&key)
+        };
+        assert_eq!(Some(&DebugValue::Counter(1)), counter("gcra_allowed_total"));
+        assert_eq!(
+            Some(&DebugValue::Counter(2)),
+            counter("gcra_cost_consumed_total")
+        );
+        assert_eq!(Some(&DebugValue::Counter(1)), counter("gcra_denied_total"));
+        assert_eq!(Some(&DebugValue::Counter(1)), counter("gcra_evicted_total"));
+    }
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl Observer<&'static str> for RecordingObserver {
+        fn on_allowed(&self, event: &CheckEvent<'_, &'static str>) {
+            let mut events = self.events.lock().unwrap();
+            events.push(format!(
+                "allowed {} {:?} {}",
+                event.key, event.policy, event.cost
+            ));
+        }
+
+        fn on_denied(&self, event: &CheckEvent<'_, &'static str>, _next_allowed_at: Instant) {
+            let mut events = self.events.lock().unwrap();
+            events.push(format!(
+                "denied {} {:?} {}",
+                event.key, event.policy, event.cost
+            ));
+        }
+
+        fn on_denied_indefinitely(&self, event: &CheckEvent<'_, &'static str>) {
+            let mut events = self.events.lock().unwrap();
+            events.push(format!(
+                "denied indefinitely {} {:?} {}",
+                event.key, event.policy, event.cost
+            ));
+        }
+
+        fn on_evicted(&self, key: &&'static str, _entry: &RateLimitEntry) {
+            let mut events = self.events.lock().unwrap();
+            events.push(format!("evicted {}", key));
+        }
+
+        fn on_pruned(&self, evicted: u64, _duration: Duration) {
+            let mut events = self.events.lock().unwrap();
+            events.push(format!("pruned {}", evicted));
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_observer() {
+        let clock = FakeClock::new();
+        let observer = Arc::new(RecordingObserver::default());
+        let rl: RateLimiter<_, _> = RateLimiter::with_clock(clock.clone());
+        let rl = rl.with_observer(observer.clone());
+        rl.register_policy("login", RateLimit::per_sec(1));
+
+        assert!(rl.check_policy("a", "login", 1).await.is_ok());
+        assert!(rl.check_policy("a", "login", 1).await.is_err());
+        assert!(rl.check("b", &RateLimit::per_sec(1), 2).await.is_err());
+
+        clock.advance_by(Duration::from_secs(10));
+        rl.prune_expired();
+
+        assert_eq!(
+            vec![
+                "allowed a Some(\"login\") 1",
+                "denied a Some(\"login\") 1",
+                "denied indefinitely b None 2",
+                "evicted a",
+                "pruned 1",
+            ],
+            *observer.events.lock().unwrap()
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn rate_limiter_tracing() {
+        use std::sync::Mutex;
+
+        #[derive(Clone, Default)]
+        struct Output(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Output {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        let rl = RateLimiter::with_shards(4, 2);
+        rl.register_policy("login", RateLimit::per_sec(1));
+        tracing::subscriber::with_default(subscriber, || {
+            futures::executor::block_on(async {
+                assert!(rl.check_policy("user", "login", 1).await.is_ok());
+                assert!(rl.check_policy("user", "login", 1).await.is_err());
+            })
+        });
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(
+            1,
+            lines.len(),
+            "Only the denial should be logged: {}",
+            output
+        );
+        for expected in [
+            "DEBUG",
+            "key=user",
+            "policy=\"login\"",
+            "cost=1",
+            "next_allowed_at=",
+            "remaining=0",
+            "gcra denied",
+        ] {
+            assert!(
+                lines[0].contains(expected),
+                "{} missing {}",
+                lines[0],
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_with_memory_store() {
+        let rl = RateLimiter::with_store(MemoryStore::default());
+        rl.register_policy("login", RateLimit::per_sec(2));
+
+        let now = Instant::now();
+        assert!(rl.check_policy_at("key", "login", 1, now).await.is_ok());
+        assert!(rl.check_policy_at("key", "login", 1, now).await.is_ok());
+        assert!(matches!(
+            rl.check_policy_at("key", "login", 1, now).await,
+            Err(RateLimiterError::Gcra(GcraError::DeniedUntil { .. }))
+        ));
+        assert!(matches!(
+            rl.check_policy_at("other", "login", 3, now).await,
+            Err(RateLimiterError::Gcra(GcraError::DeniedIndefinitely { .. }))
+        ));
+        assert_eq!(
+            1,
+            rl.store.len().unwrap(),
+            "Indefinitely denied keys are not stored"
+        );
+        assert_eq!(
+            Some("login"),
+            rl.store
+                .get(&"key")
+                .unwrap()
+                .and_then(|entry| entry.policy)
+                .as_deref()
+        );
+    }
 }
@@ -0,0 +1,639 @@
+use std::{
+    fmt::Display,
+    marker::PhantomData,
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use redis::{Client, Connection, RedisResult, Script};
+
+use crate::{
+    rate_limiter::{GcraStore, RateLimitEntry, RateLimiterError, StoreError, StoredTat},
+    Cost, GcraError, GcraState, RateLimit,
+};
+
+/// Default prefix prepended to every key written by a [RedisStore].
+pub const DEFAULT_REDIS_PREFIX: &str = "gcra:";
+/// Default number of idle connections kept by a [RedisStore].
+pub const DEFAULT_REDIS_MAX_IDLE: usize = 16;
+
+/// Redis runs Lua 5.1, whose doubles can't hold epoch nanos exactly. Timestamps are kept as
+/// decimal strings and split into seconds and nanos, so the arithmetic only ever happens on
+/// (comparatively) small relative durations.
+const TIME_HELPERS: &str = r#"
+local function split(t)
+  local n = #t
+  if n <= 9 then
+    return 0, tonumber(t)
+  end
+  return tonumber(string.sub(t, 1, n - 9)), tonumber(string.sub(t, n - 8))
+end
+
+local function diff(a, b)
+  local a_secs, a_nanos = split(a)
+  local b_secs, b_nanos = split(b)
+  return (a_secs - b_secs) * 1000000000 + (a_nanos - b_nanos)
+end
+
+local function offset(t, delta)
+  local secs, nanos = split(t)
+  nanos = nanos + delta
+  local carry = math.floor(nanos / 1000000000)
+  return string.format('%d%09d', secs + carry, nanos - carry * 1000000000)
+end
+"#;
+
+/// `KEYS[1]`: key, `ARGV[1]`: expected TAT (empty if absent), `ARGV[2]`: new value (empty to
+/// delete), `ARGV[3]`: TTL in millis.
+const COMPARE_AND_SWAP: &str = r#"
+local current = redis.call('GET', KEYS[1])
+local current_tat = ''
+if current then
+  current_tat = string.match(current, '^%d*')
+end
+if current_tat ~= ARGV[1] then
+  return 0
+end
+if ARGV[2] == '' then
+  redis.call('DEL', KEYS[1])
+else
+  redis.call('SET', KEYS[1], ARGV[2], 'PX', ARGV[3])
+end
+return 1
+"#;
+
+/// Mirrors [GcraState::check_and_modify_at] along with the policy bookkeeping of the
+/// [RateLimiter](crate::RateLimiter).
+///
+/// `KEYS[1]`: key, `ARGV[1]`: now, `ARGV[2]`: increment interval, `ARGV[3]`: period,
+/// `ARGV[4]`: encoded policy suffix.
+const CHECK_AND_MODIFY: &str = r#"
+local now = ARGV[1]
+local increment = tonumber(ARGV[2])
+local period = tonumber(ARGV[3])
+local policy = ARGV[4]
+
+local tat = nil
+local stored = redis.call('GET', KEYS[1])
+if stored then
+  local stored_tat, _, stored_policy = string.match(stored, '^(%d*):(%d*)(.*)$')
+  if stored_tat ~= '' then
+    tat = stored_tat
+  end
+  -- Entries that have fully replenished carry no state, so they can switch policies
+  if stored_policy ~= policy and tat and diff(tat, now) > 0 then
+    return {'mismatch', tat, stored_policy}
+  end
+end
+
+if increment > period then
+  redis.call('DEL', KEYS[1])
+  return {'indefinitely'}
+end
+
+local new_tat = increment
+if tat then
+  local since = diff(tat, now)
+  if since >= 0 then
+    new_tat = since + increment
+    local next_allowed_at = new_tat - period
+    if next_allowed_at > 0 then
+      return {'denied', tat, offset(now, next_allowed_at)}
+    end
+  end
+end
+
+local ttl = new_tat + period
+local new_tat_at = offset(now, new_tat)
+local expires_at = offset(now, ttl)
+redis.call('SET', KEYS[1], new_tat_at .. ':' .. expires_at .. policy, 'PX', math.ceil(ttl / 1000000))
+return {'allowed', new_tat_at, expires_at}
+"#;
+
+/// A [GcraStore] keeping its entries in Redis, so several processes can share the same limits.
+///
+/// Every check runs as a single Lua script on the server, making it atomic across all clients.
+/// Entries are stored as strings holding the TAT and expiration as nanoseconds since the Unix
+/// epoch, followed by the policy name, and are given a matching `PEXPIRE` so Redis drops them
+/// once they have fully replenished.
+///
+/// Timestamps are converted from [Instant] using the system clock, so the hosts sharing a store
+/// should keep their clocks in sync (e.g. NTP).
+///
+/// Calls are blocking round-trips, each taking a connection of its own from a pool so concurrent
+/// checks don't wait on each other. When called from a multi-threaded tokio runtime (with the
+/// `tokio` feature) they run in [block_in_place](tokio::task::block_in_place) so the other tasks
+/// of the worker move to another thread. A current-thread runtime is blocked for the duration of
+/// the round-trip.
+///
+/// Redis expires keys on its own, so [RateLimiter::prune_expired](crate::RateLimiter::prune_expired)
+/// isn't needed, and the store doesn't count its keys: [GcraStore::len] fails rather than SCAN
+/// the whole database, see [RedisStore::scan_len].
+pub struct RedisStore<K> {
+    client: Client,
+    /// Connections not currently in use
+    idle: Mutex<Vec<Connection>>,
+    max_idle: usize,
+    prefix: String,
+    /// The same moment in both clocks, to convert between them.
+    anchor: (Instant, u128),
+    compare_and_swap: Script,
+    check_and_modify: Script,
+    key: PhantomData<fn(K)>,
+}
+
+impl<K> RedisStore<K> {
+    /// Creates a store using `client`. No connection is made until the store is first used.
+    pub fn new(client: Client) -> Self {
+        let anchor_instant = Instant::now();
+        let anchor_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        Self {
+            client,
+            idle: Mutex::new(Vec::new()),
+            max_idle: DEFAULT_REDIS_MAX_IDLE,
+            prefix: DEFAULT_REDIS_PREFIX.to_string(),
+            anchor: (anchor_instant, anchor_epoch),
+            compare_and_swap: Script::new(COMPARE_AND_SWAP),
+            check_and_modify: Script::new(&format!("{TIME_HELPERS}{CHECK_AND_MODIFY}")),
+            key: PhantomData,
+        }
+    }
+
+    /// Creates a store for the Redis server at `url`, e.g. `redis://127.0.0.1/`.
+    pub fn open(url: &str) -> Result<Self, StoreError> {
+        Ok(Self::new(Client::open(url).map_err(StoreError::new)?))
+    }
+
+    /// Uses `prefix` instead of [DEFAULT_REDIS_PREFIX] for every key, so several limiters can
+    /// share a database.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Keeps at most `max_idle` connections open between calls, instead of
+    /// [DEFAULT_REDIS_MAX_IDLE]. More are opened while the calls in flight need them.
+    pub fn with_max_idle(mut self, max_idle: usize) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Counts the keys under the prefix with a full SCAN of the database.
+    pub fn scan_len(&self) -> Result<usize, StoreError> {
+        Ok(self.scan_keys()?.len())
+    }
+
+    /// Runs `f` on an idle connection, or a new one if there is none. Connections that failed
+    /// are dropped rather than returned to the pool.
+    fn with_connection<R>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> RedisResult<R>,
+    ) -> Result<R, StoreError> {
+        blocking(|| {
+            let idle = self
+                .idle
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .pop();
+            let mut con = match idle {
+                Some(con) => con,
+                None => self.client.get_connection().map_err(StoreError::new)?,
+            };
+            let result = f(&mut con);
+            let broken = result
+                .as_ref()
+                .is_err_and(|e| e.is_io_error() || e.is_connection_dropped() || e.is_timeout());
+            if !broken {
+                let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+                if idle.len() < self.max_idle {
+                    idle.push(con);
+                }
+            }
+            result.map_err(StoreError::new)
+        })
+    }
+
+    fn redis_key(&self, key: &impl Display) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn to_epoch_nanos(&self, instant: Instant) -> u128 {
+        let (anchor, anchor_epoch) = self.anchor;
+        match instant.checked_duration_since(anchor) {
+            Some(since) => anchor_epoch + since.as_nanos(),
+            None => anchor_epoch.saturating_sub((anchor - instant).as_nanos()),
+        }
+    }
+
+    fn instant_from_epoch_nanos(&self, nanos: u128) -> Instant {
+        let (anchor, anchor_epoch) = self.anchor;
+        let to_duration = |nanos: u128| Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX));
+        if nanos >= anchor_epoch {
+            anchor + to_duration(nanos - anchor_epoch)
+        } else {
+            // Instant can't go back past its platform origin, such old times are long replenished
+            anchor
+                .checked_sub(to_duration(anchor_epoch - nanos))
+                .unwrap_or(anchor)
+        }
+    }
+
+    fn parse_instant(&self, value: &str) -> Result<Option<Instant>, StoreError> {
+        if value.is_empty() {
+            return Ok(None);
+        }
+        value
+            .parse()
+            .map(|nanos| Some(self.instant_from_epoch_nanos(nanos)))
+            .map_err(|_| StoreError::new(format!("Invalid timestamp: {value}")))
+    }
+
+    /// `<tat>:<expires_at>[:<policy>]`
+    fn encode(&self, stored: &StoredTat) -> String {
+        format!(
+            "{}:{}{}",
+            self.to_epoch_nanos(stored.tat),
+            self.to_epoch_nanos(stored.expires_at),
+            encode_policy(stored.policy.as_deref())
+        )
+    }
+
+    fn decode(&self, value: &str) -> Result<StoredTat, StoreError> {
+        let invalid = || StoreError::new(format!("Invalid entry: {value}"));
+        let mut parts = value.splitn(3, ':');
+        let (Some(tat), Some(expires_at)) = (parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        Ok(StoredTat {
+            tat: self.parse_instant(tat)?.ok_or_else(invalid)?,
+            expires_at: self.parse_instant(expires_at)?.ok_or_else(invalid)?,
+            policy: parts.next().map(Arc::from),
+        })
+    }
+
+    /// Milliseconds until `stored` expires, rounded up so Redis never drops it early.
+    fn ttl_millis(stored: &StoredTat) -> u128 {
+        let ttl = stored.expires_at.saturating_duration_since(Instant::now());
+        ttl.as_nanos().div_ceil(1_000_000).max(1)
+    }
+
+    fn scan_keys(&self) -> Result<Vec<String>, StoreError> {
+        let pattern = format!("{}*", escape_glob(&self.prefix));
+        self.with_connection(|con| {
+            let mut keys = Vec::new();
+            let mut cursor = 0u64;
+            loop {
+                let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(&pattern)
+                    .arg("COUNT")
+                    .arg(100)
+                    .query(con)?;
+                keys.extend(batch);
+                if next == 0 {
+                    return Ok(keys);
+                }
+                cursor = next;
+            }
+        })
+    }
+}
+
+/// Runs the blocking `f` without stalling the other tasks of a multi-threaded tokio worker.
+fn blocking<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "tokio")]
+    {
+        use tokio::runtime::{Handle, RuntimeFlavor};
+
+        let multi_thread = Handle::try_current()
+            .is_ok_and(|handle| handle.runtime_flavor() == RuntimeFlavor::MultiThread);
+        if multi_thread {
+            return tokio::task::block_in_place(f);
+        }
+    }
+    f()
+}
+
+fn encode_policy(policy: Option<&str>) -> String {
+    policy
+        .map(|policy| format!(":{policy}"))
+        .unwrap_or_default()
+}
+
+fn escape_glob(pattern: &str) -> String {
+    let mut escaped = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl<K> GcraStore<K> for RedisStore<K>
+where
+    K: Display + FromStr,
+{
+    fn get(&self, key: &K) -> Result<Option<StoredTat>, StoreError> {
+        let value: Option<String> =
+            self.with_connection(|con| redis::cmd("GET").arg(self.redis_key(key)).query(con))?;
+        value.map(|value| self.decode(&value)).transpose()
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &K,
+        current: Option<Instant>,
+        new: Option<StoredTat>,
+    ) -> Result<bool, StoreError> {
+        let current = current
+            .map(|tat| self.to_epoch_nanos(tat).to_string())
+            .unwrap_or_default();
+        let ttl = new
+            .as_ref()
+            .map(|stored| Self::ttl_millis(stored).to_string())
+            .unwrap_or_default();
+        let new = new.map(|stored| self.encode(&stored)).unwrap_or_default();
+        let swapped: i64 = self.with_connection(|con| {
+            self.compare_and_swap
+                .key(self.redis_key(key))
+                .arg(current)
+                .arg(new)
+                .arg(ttl)
+                .invoke(con)
+        })?;
+        Ok(swapped == 1)
+    }
+
+    /// Scans every key under the prefix. Keys modified concurrently are left untouched.
+    fn retain(
+        &self,
+        mut keep: impl FnMut(&K, &mut RateLimitEntry) -> bool,
+    ) -> Result<(), StoreError> {
+        for redis_key in self.scan_keys()? {
+            // Keys written by something else are none of our business
+            let Some(key) = redis_key
+                .strip_prefix(&self.prefix)
+                .and_then(|key| key.parse::<K>().ok())
+            else {
+                continue;
+            };
+            let Some(current) = GcraStore::get(self, &key)? else {
+                continue;
+            };
+            let mut entry = RateLimitEntry::from(current.clone());
+            let new = keep(&key, &mut entry)
+                .then(|| StoredTat::from_entry(&entry))
+                .flatten();
+            if new.as_ref() != Some(&current) {
+                self.compare_and_swap(&key, Some(current.tat), new)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Always fails, counting the keys takes a full SCAN, see [RedisStore::scan_len].
+    fn len(&self) -> Result<usize, StoreError> {
+        Err(StoreError::new(
+            "RedisStore doesn't count its keys, use RedisStore::scan_len",
+        ))
+    }
+
+    /// Runs the whole check server side in a single script.
+    fn check_and_modify_at(
+        &self,
+        key: &K,
+        policy: Option<&Arc<str>>,
+        rate_limit: &RateLimit,
+        arrived_at: Instant,
+        cost: Cost,
+    ) -> (GcraState, Result<Instant, RateLimiterError>) {
+        let increment_interval = rate_limit.increment_interval(cost);
+        let reply: Result<Vec<String>, _> = self.with_connection(|con| {
+            self.check_and_modify
+                .key(self.redis_key(key))
+                .arg(self.to_epoch_nanos(arrived_at).to_string())
+                .arg(increment_interval.as_nanos().to_string())
+                .arg(rate_limit.period.as_nanos().to_string())
+                .arg(encode_policy(policy.map(|policy| &**policy)))
+                .invoke(con)
+        });
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(e) => return (GcraState::default(), Err(e.into())),
+        };
+
+        let parse_state = |tat: &str| -> Result<GcraState, StoreError> {
+            Ok(GcraState {
+                tat: self.parse_instant(tat)?,
+            })
+        };
+        let decision = match reply.as_slice() {
+            [status, tat, expires_at] if status == "allowed" => {
+                parse_state(tat).and_then(|state| {
+                    let expires_at = self
+                        .parse_instant(expires_at)?
+                        .ok_or_else(|| StoreError::new("Missing expiration"))?;
+                    Ok((state, Ok(expires_at)))
+                })
+            }
+            [status, tat, next_allowed_at] if status == "denied" => {
+                parse_state(tat).and_then(|state| {
+                    let next_allowed_at = self
+                        .parse_instant(next_allowed_at)?
+                        .ok_or_else(|| StoreError::new("Missing next allowed time"))?;
+                    Ok((
+                        state,
+                        Err(GcraError::DeniedUntil { next_allowed_at }.into()),
+                    ))
+                })
+            }
+            [status] if status == "indefinitely" => Ok((
+                GcraState::default(),
+                Err(GcraError::denied_indefinitely(cost, rate_limit.clone()).into()),
+            )),
+            [status, tat, recorded] if status == "mismatch" => parse_state(tat).map(|state| {
+                let recorded = recorded.strip_prefix(':').map(str::to_string);
+                let requested = policy.map(|policy| policy.to_string());
+                (
+                    state,
+                    Err(RateLimiterError::PolicyMismatch {
+                        requested,
+                        recorded,
+                    }),
+                )
+            }),
+            _ => Err(StoreError::new(format!("Unexpected reply: {reply:?}"))),
+        };
+        decision.unwrap_or_else(|e| (GcraState::default(), Err(e.into())))
+    }
+}
+
+#[cfg(test)]
+mod fake_redis;
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        clock::{tests::FakeClock, Clock},
+        rate_limiter::store::tests::stored,
+        RateLimiter,
+    };
+
+    use super::*;
+
+    /// Runs against `GCRA_REDIS_URL` if set, otherwise against an in-process stand-in.
+    fn store(prefix: &str) -> RedisStore<String> {
+        let url = std::env::var("GCRA_REDIS_URL")
+            .unwrap_or_else(|_| fake_redis::FakeRedis::start().url());
+        let store = RedisStore::open(&url).unwrap().with_prefix(prefix);
+        // Left over from previous runs against a real server
+        store.retain(|_key, _entry| false).unwrap();
+        store
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let store = store("test:encode:");
+        let now = Instant::now();
+        for stored in [
+            stored(now),
+            StoredTat {
+                policy: Some("with:colons".into()),
+                ..stored(now - Duration::from_secs(3))
+            },
+        ] {
+            let encoded = store.encode(&stored);
+            assert_eq!(stored, store.decode(&encoded).unwrap(), "{encoded}");
+        }
+        assert!(store.decode(":").is_err(), "Stored TATs are never unset");
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let store = store("test:cas:");
+        let key = "key".to_string();
+        let first = stored(Instant::now());
+        let second = stored(Instant::now() + Duration::from_secs(1));
+
+        assert_eq!(
+            Ok(true),
+            store.compare_and_swap(&key, None, Some(first.clone()))
+        );
+        assert_eq!(Ok(Some(first.clone())), store.get(&key));
+        assert_eq!(Ok(false), store.compare_and_swap(&key, None, None));
+        assert_eq!(
+            Ok(true),
+            store.compare_and_swap(&key, Some(first.tat), Some(second.clone()))
+        );
+        assert_eq!(Ok(1), store.scan_len());
+        assert!(store.len().is_err(), "Counting needs an explicit scan");
+
+        assert_eq!(Ok(()), store.retain(|_key, _entry| false));
+        assert_eq!(Ok(0), store.scan_len());
+    }
+
+    #[tokio::test]
+    async fn rate_limiter() {
+        let clock = FakeClock::new();
+        let rl = RateLimiter::with_clock_and_store(clock.clone(), store("test:limiter:"));
+        let rate_limit = RateLimit::new(3, Duration::from_secs(3));
+        let key = "key".to_string();
+
+        for _ in 0..3 {
+            rl.check(key.clone(), &rate_limit, 1).await.unwrap();
+        }
+        let Err(RateLimiterError::Gcra(GcraError::DeniedUntil { next_allowed_at })) =
+            rl.check(key.clone(), &rate_limit, 1).await
+        else {
+            panic!("Fourth check should have been denied");
+        };
+        assert_eq!(clock.now() + Duration::from_secs(1), next_allowed_at);
+
+        clock.advance_by(Duration::from_secs(1));
+        rl.check(key.clone(), &rate_limit, 1).await.unwrap();
+
+        assert!(
+            matches!(
+                rl.check(key.clone(), &rate_limit, 4).await,
+                Err(RateLimiterError::Gcra(GcraError::DeniedIndefinitely {
+                    cost: 4,
+                    ..
+                }))
+            ),
+            "Costs over the limit can never be allowed"
+        );
+        assert_eq!(Ok(None), rl.store().get(&key));
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_policy_mismatch() {
+        let clock = FakeClock::new();
+        let rl = RateLimiter::with_clock_and_store(clock.clone(), store("test:policy:"));
+        rl.register_policy("a", RateLimit::per_sec(1));
+        rl.register_policy("b", RateLimit::per_sec(1));
+        let key = "key".to_string();
+
+        rl.check_policy(key.clone(), "a", 1).await.unwrap();
+        assert_eq!(
+            Err(RateLimiterError::PolicyMismatch {
+                requested: Some("b".to_string()),
+                recorded: Some("a".to_string()),
+            }),
+            rl.check_policy(key.clone(), "b", 1).await
+        );
+
+        clock.advance_by(Duration::from_secs(1));
+        rl.check_policy(key.clone(), "b", 1).await.unwrap();
+        assert_eq!(
+            Some("b"),
+            rl.store().get(&key).unwrap().unwrap().policy.as_deref()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_checks_use_pooled_connections() {
+        let rl = Arc::new(RateLimiter::with_store(
+            store("test:pool:").with_max_idle(2),
+        ));
+        let rate_limit = RateLimit::per_sec(100);
+
+        let checks: Vec<_> = (0..8)
+            .map(|i| {
+                let rl = rl.clone();
+                let rate_limit = rate_limit.clone();
+                tokio::spawn(async move { rl.check(format!("key{}", i % 2), &rate_limit, 1).await })
+            })
+            .collect();
+        for check in checks {
+            assert!(check.await.unwrap().is_ok());
+        }
+
+        let idle = rl.store().idle.lock().unwrap().len();
+        assert!((1..=2).contains(&idle), "{idle} idle connections");
+        assert_eq!(Ok(2), rl.store().scan_len());
+    }
+
+    #[tokio::test]
+    async fn store_errors_are_reported() {
+        // Nothing listens on the discard port
+        let store = RedisStore::<String>::open("redis://127.0.0.1:9/").unwrap();
+        let rl = RateLimiter::with_store(store);
+        assert!(matches!(
+            rl.check("key".to_string(), &RateLimit::per_sec(1), 1).await,
+            Err(RateLimiterError::Store(_))
+        ));
+    }
+}
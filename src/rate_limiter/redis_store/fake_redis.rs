@@ -0,0 +1,354 @@
+//! Just enough of a Redis server to test [RedisStore](super::RedisStore) without one, running
+//! scripts in an embedded Lua 5.1 like the real thing.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use mlua::{Lua, Variadic};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Status("OK".to_string())
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Reply::Error(message.into())
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(out, "+{status}\r\n"),
+            Reply::Error(error) => write!(out, "-{error}\r\n"),
+            Reply::Integer(i) => write!(out, ":{i}\r\n"),
+            Reply::Bulk(None) => write!(out, "$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                write!(out, "\r\n")
+            }
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(out))
+            }
+        }
+    }
+}
+
+struct Value {
+    bytes: Vec<u8>,
+    expires_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Db {
+    values: HashMap<Vec<u8>, Value>,
+    scripts: HashMap<String, String>,
+}
+
+pub struct FakeRedis {
+    addr: SocketAddr,
+}
+
+impl FakeRedis {
+    /// Listens on a random local port until the test process exits.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let db = Arc::new(Mutex::new(Db::default()));
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let db = db.clone();
+                thread::spawn(move || serve(stream, &db));
+            }
+        });
+        Self { addr }
+    }
+
+    pub fn url(&self) -> String {
+        format!("redis://{}/", self.addr)
+    }
+}
+
+fn serve(stream: TcpStream, db: &Mutex<Db>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    while let Some(command) = read_command(&mut reader)? {
+        let reply = db
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .execute(&command);
+        reply.write_to(&mut writer)?;
+    }
+    Ok(())
+}
+
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(count) = read_header(reader, b'*')? else {
+        return Ok(None);
+    };
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = read_header(reader, b'$')?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn read_header(reader: &mut impl BufRead, kind: u8) -> io::Result<Option<usize>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    line.strip_prefix(kind as char)
+        .and_then(|len| len.trim_end().parse().ok())
+        .map(Some)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, line))
+}
+
+impl Db {
+    fn execute(&mut self, command: &[Vec<u8>]) -> Reply {
+        let Some(name) = command.first() else {
+            return Reply::error("ERR empty command");
+        };
+        let args: Vec<&[u8]> = command[1..].iter().map(Vec::as_slice).collect();
+        match (name.to_ascii_uppercase().as_slice(), args.as_slice()) {
+            (b"PING", []) => Reply::Status("PONG".to_string()),
+            (b"GET", [key]) => Reply::Bulk(self.get(key).map(|value| value.bytes.clone())),
+            (b"SET", [key, value, options @ ..]) => self.set(key, value, options),
+            (b"DEL", keys) => {
+                let removed = keys
+                    .iter()
+                    .filter(|key| self.get(key).is_some() && self.values.remove(**key).is_some())
+                    .count();
+                Reply::Integer(removed as i64)
+            }
+            (b"PTTL", [key]) => match self.get(key) {
+                None => Reply::Integer(-2),
+                Some(Value {
+                    expires_at: None, ..
+                }) => Reply::Integer(-1),
+                Some(Value {
+                    expires_at: Some(expires_at),
+                    ..
+                }) => Reply::Integer(
+                    expires_at
+                        .saturating_duration_since(Instant::now())
+                        .as_millis() as i64,
+                ),
+            },
+            (b"SCAN", [_cursor, options @ ..]) => self.scan(options),
+            (b"SCRIPT", [subcommand, script]) if subcommand.eq_ignore_ascii_case(b"LOAD") => {
+                let script = String::from_utf8_lossy(script).into_owned();
+                let sha = redis::Script::new(&script).get_hash().to_string();
+                self.scripts.insert(sha.clone(), script);
+                Reply::Bulk(Some(sha.into_bytes()))
+            }
+            (b"EVAL", [script, rest @ ..]) => {
+                let script = String::from_utf8_lossy(script).into_owned();
+                self.eval(&script, rest)
+            }
+            (b"EVALSHA", [sha, rest @ ..]) => {
+                let sha = String::from_utf8_lossy(sha).to_lowercase();
+                match self.scripts.get(&sha).cloned() {
+                    Some(script) => self.eval(&script, rest),
+                    None => Reply::error("NOSCRIPT No matching script. Please use EVAL."),
+                }
+            }
+            (name, _) => Reply::error(format!(
+                "ERR unknown command '{}'",
+                String::from_utf8_lossy(name)
+            )),
+        }
+    }
+
+    fn get(&mut self, key: &[u8]) -> Option<&Value> {
+        let expired = self
+            .values
+            .get(key)?
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Instant::now());
+        if expired {
+            self.values.remove(key);
+            return None;
+        }
+        self.values.get(key)
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8], options: &[&[u8]]) -> Reply {
+        let expires_at = match options {
+            [] => None,
+            [px, millis] if px.eq_ignore_ascii_case(b"PX") => {
+                match std::str::from_utf8(millis)
+                    .ok()
+                    .and_then(|m| m.parse().ok())
+                {
+                    Some(millis) if millis > 0 => {
+                        Some(Instant::now() + Duration::from_millis(millis))
+                    }
+                    _ => return Reply::error("ERR invalid expire time in 'set' command"),
+                }
+            }
+            _ => return Reply::error("ERR syntax error"),
+        };
+        self.values.insert(
+            key.to_vec(),
+            Value {
+                bytes: value.to_vec(),
+                expires_at,
+            },
+        );
+        Reply::ok()
+    }
+
+    /// Returns every match in a single batch.
+    fn scan(&mut self, options: &[&[u8]]) -> Reply {
+        let mut pattern: &[u8] = b"*";
+        for option in options.chunks(2) {
+            match option {
+                [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = value,
+                [name, _] if name.eq_ignore_ascii_case(b"COUNT") => {}
+                _ => return Reply::error("ERR syntax error"),
+            }
+        }
+        let keys: Vec<Vec<u8>> = self.values.keys().cloned().collect();
+        let matches = keys
+            .into_iter()
+            .filter(|key| self.get(key).is_some() && glob_matches(pattern, key))
+            .map(|key| Reply::Bulk(Some(key)))
+            .collect();
+        Reply::Array(vec![
+            Reply::Bulk(Some(b"0".to_vec())),
+            Reply::Array(matches),
+        ])
+    }
+
+    fn eval(&mut self, script: &str, args: &[&[u8]]) -> Reply {
+        let Some((num_keys, args)) = args.split_first() else {
+            return Reply::error("ERR wrong number of arguments for 'eval' command");
+        };
+        let num_keys = match std::str::from_utf8(num_keys)
+            .ok()
+            .and_then(|n| n.parse().ok())
+        {
+            Some(num_keys) if num_keys <= args.len() => num_keys,
+            _ => return Reply::error("ERR Number of keys can't be greater than number of args"),
+        };
+        let (keys, argv) = args.split_at(num_keys);
+
+        let lua = Lua::new();
+        let result = lua.scope(|scope| {
+            let call = scope.create_function_mut(|lua, args: Variadic<mlua::String>| {
+                let command: Vec<Vec<u8>> =
+                    args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+                if command
+                    .first()
+                    .is_some_and(|name| name.to_ascii_uppercase().starts_with(b"EVAL"))
+                {
+                    return Err(mlua::Error::runtime("EVAL can't be called from scripts"));
+                }
+                match self.execute(&command) {
+                    Reply::Error(error) => Err(mlua::Error::runtime(error)),
+                    reply => to_lua(lua, reply),
+                }
+            })?;
+            let redis = lua.create_table()?;
+            redis.set("call", call)?;
+            let globals = lua.globals();
+            globals.set("redis", redis)?;
+            globals.set(
+                "KEYS",
+                lua.create_sequence_from(
+                    keys.iter()
+                        .map(|key| lua.create_string(key))
+                        .collect::<Result<Vec<_>, _>>()?,
+                )?,
+            )?;
+            globals.set(
+                "ARGV",
+                lua.create_sequence_from(
+                    argv.iter()
+                        .map(|arg| lua.create_string(arg))
+                        .collect::<Result<Vec<_>, _>>()?,
+                )?,
+            )?;
+            lua.load(script).eval().map(from_lua)
+        });
+        result.unwrap_or_else(|e| Reply::error(format!("ERR {e}")))
+    }
+}
+
+fn to_lua(lua: &Lua, reply: Reply) -> mlua::Result<mlua::Value<'_>> {
+    Ok(match reply {
+        Reply::Bulk(Some(bytes)) => mlua::Value::String(lua.create_string(bytes)?),
+        Reply::Bulk(None) => mlua::Value::Boolean(false),
+        Reply::Integer(i) => mlua::Value::Integer(i),
+        Reply::Status(status) => {
+            let table = lua.create_table()?;
+            table.set("ok", status)?;
+            mlua::Value::Table(table)
+        }
+        Reply::Error(error) => return Err(mlua::Error::runtime(error)),
+        Reply::Array(items) => {
+            let items = items
+                .into_iter()
+                .map(|item| to_lua(lua, item))
+                .collect::<mlua::Result<Vec<_>>>()?;
+            mlua::Value::Table(lua.create_sequence_from(items)?)
+        }
+    })
+}
+
+/// Same conversions as Redis, numbers are truncated to integers.
+fn from_lua(value: mlua::Value) -> Reply {
+    match value {
+        mlua::Value::String(s) => Reply::Bulk(Some(s.as_bytes().to_vec())),
+        mlua::Value::Integer(i) => Reply::Integer(i),
+        mlua::Value::Number(n) => Reply::Integer(n as i64),
+        mlua::Value::Boolean(true) => Reply::Integer(1),
+        mlua::Value::Table(table) => {
+            if let Ok(Some(status)) = table.get::<_, Option<String>>("ok") {
+                return Reply::Status(status);
+            }
+            if let Ok(Some(error)) = table.get::<_, Option<String>>("err") {
+                return Reply::Error(error);
+            }
+            Reply::Array(
+                table
+                    .sequence_values::<mlua::Value>()
+                    .map(|item| item.map(from_lua).unwrap_or(Reply::Bulk(None)))
+                    .collect(),
+            )
+        }
+        _ => Reply::Bulk(None),
+    }
+}
+
+/// Glob matching as used by `SCAN MATCH`, supporting `*`, `?` and `\` escapes.
+fn glob_matches(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern {
+        [] => s.is_empty(),
+        [b'*', rest @ ..] => (0..=s.len()).any(|skip| glob_matches(rest, &s[skip..])),
+        [b'?', rest @ ..] => !s.is_empty() && glob_matches(rest, &s[1..]),
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => {
+            s.first() == Some(c) && glob_matches(rest, &s[1..])
+        }
+    }
+}
@@ -0,0 +1,226 @@
+#[cfg(feature = "metrics")]
+use std::{
+    collections::HashMap,
+    sync::{PoisonError, RwLock},
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[cfg(feature = "metrics")]
+use crate::rate_limiter::FxBuildHasher;
+use crate::Cost;
+
+/// A point in time snapshot of what a [RateLimiter](crate::RateLimiter) has been doing.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct RateLimiterStats {
+    /// Number of checks that were allowed
+    pub allowed: u64,
+    /// Number of checks denied with [GcraError::DeniedUntil](crate::GcraError::DeniedUntil)
+    pub denied: u64,
+    /// Number of checks denied with
+    /// [GcraError::DeniedIndefinitely](crate::GcraError::DeniedIndefinitely)
+    pub denied_indefinitely: u64,
+    /// Total cost of all allowed checks, in whole units
+    pub cost_consumed: u64,
+    /// Number of entries currently tracked, 0 if the store doesn't count them
+    pub entries: usize,
+    /// Number of entries removed because they expired or their policy was reset
+    pub evicted: u64,
+    /// Number of times [RateLimiter::prune_expired](crate::RateLimiter::prune_expired) ran
+    pub prunes: u64,
+    /// Total time spent pruning
+    pub prune_duration: Duration,
+}
+
+/// Lock free counters backing [RateLimiterStats], also forwarded to the `metrics` facade when
+/// the `metrics` feature is enabled.
+#[derive(Default, Debug)]
+pub(super) struct LimiterStats {
+    /// Value of the `limiter` label on all metrics
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub(super) name: Option<Arc<str>>,
+    /// Metric keys of each policy seen so far, so checks don't rebuild their labels
+    #[cfg(feature = "metrics")]
+    policy_keys: RwLock<HashMap<Option<Arc<str>>, Arc<PolicyKeys>, FxBuildHasher>>,
+    allowed: AtomicU64,
+    denied: AtomicU64,
+    denied_indefinitely: AtomicU64,
+    cost_consumed: AtomicU64,
+    /// Sum of the fractional parts of the costs, carried into `cost_consumed` as they add up
+    cost_fractions: AtomicU64,
+    evicted: AtomicU64,
+    prunes: AtomicU64,
+    prune_nanos: AtomicU64,
+}
+
+#[cfg(feature = "metrics")]
+const DEFAULT_LIMITER_LABEL: &str = "default";
+#[cfg(feature = "metrics")]
+static METADATA: metrics::Metadata<'static> =
+    metrics::Metadata::new(module_path!(), metrics::Level::INFO, Some(module_path!()));
+/// Label used for checks against an ad-hoc [RateLimit](crate::RateLimit)
+#[cfg(feature = "metrics")]
+const AD_HOC_POLICY_LABEL: &str = "ad-hoc";
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+impl LimiterStats {
+    pub(super) fn record_allowed(&self, policy: Option<&Arc<str>>, cost: Cost) {
+        self.allowed.fetch_add(1, Ordering::Relaxed);
+        let fraction = (cost.parts() % u128::from(Cost::SCALE)) as u64;
+        let carried = match fraction {
+            0 => 0,
+            fraction => {
+                let before = self.cost_fractions.fetch_add(fraction, Ordering::Relaxed);
+                (before + fraction) / Cost::SCALE - before / Cost::SCALE
+            }
+        };
+        let cost = cost.floor().saturating_add(carried);
+        self.cost_consumed.fetch_add(cost, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        {
+            let keys = self.policy_keys(policy);
+            increment(&keys.allowed, 1);
+            increment(&keys.cost_consumed, cost);
+        }
+    }
+
+    pub(super) fn record_denied(&self, policy: Option<&Arc<str>>) {
+        self.denied.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        increment(&self.policy_keys(policy).denied, 1);
+    }
+
+    pub(super) fn record_denied_indefinitely(&self, policy: Option<&Arc<str>>) {
+        self.denied_indefinitely.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        increment(&self.policy_keys(policy).denied_indefinitely, 1);
+    }
+
+    pub(super) fn record_evicted(&self, policy: Option<&Arc<str>>, evicted: u64) {
+        self.evicted.fetch_add(evicted, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        increment(&self.policy_keys(policy).evicted, evicted);
+    }
+
+    pub(super) fn record_prune(&self, duration: Duration, entries: Option<usize>) {
+        self.prunes.fetch_add(1, Ordering::Relaxed);
+        self.prune_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        {
+            let labels = self.limiter_labels();
+            metrics::histogram!("gcra_prune_duration_seconds", labels.clone()).record(duration);
+            if let Some(entries) = entries {
+                metrics::gauge!("gcra_entries", labels).set(entries as f64);
+            }
+        }
+    }
+
+    pub(super) fn snapshot(&self, entries: usize) -> RateLimiterStats {
+        RateLimiterStats {
+            allowed: self.allowed.load(Ordering::Relaxed),
+            denied: self.denied.load(Ordering::Relaxed),
+            denied_indefinitely: self.denied_indefinitely.load(Ordering::Relaxed),
+            cost_consumed: self.cost_consumed.load(Ordering::Relaxed),
+            entries,
+            evicted: self.evicted.load(Ordering::Relaxed),
+            prunes: self.prunes.load(Ordering::Relaxed),
+            prune_duration: Duration::from_nanos(self.prune_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn limiter_label(&self) -> metrics::SharedString {
+        match &self.name {
+            Some(name) => name.clone().into(),
+            None => DEFAULT_LIMITER_LABEL.into(),
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn limiter_labels(&self) -> Vec<metrics::Label> {
+        vec![metrics::Label::new("limiter", self.limiter_label())]
+    }
+
+    #[cfg(feature = "metrics")]
+    fn policy_keys(&self, policy: Option<&Arc<str>>) -> Arc<PolicyKeys> {
+        let cached = self
+            .policy_keys
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&policy.cloned())
+            .cloned();
+        if let Some(keys) = cached {
+            return keys;
+        }
+
+        let label: metrics::SharedString = match policy {
+            Some(policy) => policy.clone().into(),
+            None => AD_HOC_POLICY_LABEL.into(),
+        };
+        let labels = vec![
+            metrics::Label::new("limiter", self.limiter_label()),
+            metrics::Label::new("policy", label),
+        ];
+        let key = |name: &'static str| metrics::Key::from_parts(name, labels.clone());
+        let keys = Arc::new(PolicyKeys {
+            allowed: key("gcra_allowed_total"),
+            cost_consumed: key("gcra_cost_consumed_total"),
+            denied: key("gcra_denied_total"),
+            denied_indefinitely: key("gcra_denied_indefinitely_total"),
+            evicted: key("gcra_evicted_total"),
+        });
+        self.policy_keys
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(policy.cloned())
+            .or_insert(keys)
+            .clone()
+    }
+}
+
+/// Keys of the per policy counters.
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+struct PolicyKeys {
+    allowed: metrics::Key,
+    cost_consumed: metrics::Key,
+    denied: metrics::Key,
+    denied_indefinitely: metrics::Key,
+    evicted: metrics::Key,
+}
+
+/// Same as `metrics::counter!(..).increment(value)`, without building the key.
+#[cfg(feature = "metrics")]
+fn increment(key: &metrics::Key, value: u64) {
+    metrics::with_recorder(|recorder| recorder.register_counter(key, &METADATA).increment(value));
+}
+
+impl Clone for LimiterStats {
+    fn clone(&self) -> Self {
+        let copy = |counter: &AtomicU64| AtomicU64::new(counter.load(Ordering::Relaxed));
+        Self {
+            name: self.name.clone(),
+            #[cfg(feature = "metrics")]
+            policy_keys: Default::default(),
+            allowed: copy(&self.allowed),
+            denied: copy(&self.denied),
+            denied_indefinitely: copy(&self.denied_indefinitely),
+            cost_consumed: copy(&self.cost_consumed),
+            cost_fractions: copy(&self.cost_fractions),
+            evicted: copy(&self.evicted),
+            prunes: copy(&self.prunes),
+            prune_nanos: copy(&self.prune_nanos),
+        }
+    }
+}
@@ -0,0 +1,476 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, Hash},
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use thiserror::Error;
+
+use crate::{
+    rate_limiter::{FxBuildHasher, RateLimitEntry, RateLimitRequest, RateLimiterError},
+    Cost, GcraError, GcraState, RateLimit,
+};
+
+/// A [GcraStore] could not be reached or returned something unexpected.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Store failed: {reason}")]
+pub struct StoreError {
+    pub reason: String,
+}
+
+impl StoreError {
+    pub fn new(reason: impl ToString) -> Self {
+        Self {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// What a [GcraStore] keeps for a key: a TAT with a TTL, tagged with the policy it was set under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredTat {
+    /// GCRA's Theoretical Arrival Time
+    pub tat: Instant,
+    /// The TTL: the key has fully replenished by then, so stores may drop it any time after.
+    pub expires_at: Instant,
+    /// Name of the policy the TAT was set under. Unset for ad-hoc [RateLimit] checks.
+    pub policy: Option<Arc<str>>,
+}
+
+impl StoredTat {
+    /// The part of `entry` worth storing, `None` if it carries no state.
+    pub fn from_entry(entry: &RateLimitEntry) -> Option<Self> {
+        Some(Self {
+            tat: entry.tat?,
+            expires_at: entry.expires_at?,
+            policy: entry.policy.clone(),
+        })
+    }
+}
+
+impl From<StoredTat> for RateLimitEntry {
+    fn from(stored: StoredTat) -> Self {
+        RateLimitEntry {
+            gcra_state: GcraState {
+                tat: Some(stored.tat),
+            },
+            expires_at: Some(stored.expires_at),
+            policy: stored.policy,
+        }
+    }
+}
+
+/// Storage for the state of every key tracked by a [RateLimiter](crate::RateLimiter).
+///
+/// Implementations only need to provide an atomic compare-and-swap of a key's [StoredTat], the
+/// GCRA logic stays in the limiter.
+pub trait GcraStore<K> {
+    /// Returns the TAT currently stored for `key`.
+    fn get(&self, key: &K) -> Result<Option<StoredTat>, StoreError>;
+
+    /// Atomically replaces the TAT of `key` with `new`, but only if the stored TAT still equals
+    /// `current`. A `None` TAT means the key is absent, so a `new` of `None` removes it.
+    ///
+    /// Returns whether the TAT was swapped.
+    fn compare_and_swap(
+        &self,
+        key: &K,
+        current: Option<Instant>,
+        new: Option<StoredTat>,
+    ) -> Result<bool, StoreError>;
+
+    /// Visits every key as a [RateLimitEntry], allowing it to be modified, and removes those for
+    /// which `keep` returns `false`.
+    fn retain(&self, keep: impl FnMut(&K, &mut RateLimitEntry) -> bool) -> Result<(), StoreError>;
+
+    /// Number of entries currently stored, only used for statistics. Stores that can't count
+    /// their entries cheaply may fail instead.
+    fn len(&self) -> Result<usize, StoreError>;
+
+    fn is_empty(&self) -> Result<bool, StoreError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Atomically reads, modifies and writes back the entry for `key`.
+    ///
+    /// The default implementation retries [GcraStore::compare_and_swap] until it succeeds, so
+    /// `modify` may be called more than once. Stores that can lock a key should override this.
+    fn update<R>(
+        &self,
+        key: &K,
+        mut modify: impl FnMut(&mut Option<RateLimitEntry>) -> R,
+    ) -> Result<R, StoreError> {
+        loop {
+            let current = self.get(key)?.map(RateLimitEntry::from);
+            let mut new = current.clone();
+            let result = modify(&mut new);
+            if new == current {
+                return Ok(result);
+            }
+            let current_tat = current.and_then(|entry| entry.tat);
+            let new = new.as_ref().and_then(StoredTat::from_entry);
+            if self.compare_and_swap(key, current_tat, new)? {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Runs a full GCRA check of `key` under `policy`, see
+    /// [RateLimiter::check_policy](crate::RateLimiter::check_policy) for the semantics.
+    ///
+    /// Returns the state of `key` after the check along with the result. The default
+    /// implementation runs the check through [GcraStore::update], stores that can run it natively
+    /// (e.g. server side) should override this.
+    fn check_and_modify_at(
+        &self,
+        key: &K,
+        policy: Option<&Arc<str>>,
+        rate_limit: &RateLimit,
+        arrived_at: Instant,
+        cost: Cost,
+    ) -> (GcraState, Result<Instant, RateLimiterError>) {
+        let mut state = GcraState::default();
+        let result = self.update(key, |entry| {
+            let result = check_entry_at(entry, policy, rate_limit, arrived_at, cost);
+            state = entry
+                .as_ref()
+                .map(|entry| entry.gcra_state)
+                .unwrap_or_default();
+            result
+        });
+        match result {
+            Ok(result) => (state, result),
+            Err(e) => (state, Err(e.into())),
+        }
+    }
+}
+
+/// Checks and updates a stored entry, creating it if needed.
+fn check_entry_at(
+    entry: &mut Option<RateLimitEntry>,
+    policy: Option<&Arc<str>>,
+    rate_limit: &RateLimit,
+    arrived_at: Instant,
+    cost: Cost,
+) -> Result<Instant, RateLimiterError> {
+    let entry_ref = entry.get_or_insert_with(RateLimitEntry::default);
+    if entry_ref.policy.as_ref() != policy {
+        // Entries that have fully replenished carry no state, so they can switch policies
+        let is_active = entry_ref.tat.is_some_and(|tat| tat > arrived_at);
+        if is_active {
+            return Err(RateLimiterError::PolicyMismatch {
+                requested: policy.map(|policy| policy.to_string()),
+                recorded: entry_ref.policy.as_ref().map(|policy| policy.to_string()),
+            });
+        }
+        entry_ref.policy = policy.cloned();
+    }
+
+    let result = entry_ref
+        .check_and_modify_at(rate_limit, arrived_at, cost)
+        .map(|_| {
+            entry_ref.update_expiration(rate_limit);
+            // Guaranteed to be set from update_expiration
+            entry_ref.expires_at.unwrap()
+        });
+    if let Err(GcraError::DeniedIndefinitely { .. }) = result {
+        // No need to keep this in the store
+        *entry = None;
+    }
+    result.map_err(Into::into)
+}
+
+/// The default sharded in-process [GcraStore], backed by a [DashMap].
+#[derive(Clone)]
+pub struct DashMapStore<T: Eq + Hash, S = FxBuildHasher> {
+    map: DashMap<RateLimitRequest<T>, RateLimitEntry, S>,
+}
+
+impl<T: Eq + Hash, S: BuildHasher + Clone> DashMapStore<T, S> {
+    /// Same as [DashMapStore::with_shards], hashing keys with `hasher`.
+    pub fn with_shards_and_hasher(max_data_capacity: usize, num_shards: usize, hasher: S) -> Self {
+        Self {
+            map: DashMap::with_capacity_and_hasher_and_shard_amount(
+                max_data_capacity,
+                hasher,
+                num_shards,
+            ),
+        }
+    }
+}
+
+impl<T: Eq + Hash> DashMapStore<T, FxBuildHasher> {
+    pub fn new(max_data_capacity: usize) -> Self {
+        Self {
+            map: DashMap::with_capacity_and_hasher(max_data_capacity, FxBuildHasher::default()),
+        }
+    }
+
+    pub fn with_shards(max_data_capacity: usize, num_shards: usize) -> Self {
+        Self {
+            map: DashMap::with_capacity_and_hasher_and_shard_amount(
+                max_data_capacity,
+                FxBuildHasher::default(),
+                num_shards,
+            ),
+        }
+    }
+}
+
+impl<T, S> Default for DashMapStore<T, S>
+where
+    T: Eq + Hash,
+    S: Default + BuildHasher + Clone,
+{
+    fn default() -> Self {
+        Self {
+            map: DashMap::default(),
+        }
+    }
+}
+
+impl<T, S> GcraStore<T> for DashMapStore<T, S>
+where
+    T: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    fn get(&self, key: &T) -> Result<Option<StoredTat>, StoreError> {
+        Ok(self
+            .map
+            .get(key)
+            .and_then(|entry| StoredTat::from_entry(&entry)))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &T,
+        current: Option<Instant>,
+        new: Option<StoredTat>,
+    ) -> Result<bool, StoreError> {
+        let request_key = RateLimitRequest { key: key.clone() };
+        match self.map.entry(request_key) {
+            Entry::Occupied(mut occupied) => {
+                if occupied.get().tat != current {
+                    return Ok(false);
+                }
+                match new {
+                    Some(new) => *occupied.get_mut() = new.into(),
+                    None => {
+                        occupied.remove();
+                    }
+                }
+            }
+            Entry::Vacant(vacant) => {
+                if current.is_some() {
+                    return Ok(false);
+                }
+                if let Some(new) = new {
+                    vacant.insert(new.into());
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn retain(
+        &self,
+        mut keep: impl FnMut(&T, &mut RateLimitEntry) -> bool,
+    ) -> Result<(), StoreError> {
+        self.map
+            .retain(|request_key, entry| keep(&request_key.key, entry));
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize, StoreError> {
+        Ok(self.map.len())
+    }
+
+    /// Holds the shard lock for `key` while `modify` runs, so it is only ever called once.
+    fn update<R>(
+        &self,
+        key: &T,
+        mut modify: impl FnMut(&mut Option<RateLimitEntry>) -> R,
+    ) -> Result<R, StoreError> {
+        let request_key = RateLimitRequest { key: key.clone() };
+        match self.map.entry(request_key) {
+            Entry::Occupied(mut occupied) => {
+                let mut entry = Some(std::mem::take(occupied.get_mut()));
+                let result = modify(&mut entry);
+                match entry {
+                    Some(entry) => *occupied.get_mut() = entry,
+                    None => {
+                        occupied.remove();
+                    }
+                }
+                Ok(result)
+            }
+            Entry::Vacant(vacant) => {
+                let mut entry = None;
+                let result = modify(&mut entry);
+                if let Some(entry) = entry {
+                    vacant.insert(entry);
+                }
+                Ok(result)
+            }
+        }
+    }
+}
+
+/// A simple [GcraStore] behind a single [Mutex], relying on the default compare-and-swap based
+/// [GcraStore::update]. Intended as a reference implementation for tests.
+#[derive(Debug)]
+pub struct MemoryStore<T> {
+    map: Mutex<HashMap<T, StoredTat>>,
+}
+
+impl<T> Default for MemoryStore<T> {
+    fn default() -> Self {
+        Self {
+            map: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> Clone for MemoryStore<T> {
+    fn clone(&self) -> Self {
+        let map = self.map.lock().unwrap_or_else(PoisonError::into_inner);
+        Self {
+            map: Mutex::new(map.clone()),
+        }
+    }
+}
+
+impl<T> GcraStore<T> for MemoryStore<T>
+where
+    T: Eq + Hash + Clone,
+{
+    fn get(&self, key: &T) -> Result<Option<StoredTat>, StoreError> {
+        let map = self.map.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(map.get(key).cloned())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &T,
+        current: Option<Instant>,
+        new: Option<StoredTat>,
+    ) -> Result<bool, StoreError> {
+        let mut map = self.map.lock().unwrap_or_else(PoisonError::into_inner);
+        if map.get(key).map(|stored| stored.tat) != current {
+            return Ok(false);
+        }
+        match new {
+            Some(new) => map.insert(key.clone(), new),
+            None => map.remove(key),
+        };
+        Ok(true)
+    }
+
+    fn retain(
+        &self,
+        mut keep: impl FnMut(&T, &mut RateLimitEntry) -> bool,
+    ) -> Result<(), StoreError> {
+        let mut map = self.map.lock().unwrap_or_else(PoisonError::into_inner);
+        map.retain(|key, stored| {
+            let mut entry = RateLimitEntry::from(stored.clone());
+            let keep = keep(key, &mut entry);
+            match StoredTat::from_entry(&entry) {
+                Some(entry) if keep => {
+                    *stored = entry;
+                    true
+                }
+                _ => false,
+            }
+        });
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize, StoreError> {
+        let map = self.map.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(map.len())
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    pub(crate) fn stored(tat: Instant) -> StoredTat {
+        StoredTat {
+            tat,
+            expires_at: tat + Duration::from_secs(1),
+            policy: None,
+        }
+    }
+
+    fn assert_compare_and_swap(store: impl GcraStore<&'static str>) {
+        let now = Instant::now();
+        let first = stored(now);
+        let second = stored(now + Duration::from_secs(1));
+
+        assert_eq!(
+            Ok(true),
+            store.compare_and_swap(&"key", None, Some(first.clone()))
+        );
+        assert_eq!(Ok(Some(first.clone())), store.get(&"key"));
+        assert_eq!(
+            Ok(false),
+            store.compare_and_swap(&"key", None, Some(second.clone())),
+            "Swapping from a stale TAT should fail"
+        );
+        assert_eq!(
+            Ok(true),
+            store.compare_and_swap(&"key", Some(first.tat), Some(second.clone()))
+        );
+        assert_eq!(Ok(Some(second.clone())), store.get(&"key"));
+
+        assert_eq!(
+            Ok(false),
+            store.compare_and_swap(&"other", Some(first.tat), None),
+            "Absent keys only match None"
+        );
+        assert_eq!(
+            Ok(true),
+            store.compare_and_swap(&"key", Some(second.tat), None)
+        );
+        assert_eq!(Ok(true), store.is_empty());
+    }
+
+    fn assert_update_and_retain(store: impl GcraStore<&'static str>) {
+        let now = Instant::now();
+        for (key, tat) in [("old", now), ("new", now + Duration::from_secs(1))] {
+            let updated = store.update(&key, |entry| {
+                assert!(entry.is_none());
+                *entry = Some(stored(tat).into());
+            });
+            assert_eq!(Ok(()), updated);
+        }
+        assert_eq!(Ok(2), store.len());
+
+        assert_eq!(Ok(()), store.retain(|_key, entry| entry.tat > Some(now)));
+        assert_eq!(Ok(None), store.get(&"old"));
+        assert_eq!(
+            Ok(Some(stored(now + Duration::from_secs(1)))),
+            store.get(&"new")
+        );
+
+        assert_eq!(Ok(()), store.update(&"new", |entry| *entry = None));
+        assert_eq!(Ok(true), store.is_empty());
+    }
+
+    #[test]
+    fn dash_map_store() {
+        assert_compare_and_swap(DashMapStore::with_shards(4, 2));
+        assert_update_and_retain(DashMapStore::with_shards(4, 2));
+    }
+
+    #[test]
+    fn memory_store() {
+        assert_compare_and_swap(MemoryStore::default());
+        assert_update_and_retain(MemoryStore::default());
+    }
+}
@@ -0,0 +1,504 @@
+//! A server speaking the Redis protocol (RESP) which implements redis-cell's `CL.THROTTLE` on
+//! top of a [RateLimiter], so existing Redis clients can use it unchanged.
+//!
+//! # Commands
+//! - `CL.THROTTLE key max_burst count period [quantity]`, see [RateLimiter::throttle].
+//! - `CL.INSPECT key` returns the key's state as field/value pairs like `HGETALL`: `policy`,
+//!   `reset_after_ms` and `expires_after_ms`. Returns nil for unknown keys.
+//! - `PING [message]`
+//! - `QUIT`
+
+use std::{
+    future::Future,
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    clock::{Clock, InstantClock},
+    GcraStore, RateLimiter, RateLimiterError,
+};
+
+/// Longest line accepted from clients, including inline commands.
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// Longest bulk string argument accepted from clients. Commands only take keys and numbers.
+const MAX_BULK_LEN: usize = 1024 * 1024;
+/// Most arguments accepted in a single command.
+const MAX_ARGS: usize = 1024;
+
+/// A single RESP2 value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Frame>),
+}
+
+impl Frame {
+    pub fn ok() -> Self {
+        Frame::Simple("OK".to_string())
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Frame::Error(message.into())
+    }
+
+    /// Appends the wire representation of the frame to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Frame::Simple(s) => out.extend_from_slice(format!("+{s}\r\n").as_bytes()),
+            Frame::Error(e) => out.extend_from_slice(format!("-{e}\r\n").as_bytes()),
+            Frame::Integer(i) => out.extend_from_slice(format!(":{i}\r\n").as_bytes()),
+            Frame::Bulk(bytes) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            Frame::Null => out.extend_from_slice(b"$-1\r\n"),
+            Frame::Array(frames) => {
+                out.extend_from_slice(format!("*{}\r\n", frames.len()).as_bytes());
+                frames.iter().for_each(|frame| frame.encode(out));
+            }
+        }
+    }
+}
+
+/// Reads the next command sent by a client, either as an array of bulk strings or inline as
+/// sent by `telnet`. Returns `None` once the client disconnects.
+pub async fn read_command<R>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        let Some(line) = read_line(reader).await? else {
+            return Ok(None);
+        };
+        let Some(count) = line.strip_prefix(b"*") else {
+            let args: Vec<Vec<u8>> = line
+                .split(u8::is_ascii_whitespace)
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        };
+
+        let count = parse_len(count)?;
+        if count > MAX_ARGS {
+            return Err(invalid_data("too many arguments"));
+        }
+        let mut args = Vec::with_capacity(count.min(64));
+        for _ in 0..count {
+            let line = read_line(reader)
+                .await?
+                .ok_or(io::ErrorKind::UnexpectedEof)?;
+            let len = line
+                .strip_prefix(b"$")
+                .ok_or_else(|| invalid_data("expected '$'"))
+                .and_then(parse_len)?;
+            if len > MAX_BULK_LEN {
+                return Err(invalid_data("invalid bulk length"));
+            }
+            // Grows with the bytes actually received rather than trusting the announced length
+            let mut arg = Vec::new();
+            let expected = len as u64 + 2;
+            if (&mut *reader).take(expected).read_to_end(&mut arg).await? as u64 != expected {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if !arg.ends_with(b"\r\n") {
+                return Err(invalid_data("expected CRLF after bulk string"));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        return Ok(Some(args));
+    }
+}
+
+async fn read_line<R>(reader: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    let limit = MAX_LINE_LEN as u64 + 2;
+    if (&mut *reader)
+        .take(limit)
+        .read_until(b'\n', &mut line)
+        .await?
+        == 0
+    {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") && line.len() as u64 == limit {
+        return Err(invalid_data("line too long"));
+    }
+    while line.last().is_some_and(|&c| c == b'\n' || c == b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(len: &[u8]) -> io::Result<usize> {
+    std::str::from_utf8(len)
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .ok_or_else(|| invalid_data("invalid length"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Protocol error: {message}"),
+    )
+}
+
+/// Serves `CL.THROTTLE` backed by a [RateLimiter] keyed by the raw key strings.
+pub struct RespServer<C = InstantClock> {
+    limiter: Arc<RateLimiter<String, C>>,
+}
+
+impl<C> Clone for RespServer<C> {
+    fn clone(&self) -> Self {
+        Self {
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+impl<C> RespServer<C>
+where
+    C: Clock + Send + Sync + 'static,
+{
+    pub fn new(limiter: Arc<RateLimiter<String, C>>) -> Self {
+        Self { limiter }
+    }
+
+    pub fn limiter(&self) -> &Arc<RateLimiter<String, C>> {
+        &self.limiter
+    }
+
+    /// Accepts connections until `shutdown` completes. Connections already established are
+    /// left to finish on their own.
+    pub async fn serve(
+        &self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> io::Result<()> {
+        tokio::pin!(shutdown);
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => accepted?.0,
+                _ = &mut shutdown => return Ok(()),
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                // Errors only ever concern the one connection
+                let _ = server.serve_connection(stream).await;
+            });
+        }
+    }
+
+    /// Answers commands from a single client until it disconnects or sends `QUIT`.
+    pub async fn serve_connection(&self, stream: TcpStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut out = Vec::new();
+        loop {
+            let command = match read_command(&mut reader).await {
+                Ok(Some(command)) => command,
+                Ok(None) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    // Like Redis, there is no way to resync after a protocol error
+                    Frame::error(format!("ERR {e}")).encode(&mut out);
+                    writer.write_all(&out).await?;
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+
+            let quit = command
+                .first()
+                .is_some_and(|name| name.eq_ignore_ascii_case(b"QUIT"));
+            let reply = if quit {
+                Frame::ok()
+            } else {
+                self.execute(&command).await
+            };
+            out.clear();
+            reply.encode(&mut out);
+            writer.write_all(&out).await?;
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs a single command, with its name as the first argument.
+    pub async fn execute(&self, command: &[Vec<u8>]) -> Frame {
+        let Some((name, args)) = command.split_first() else {
+            return Frame::error("ERR empty command");
+        };
+        let name = String::from_utf8_lossy(name).to_ascii_uppercase();
+        match (name.as_str(), args) {
+            ("PING", []) => Frame::Simple("PONG".to_string()),
+            ("PING", [message]) => Frame::Bulk(message.clone()),
+            ("CL.THROTTLE", [key, max_burst, count, period]) => {
+                self.throttle(key, max_burst, count, period, b"1").await
+            }
+            ("CL.THROTTLE", [key, max_burst, count, period, quantity]) => {
+                self.throttle(key, max_burst, count, period, quantity).await
+            }
+            ("CL.INSPECT", [key]) => self.inspect(key),
+            ("PING" | "CL.THROTTLE" | "CL.INSPECT", _) => Frame::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            )),
+            _ => Frame::error(format!(
+                "ERR unknown command '{}'",
+                String::from_utf8_lossy(&command[0])
+            )),
+        }
+    }
+
+    async fn throttle(
+        &self,
+        key: &[u8],
+        max_burst: &[u8],
+        count: &[u8],
+        period: &[u8],
+        quantity: &[u8],
+    ) -> Frame {
+        let (Some(max_burst), Some(count), Some(period), Some(quantity)) = (
+            parse_int(max_burst),
+            parse_int(count),
+            parse_int(period),
+            parse_int(quantity),
+        ) else {
+            return Frame::error("ERR value is not an integer or out of range");
+        };
+        let key = String::from_utf8_lossy(key).into_owned();
+        let throttled = self
+            .limiter
+            .throttle(key, max_burst, count, Duration::from_secs(period), quantity)
+            .await;
+        match throttled {
+            Ok(throttled) => Frame::Array(
+                <[i64; 5]>::from(throttled)
+                    .into_iter()
+                    .map(Frame::Integer)
+                    .collect(),
+            ),
+            Err(e @ RateLimiterError::InvalidThrottle { .. }) => Frame::error(format!("ERR {e}")),
+            Err(e @ RateLimiterError::PolicyMismatch { .. }) => {
+                Frame::error(format!("WRONGTYPE {e}"))
+            }
+            Err(e) => Frame::error(format!("ERR {e}")),
+        }
+    }
+
+    fn inspect(&self, key: &[u8]) -> Frame {
+        let key = String::from_utf8_lossy(key).into_owned();
+        let entry = match self.limiter.store().get(&key) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Frame::Null,
+            Err(e) => return Frame::error(format!("ERR {e}")),
+        };
+        let now = self.limiter.clock().now();
+        let millis_after = |instant: Instant| {
+            let after = instant.saturating_duration_since(now);
+            Frame::Integer(after.as_millis().try_into().unwrap_or(i64::MAX))
+        };
+        let field = |name: &str| Frame::Bulk(name.as_bytes().to_vec());
+        Frame::Array(vec![
+            field("policy"),
+            entry.policy.as_ref().map_or(Frame::Null, |policy| {
+                Frame::Bulk(policy.as_bytes().to_vec())
+            }),
+            field("reset_after_ms"),
+            millis_after(entry.tat),
+            field("expires_after_ms"),
+            millis_after(entry.expires_at),
+        ])
+    }
+}
+
+fn parse_int<T: std::str::FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use crate::clock::tests::FakeClock;
+
+    use super::*;
+
+    async fn start() -> (String, FakeClock) {
+        let clock = FakeClock::new();
+        let limiter = Arc::new(RateLimiter::with_clock(clock.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            RespServer::new(limiter)
+                .serve(listener, std::future::pending())
+                .await
+        });
+        (url, clock)
+    }
+
+    /// Runs `f` with a connection from the `redis` crate, to check real clients work unchanged.
+    async fn with_client<R: Send + 'static>(
+        url: &str,
+        f: impl FnOnce(&mut redis::Connection) -> R + Send + 'static,
+    ) -> R {
+        let client = redis::Client::open(url).unwrap();
+        tokio::task::spawn_blocking(move || f(&mut client.get_connection().unwrap()))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn throttle() {
+        let (url, clock) = start().await;
+        let throttle = |quantity: u32| {
+            redis::cmd("CL.THROTTLE")
+                .arg("user123")
+                .arg(15)
+                .arg(30)
+                .arg(60)
+                .arg(quantity)
+                .clone()
+        };
+
+        let replies: Vec<Vec<i64>> = with_client(&url, move |con| {
+            let first = throttle(1).query(con).unwrap();
+            let rest = throttle(15).query(con).unwrap();
+            let limited = throttle(1).query(con).unwrap();
+            vec![first, rest, limited]
+        })
+        .await;
+        assert_eq!(
+            vec![
+                vec![0, 16, 15, -1, 2],
+                vec![0, 16, 0, -1, 32],
+                vec![1, 16, 0, 2, 32],
+            ],
+            replies
+        );
+
+        clock.advance_by(Duration::from_secs(2));
+        let (throttled, inspected) = with_client(&url, move |con| {
+            let throttled: Vec<i64> = redis::cmd("CL.THROTTLE")
+                .arg("user123")
+                .arg(15)
+                .arg(30)
+                .arg(60)
+                .query(con)
+                .unwrap();
+            let inspected: redis::Value =
+                redis::cmd("CL.INSPECT").arg("user123").query(con).unwrap();
+            (throttled, inspected)
+        })
+        .await;
+        assert_eq!(vec![0, 16, 0, -1, 32], throttled, "Quantity defaults to 1");
+        assert_eq!(
+            redis::Value::Array(vec![
+                redis::Value::BulkString(b"policy".to_vec()),
+                redis::Value::Nil,
+                redis::Value::BulkString(b"reset_after_ms".to_vec()),
+                redis::Value::Int(32_000),
+                redis::Value::BulkString(b"expires_after_ms".to_vec()),
+                redis::Value::Int(64_000),
+            ]),
+            inspected
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn errors() {
+        let (url, _clock) = start().await;
+        let errors = with_client(&url, |con| {
+            let mut query = |cmd: &mut redis::Cmd| {
+                cmd.query::<redis::Value>(con)
+                    .unwrap_err()
+                    .detail()
+                    .map(str::to_string)
+            };
+            vec![
+                query(redis::cmd("CL.THROTTLE").arg("key").arg(1)),
+                query(redis::cmd("CL.THROTTLE").arg("key").arg(-1).arg(1).arg(1)),
+                query(redis::cmd("CL.THROTTLE").arg("key").arg(1).arg(0).arg(1)),
+                query(&mut redis::cmd("NOPE")),
+            ]
+        })
+        .await;
+        assert_eq!(
+            vec![
+                Some("wrong number of arguments for 'cl.throttle' command".to_string()),
+                Some("value is not an integer or out of range".to_string()),
+                Some("Invalid throttle of 0 per 1s with a burst of 1".to_string()),
+                Some("unknown command 'NOPE'".to_string()),
+            ],
+            errors
+        );
+    }
+
+    #[tokio::test]
+    async fn inline_commands() {
+        let (url, _clock) = start().await;
+        let addr = url.trim_start_matches("redis://").trim_end_matches('/');
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        stream
+            .write_all(b"PING\r\nCL.INSPECT missing\r\nPING hello\r\nQUIT\r\n")
+            .await
+            .unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).await.unwrap();
+        assert_eq!("+PONG\r\n$-1\r\n$5\r\nhello\r\n+OK\r\n", replies);
+    }
+
+    #[tokio::test]
+    async fn oversized_commands() {
+        let long_line = [vec![b'a'; MAX_LINE_LEN + 3], b"\r\n".to_vec()].concat();
+        let huge_bulk = format!("*1\r\n${}\r\n", MAX_BULK_LEN + 1).into_bytes();
+        let many_args = format!("*{}\r\n", MAX_ARGS + 1).into_bytes();
+        // Announces a large bulk but never sends it
+        let truncated_bulk = format!("*1\r\n${MAX_BULK_LEN}\r\nshort").into_bytes();
+
+        for (input, expected) in [
+            (long_line, "line too long"),
+            (huge_bulk, "invalid bulk length"),
+            (many_args, "too many arguments"),
+        ] {
+            let error = read_command(&mut input.as_slice()).await.unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, error.kind());
+            assert!(error.to_string().contains(expected), "{error}");
+        }
+        let error = read_command(&mut truncated_bulk.as_slice())
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, error.kind());
+
+        let max_bulk = [
+            format!("*1\r\n${MAX_BULK_LEN}\r\n").into_bytes(),
+            vec![b'a'; MAX_BULK_LEN],
+            b"\r\n".to_vec(),
+        ]
+        .concat();
+        let command = read_command(&mut max_bulk.as_slice()).await.unwrap();
+        assert_eq!(Some(vec![vec![b'a'; MAX_BULK_LEN]]), command);
+    }
+}
@@ -0,0 +1,348 @@
+//! Pacing the items of a [Stream] with a [GcraState], or per key of the items with a
+//! [RateLimiter] using [ThrottleExt::throttle_by_key].
+//!
+//! ```rust
+//! use futures::StreamExt;
+//! use gcra::{stream::{OnExcess, ThrottleExt}, RateLimit};
+//!
+//! # #[tokio::main(flavor = "current_thread", start_paused = true)]
+//! # async fn main() {
+//! let batches = futures::stream::iter([vec![1, 2], vec![3], vec![4, 5, 6]])
+//!     .throttle(RateLimit::per_sec(10))
+//!     .with_cost(|batch: &Vec<u32>| batch.len() as u32)
+//!     .with_on_excess(OnExcess::Delay);
+//! assert_eq!(3, batches.count().await);
+//! # }
+//! ```
+//!
+//! Delays sleep on tokio's timer until the [Clock] says the next item is allowed. With
+//! [TokioClock](crate::clock::TokioClock), both follow tokio's paused time in tests.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(feature = "rate-limiter")]
+use std::{hash::Hash, sync::Arc};
+
+use futures_core::Stream;
+use pin_project_lite::pin_project;
+use tokio::time::{sleep_until, Instant as TokioInstant, Sleep};
+
+#[cfg(feature = "rate-limiter")]
+use crate::RateLimiter;
+use crate::{
+    clock::{Clock, InstantClock},
+    Cost, GcraError, GcraState, IntoCost, RateLimit,
+};
+
+#[cfg(feature = "rate-limiter")]
+mod by_key;
+
+#[cfg(feature = "rate-limiter")]
+pub use by_key::ThrottleByKey;
+
+/// What to do with items arriving faster than the rate limit allows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnExcess {
+    /// Hold the item back until it is allowed, which also stops pulling from the inner stream.
+    #[default]
+    Delay,
+    /// Discard the item and move on to the next one.
+    Drop,
+}
+
+/// Adds [ThrottleExt::throttle] to every [Stream].
+pub trait ThrottleExt: Stream + Sized {
+    /// Limits the items of this stream to `rate_limit`, each costing 1 and delayed while over
+    /// the limit.
+    fn throttle(self, rate_limit: RateLimit) -> Throttle<Self, fn(&Self::Item) -> u32> {
+        Throttle::new(self, rate_limit)
+    }
+
+    /// Limits the items of this stream to `rate_limit` per key returned by `key`, tracked by
+    /// `limiter`, each costing 1 and delayed while over the limit.
+    #[cfg(feature = "rate-limiter")]
+    #[allow(clippy::type_complexity)]
+    fn throttle_by_key<KF, Key, C, St, O>(
+        self,
+        limiter: Arc<RateLimiter<Key, C, St, O>>,
+        rate_limit: RateLimit,
+        key: KF,
+    ) -> ThrottleByKey<Self, KF, fn(&Self::Item) -> u32, fn(Self::Item), Key, C, St, O>
+    where
+        KF: FnMut(&Self::Item) -> Key,
+        Key: Eq + Hash,
+    {
+        ThrottleByKey::new(self, limiter, rate_limit, key)
+    }
+}
+
+impl<S: Stream> ThrottleExt for S {}
+
+pin_project! {
+    /// Stream returned by [ThrottleExt::throttle].
+    ///
+    /// Items whose cost exceeds the rate limit can never be allowed and are always dropped.
+    pub struct Throttle<S: Stream, F, C = InstantClock> {
+        #[pin]
+        stream: S,
+        rate_limit: RateLimit,
+        state: GcraState,
+        cost: F,
+        clock: C,
+        on_excess: OnExcess,
+        // The delayed item along with its cost
+        pending: Option<(S::Item, Cost)>,
+        sleep: Option<Pin<Box<Sleep>>>,
+    }
+}
+
+impl<S: Stream> Throttle<S, fn(&S::Item) -> u32> {
+    pub fn new(stream: S, rate_limit: RateLimit) -> Self {
+        Self {
+            stream,
+            rate_limit,
+            state: GcraState::default(),
+            cost: |_| 1,
+            clock: InstantClock,
+            on_excess: OnExcess::default(),
+            pending: None,
+            sleep: None,
+        }
+    }
+}
+
+impl<S: Stream, F, C> Throttle<S, F, C> {
+    /// Charges items the cost returned by `cost`, a `u32` or a fractional [Cost].
+    pub fn with_cost<F2, T>(self, cost: F2) -> Throttle<S, F2, C>
+    where
+        F2: FnMut(&S::Item) -> T,
+        T: IntoCost,
+    {
+        Throttle {
+            stream: self.stream,
+            rate_limit: self.rate_limit,
+            state: self.state,
+            cost,
+            clock: self.clock,
+            on_excess: self.on_excess,
+            pending: self.pending,
+            sleep: self.sleep,
+        }
+    }
+
+    /// Reads the time from `clock`.
+    pub fn with_clock<C2: Clock>(self, clock: C2) -> Throttle<S, F, C2> {
+        Throttle {
+            stream: self.stream,
+            rate_limit: self.rate_limit,
+            state: self.state,
+            cost: self.cost,
+            clock,
+            on_excess: self.on_excess,
+            pending: self.pending,
+            sleep: self.sleep,
+        }
+    }
+
+    /// Sets what happens to items over the limit, which are delayed by default.
+    pub fn with_on_excess(mut self, on_excess: OnExcess) -> Self {
+        self.on_excess = on_excess;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S, F, T, C> Stream for Throttle<S, F, C>
+where
+    S: Stream,
+    F: FnMut(&S::Item) -> T,
+    T: IntoCost,
+    C: Clock,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let mut this = self.project();
+        loop {
+            let (item, cost) = match this.pending.take() {
+                Some(pending) => pending,
+                None => match this.stream.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(item)) => {
+                        let cost = (this.cost)(&item).into_cost();
+                        (item, cost)
+                    }
+                    Poll::Ready(None) => return Poll::Ready(None),
+                    Poll::Pending => return Poll::Pending,
+                },
+            };
+
+            let now = this.clock.now();
+            match this.state.check_and_modify_at(this.rate_limit, now, cost) {
+                Ok(()) => return Poll::Ready(Some(item)),
+                Err(GcraError::DeniedUntil { next_allowed_at })
+                    if *this.on_excess == OnExcess::Delay =>
+                {
+                    let deadline = TokioInstant::from_std(next_allowed_at);
+                    let sleep = match this.sleep {
+                        Some(sleep) => {
+                            sleep.as_mut().reset(deadline);
+                            sleep
+                        }
+                        None => this.sleep.insert(Box::pin(sleep_until(deadline))),
+                    };
+                    if sleep.as_mut().poll(cx).is_pending() {
+                        *this.pending = Some((item, cost));
+                        return Poll::Pending;
+                    }
+                    // Already due, check again
+                    *this.pending = Some((item, cost));
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let pending = usize::from(self.pending.is_some());
+        let (_, upper) = self.stream.size_hint();
+        (0, upper.and_then(|upper| upper.checked_add(pending)))
+    }
+}
+
+impl<S, F, C> fmt::Debug for Throttle<S, F, C>
+where
+    S: Stream + fmt::Debug,
+    C: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Throttle")
+            .field("stream", &self.stream)
+            .field("rate_limit", &self.rate_limit)
+            .field("state", &self.state)
+            .field("clock", &self.clock)
+            .field("on_excess", &self.on_excess)
+            .field("pending", &self.pending.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{channel::mpsc, SinkExt, StreamExt};
+    use tokio::time::Instant;
+
+    use crate::clock::TokioClock;
+
+    use super::*;
+
+    /// Offsets from the start at which the items of `stream` were yielded.
+    pub(super) async fn arrivals<S: Stream>(stream: S) -> Vec<(S::Item, Duration)> {
+        let start = Instant::now();
+        stream.map(|item| (item, start.elapsed())).collect().await
+    }
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delays_items_over_the_limit() {
+        let throttled = futures::stream::iter(1..=5)
+            .throttle(RateLimit::per_sec(2))
+            .with_clock(TokioClock);
+
+        assert_eq!(
+            vec![
+                (1, millis(0)),
+                (2, millis(0)),
+                (3, millis(500)),
+                (4, millis(1000)),
+                (5, millis(1500)),
+            ],
+            arrivals(throttled).await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_items_over_the_limit() {
+        let ticks = futures::stream::iter(0..10).then(|tick| async move {
+            tokio::time::sleep(millis(100)).await;
+            tick
+        });
+        let throttled = ticks
+            .throttle(RateLimit::per_sec(2))
+            .with_clock(TokioClock)
+            .with_on_excess(OnExcess::Drop);
+
+        let items: Vec<_> = throttled.collect().await;
+        assert_eq!(vec![0, 1, 5], items);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn charges_item_costs() {
+        let throttled = futures::stream::iter([1, 2, 5, 2])
+            .throttle(RateLimit::per_sec(4))
+            .with_cost(|cost: &u32| *cost)
+            .with_clock(TokioClock);
+
+        assert_eq!(
+            vec![(1, millis(0)), (2, millis(0)), (2, millis(250))],
+            arrivals(throttled).await,
+            "Items costing more than the limit are dropped"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn charges_fractional_costs() {
+        let half = "0.5".parse::<Cost>().unwrap();
+        let throttled = futures::stream::iter(1..=3)
+            .throttle(RateLimit::per_sec(1))
+            .with_cost(move |_| half)
+            .with_clock(TokioClock);
+
+        assert_eq!(
+            vec![(1, millis(0)), (2, millis(0)), (3, millis(500))],
+            arrivals(throttled).await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wakes_the_consumer() {
+        let (mut sender, receiver) = mpsc::channel(8);
+        let consumer = tokio::spawn(arrivals(
+            receiver
+                .throttle(RateLimit::per_sec(1))
+                .with_clock(TokioClock),
+        ));
+
+        for item in 1..=3 {
+            sender.send(item).await.unwrap();
+        }
+        tokio::time::sleep(millis(5000)).await;
+        sender.send(4).await.unwrap();
+        drop(sender);
+
+        assert_eq!(
+            vec![
+                (1, millis(0)),
+                (2, millis(1000)),
+                (3, millis(2000)),
+                (4, millis(5000)),
+            ],
+            consumer.await.unwrap()
+        );
+    }
+}
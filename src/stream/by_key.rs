@@ -0,0 +1,434 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap, VecDeque},
+    fmt::{self, Display},
+    future::Future,
+    hash::Hash,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use futures_core::Stream;
+use pin_project_lite::pin_project;
+use tokio::time::{sleep_until, Instant as TokioInstant, Sleep};
+
+use crate::{clock::Clock, Cost, GcraStore, IntoCost, Observer, RateLimit, RateLimiter};
+
+use super::OnExcess;
+
+/// Items of one key waiting for their turn, oldest first, with their costs.
+type Delayed<T> = VecDeque<(T, Cost)>;
+
+pin_project! {
+    /// Stream returned by [ThrottleExt::throttle_by_key](super::ThrottleExt::throttle_by_key).
+    ///
+    /// Keys are limited independently: while the items of one key are delayed, items of other
+    /// keys keep flowing, and the items of a key are always yielded in the order they arrived.
+    ///
+    /// Items over their key's limit are handed to the overflow function, which drops them by
+    /// default, when:
+    /// - [OnExcess::Drop] is set,
+    /// - [OnExcess::Delay] is set but [ThrottleByKey::with_max_delayed] items are already
+    ///   delayed,
+    /// - their cost exceeds the limit, or their check fails.
+    pub struct ThrottleByKey<S, KF, CF, OF, Key, C, St, O>
+    where
+        S: Stream,
+        Key: Eq,
+        Key: Hash,
+    {
+        #[pin]
+        stream: S,
+        limiter: Arc<RateLimiter<Key, C, St, O>>,
+        rate_limit: RateLimit,
+        key: KF,
+        cost: CF,
+        overflow: OF,
+        on_excess: OnExcess,
+        max_delayed: usize,
+        delayed: HashMap<Key, Delayed<S::Item>>,
+        delayed_count: usize,
+        // When the oldest delayed item of each key is due, in order. The sequence number keeps
+        // entries due at the same time apart.
+        timers: BTreeMap<(Instant, u64), Key>,
+        sequence: u64,
+        sleep: Option<Pin<Box<Sleep>>>,
+        done: bool,
+    }
+}
+
+impl<S, KF, Key, C, St, O> ThrottleByKey<S, KF, fn(&S::Item) -> u32, fn(S::Item), Key, C, St, O>
+where
+    S: Stream,
+    KF: FnMut(&S::Item) -> Key,
+    Key: Eq + Hash,
+{
+    /// The default maximum number of delayed items.
+    pub const DEFAULT_MAX_DELAYED: usize = 1024;
+
+    pub fn new(
+        stream: S,
+        limiter: Arc<RateLimiter<Key, C, St, O>>,
+        rate_limit: RateLimit,
+        key: KF,
+    ) -> Self {
+        Self {
+            stream,
+            limiter,
+            rate_limit,
+            key,
+            cost: |_| 1,
+            overflow: drop,
+            on_excess: OnExcess::default(),
+            max_delayed: Self::DEFAULT_MAX_DELAYED,
+            delayed: HashMap::new(),
+            delayed_count: 0,
+            timers: BTreeMap::new(),
+            sequence: 0,
+            sleep: None,
+            done: false,
+        }
+    }
+}
+
+impl<S, KF, CF, OF, Key, C, St, O> ThrottleByKey<S, KF, CF, OF, Key, C, St, O>
+where
+    S: Stream,
+    Key: Eq + Hash,
+{
+    /// Charges items the cost returned by `cost`, a `u32` or a fractional [Cost].
+    pub fn with_cost<CF2, T>(self, cost: CF2) -> ThrottleByKey<S, KF, CF2, OF, Key, C, St, O>
+    where
+        CF2: FnMut(&S::Item) -> T,
+        T: IntoCost,
+    {
+        ThrottleByKey {
+            stream: self.stream,
+            limiter: self.limiter,
+            rate_limit: self.rate_limit,
+            key: self.key,
+            cost,
+            overflow: self.overflow,
+            on_excess: self.on_excess,
+            max_delayed: self.max_delayed,
+            delayed: self.delayed,
+            delayed_count: self.delayed_count,
+            timers: self.timers,
+            sequence: self.sequence,
+            sleep: self.sleep,
+            done: self.done,
+        }
+    }
+
+    /// Hands items over the limit to `overflow` instead of dropping them, e.g. to send them to
+    /// a channel processed separately.
+    pub fn with_overflow<OF2>(self, overflow: OF2) -> ThrottleByKey<S, KF, CF, OF2, Key, C, St, O>
+    where
+        OF2: FnMut(S::Item),
+    {
+        ThrottleByKey {
+            stream: self.stream,
+            limiter: self.limiter,
+            rate_limit: self.rate_limit,
+            key: self.key,
+            cost: self.cost,
+            overflow,
+            on_excess: self.on_excess,
+            max_delayed: self.max_delayed,
+            delayed: self.delayed,
+            delayed_count: self.delayed_count,
+            timers: self.timers,
+            sequence: self.sequence,
+            sleep: self.sleep,
+            done: self.done,
+        }
+    }
+
+    /// Sets what happens to items over the limit, which are delayed by default.
+    pub fn with_on_excess(mut self, on_excess: OnExcess) -> Self {
+        self.on_excess = on_excess;
+        self
+    }
+
+    /// Bounds how many items are delayed at once across all keys, [Self::DEFAULT_MAX_DELAYED]
+    /// by default.
+    pub fn with_max_delayed(mut self, max_delayed: usize) -> Self {
+        self.max_delayed = max_delayed;
+        self
+    }
+
+    /// The number of items currently delayed.
+    pub fn delayed(&self) -> usize {
+        self.delayed_count
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S, KF, CF, T, OF, Key, C, St, O> Stream for ThrottleByKey<S, KF, CF, OF, Key, C, St, O>
+where
+    S: Stream,
+    KF: FnMut(&S::Item) -> Key,
+    CF: FnMut(&S::Item) -> T,
+    T: IntoCost,
+    OF: FnMut(S::Item),
+    Key: Send + Clone + Hash + Eq + Display + 'static,
+    C: Clock,
+    St: GcraStore<Key>,
+    O: Observer<Key>,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let mut this = self.project();
+        loop {
+            let now = this.limiter.clock().now();
+
+            // Delayed items first, they've been waiting the longest
+            if let Some(((due, _), _)) = this.timers.first_key_value() {
+                if *due <= now {
+                    let ((_, _), key) = this.timers.pop_first().expect("Checked above");
+                    let Entry::Occupied(mut entry) = this.delayed.entry(key.clone()) else {
+                        unreachable!("Timers are only set for keys with delayed items");
+                    };
+                    let (item, cost) = entry.get_mut().pop_front().expect("Queues are never empty");
+                    *this.delayed_count -= 1;
+                    let decision =
+                        this.limiter
+                            .decide_sync_at(key.clone(), this.rate_limit, cost, now);
+                    let outcome = match decision {
+                        Ok(decision) if decision.allowed => Ok(()),
+                        Ok(decision) => Err(decision.retry_after),
+                        Err(_) => Err(None),
+                    };
+                    let mut allowed = None;
+                    match outcome {
+                        Ok(()) => allowed = Some(item),
+                        Err(Some(_)) => {
+                            entry.get_mut().push_front((item, cost));
+                            *this.delayed_count += 1;
+                        }
+                        Err(None) => (this.overflow)(item),
+                    }
+                    let next_due = match outcome {
+                        Err(Some(retry_after)) => Some(now + retry_after),
+                        _ if entry.get().is_empty() => {
+                            entry.remove();
+                            None
+                        }
+                        // The next item of the key may well be allowed right away
+                        _ => Some(now),
+                    };
+                    if let Some(due) = next_due {
+                        *this.sequence += 1;
+                        this.timers.insert((due, *this.sequence), key);
+                    }
+                    if let Some(item) = allowed {
+                        return Poll::Ready(Some(item));
+                    }
+                    continue;
+                }
+            }
+
+            if !*this.done {
+                match this.stream.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(item)) => {
+                        let key = (this.key)(&item);
+                        let cost = (this.cost)(&item).into_cost();
+                        let has_room = *this.on_excess == OnExcess::Delay
+                            && *this.delayed_count < *this.max_delayed;
+                        if let Some(queue) = this.delayed.get_mut(&key) {
+                            // Queue up behind the key's delayed items to keep them in order
+                            if has_room {
+                                queue.push_back((item, cost));
+                                *this.delayed_count += 1;
+                            } else {
+                                (this.overflow)(item);
+                            }
+                            continue;
+                        }
+                        match this
+                            .limiter
+                            .decide_sync_at(key.clone(), this.rate_limit, cost, now)
+                        {
+                            Ok(decision) if decision.allowed => return Poll::Ready(Some(item)),
+                            Ok(decision) => match decision.retry_after {
+                                Some(retry_after) if has_room => {
+                                    this.delayed
+                                        .insert(key.clone(), VecDeque::from([(item, cost)]));
+                                    *this.delayed_count += 1;
+                                    *this.sequence += 1;
+                                    this.timers.insert((now + retry_after, *this.sequence), key);
+                                }
+                                _ => (this.overflow)(item),
+                            },
+                            Err(_) => (this.overflow)(item),
+                        }
+                        continue;
+                    }
+                    Poll::Ready(None) => *this.done = true,
+                    Poll::Pending => {}
+                }
+            }
+
+            let Some(((due, _), _)) = this.timers.first_key_value() else {
+                if *this.done {
+                    return Poll::Ready(None);
+                }
+                return Poll::Pending;
+            };
+            let deadline = TokioInstant::from_std(*due);
+            let sleep = match this.sleep {
+                Some(sleep) => {
+                    sleep.as_mut().reset(deadline);
+                    sleep
+                }
+                None => this.sleep.insert(Box::pin(sleep_until(deadline))),
+            };
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (_, upper) = self.stream.size_hint();
+        (
+            0,
+            upper.and_then(|upper| upper.checked_add(self.delayed_count)),
+        )
+    }
+}
+
+impl<S, KF, CF, OF, Key, C, St, O> fmt::Debug for ThrottleByKey<S, KF, CF, OF, Key, C, St, O>
+where
+    S: Stream + fmt::Debug,
+    Key: Eq + Hash,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThrottleByKey")
+            .field("stream", &self.stream)
+            .field("rate_limit", &self.rate_limit)
+            .field("on_excess", &self.on_excess)
+            .field("max_delayed", &self.max_delayed)
+            .field("delayed", &self.delayed_count)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use futures::{channel::mpsc, SinkExt, StreamExt};
+
+    use crate::{
+        clock::TokioClock,
+        stream::{tests::arrivals, ThrottleExt},
+    };
+
+    use super::*;
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn limiter() -> Arc<RateLimiter<&'static str, TokioClock>> {
+        Arc::new(RateLimiter::with_clock(TokioClock))
+    }
+
+    type Event = (&'static str, u32);
+
+    fn device((device, _): &Event) -> &'static str {
+        device
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delays_keys_independently() {
+        let events = futures::stream::iter([("a", 1), ("a", 2), ("b", 1), ("a", 3), ("b", 2)]);
+        let throttled = events.throttle_by_key(limiter(), RateLimit::per_sec(1), device);
+
+        assert_eq!(
+            vec![
+                (("a", 1), millis(0)),
+                (("b", 1), millis(0)),
+                (("a", 2), millis(1000)),
+                (("b", 2), millis(1000)),
+                (("a", 3), millis(2000)),
+            ],
+            arrivals(throttled).await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn routes_excess_to_overflow() {
+        let overflow = Mutex::new(Vec::new());
+        let events = futures::stream::iter([("a", 1), ("a", 2), ("b", 1), ("a", 3), ("b", 2)]);
+        let throttled = events
+            .throttle_by_key(limiter(), RateLimit::per_sec(1), device)
+            .with_on_excess(OnExcess::Drop)
+            .with_overflow(|event| overflow.lock().unwrap().push(event));
+
+        let allowed: Vec<_> = throttled.collect().await;
+        assert_eq!(vec![("a", 1), ("b", 1)], allowed);
+        assert_eq!(
+            vec![("a", 2), ("a", 3), ("b", 2)],
+            overflow.into_inner().unwrap()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bounds_delayed_items() {
+        let overflow = Mutex::new(Vec::new());
+        let events =
+            futures::stream::iter([("a", 1), ("a", 2), ("a", 3), ("b", 1), ("b", 2), ("c", 1)]);
+        let throttled = events
+            .throttle_by_key(limiter(), RateLimit::per_sec(1), device)
+            .with_cost(|&(device, _): &Event| if device == "c" { 2 } else { 1 })
+            .with_max_delayed(1)
+            .with_overflow(|event| overflow.lock().unwrap().push(event));
+
+        assert_eq!(
+            vec![
+                (("a", 1), millis(0)),
+                (("b", 1), millis(0)),
+                (("a", 2), millis(1000)),
+            ],
+            arrivals(throttled).await,
+        );
+        assert_eq!(
+            vec![("a", 3), ("b", 2), ("c", 1)],
+            overflow.into_inner().unwrap(),
+            "Items over the delay bound or costing more than the limit overflow"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_pulling_while_delaying() {
+        let (mut sender, receiver) = mpsc::channel(8);
+        let consumer = tokio::spawn(arrivals(receiver.throttle_by_key(
+            limiter(),
+            RateLimit::per_sec(1),
+            device,
+        )));
+
+        sender.send(("a", 1)).await.unwrap();
+        sender.send(("a", 2)).await.unwrap();
+        tokio::time::sleep(millis(300)).await;
+        sender.send(("b", 1)).await.unwrap();
+        drop(sender);
+
+        assert_eq!(
+            vec![
+                (("a", 1), millis(0)),
+                (("b", 1), millis(300)),
+                (("a", 2), millis(1000)),
+            ],
+            consumer.await.unwrap()
+        );
+    }
+}
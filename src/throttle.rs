@@ -0,0 +1,243 @@
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::{GcraError, GcraState, RateLimit};
+
+/// The parameters of a [GcraState::throttle] don't describe a usable rate.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("Invalid throttle of {count} per {period:?} with a burst of {max_burst}")]
+pub struct InvalidThrottleError {
+    pub max_burst: u32,
+    pub count: u32,
+    pub period: Duration,
+}
+
+/// Reply of a [redis-cell](https://github.com/brandur/redis-cell) compatible throttle, see
+/// [GcraState::throttle].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ThrottleResult {
+    /// Whether the action was limited.
+    pub limited: bool,
+    /// Total limit of the key, `max_burst + 1`.
+    pub limit: i64,
+    /// Remaining limit of the key.
+    pub remaining: i64,
+    /// Seconds until the action can be retried, or `-1` if it wasn't limited or never will
+    /// succeed.
+    pub retry_after: i64,
+    /// Seconds until the limit fully resets.
+    pub reset_after: i64,
+}
+
+impl ThrottleResult {
+    /// Computes the reply from the `state` left after checking it against `rate_limit` at `now`.
+    pub(crate) fn new(
+        state: &GcraState,
+        rate_limit: &RateLimit,
+        now: Instant,
+        checked: &Result<(), GcraError>,
+    ) -> Self {
+        let emission_interval = rate_limit.emission_interval.as_nanos() as i128;
+        let ttl = state.tat.map_or(0, |tat| signed_nanos(tat, now));
+        // Free room in the burst, which can be negative by less than one emission while the
+        // previous request is still draining
+        let next = rate_limit.period.as_nanos() as i128 - ttl;
+        let remaining = if next > -emission_interval {
+            next / emission_interval
+        } else {
+            0
+        };
+        let retry_after = match checked {
+            Err(GcraError::DeniedUntil { next_allowed_at }) => {
+                signed_nanos(*next_allowed_at, now) / NANOS_PER_SEC
+            }
+            _ => -1,
+        };
+
+        Self {
+            limited: checked.is_err(),
+            limit: rate_limit
+                .resource_limit_u64()
+                .try_into()
+                .unwrap_or(i64::MAX),
+            remaining: remaining.try_into().unwrap_or(i64::MAX),
+            retry_after: retry_after as i64,
+            reset_after: (ttl / NANOS_PER_SEC) as i64,
+        }
+    }
+}
+
+/// The `CL.THROTTLE` reply, `limited` being `1` or `0`.
+impl From<ThrottleResult> for [i64; 5] {
+    fn from(result: ThrottleResult) -> Self {
+        [
+            result.limited.into(),
+            result.limit,
+            result.remaining,
+            result.retry_after,
+            result.reset_after,
+        ]
+    }
+}
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+/// `to - from` in nanos, negative if `to` is earlier. Integer division truncates this towards
+/// zero when converting to seconds, same as redis-cell.
+fn signed_nanos(to: Instant, from: Instant) -> i128 {
+    match to.checked_duration_since(from) {
+        Some(after) => after.as_nanos() as i128,
+        None => -((from - to).as_nanos() as i128),
+    }
+}
+
+impl RateLimit {
+    /// The [RateLimit] redis-cell uses for `count` actions per `period` with a burst of
+    /// `max_burst` on top of the first action.
+    pub fn with_max_burst(
+        max_burst: u32,
+        count: u32,
+        period: Duration,
+    ) -> Result<Self, InvalidThrottleError> {
+        let invalid = || InvalidThrottleError {
+            max_burst,
+            count,
+            period,
+        };
+        let resource_limit = max_burst.checked_add(1).ok_or_else(invalid)?;
+        let emission_interval = period.checked_div(count).ok_or_else(invalid)?;
+        if emission_interval.is_zero() {
+            return Err(invalid());
+        }
+        let period = emission_interval
+            .checked_mul(resource_limit)
+            .ok_or_else(invalid)?;
+        Ok(Self::new(resource_limit, period))
+    }
+}
+
+impl GcraState {
+    /// Same as redis-cell's `CL.THROTTLE key max_burst count period quantity`.
+    ///
+    /// Simply passes the current Instant to [`throttle_at()`]
+    #[inline]
+    pub fn throttle(
+        &mut self,
+        max_burst: u32,
+        count: u32,
+        period: Duration,
+        quantity: u32,
+    ) -> Result<ThrottleResult, InvalidThrottleError> {
+        self.throttle_at(max_burst, count, period, quantity, Instant::now())
+    }
+
+    /// Same as redis-cell's `CL.THROTTLE key max_burst count period quantity` at the given
+    /// arrival time, allowing `count` actions per `period` with bursts of up to `max_burst + 1`.
+    ///
+    /// Unlike [GcraState::check_and_modify_at], being limited isn't an error so results can be
+    /// compared one to one with redis-cell. The state is only updated if the action is allowed.
+    ///
+    /// # Errors
+    /// - [InvalidThrottleError] if `count` and `period` don't make for a usable rate.
+    pub fn throttle_at(
+        &mut self,
+        max_burst: u32,
+        count: u32,
+        period: Duration,
+        quantity: u32,
+        now: Instant,
+    ) -> Result<ThrottleResult, InvalidThrottleError> {
+        let rate_limit = RateLimit::with_max_burst(max_burst, count, period)?;
+        let checked = self.check_and_modify_at(&rate_limit, now, quantity);
+        Ok(ThrottleResult::new(self, &rate_limit, now, &checked))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(result: Result<ThrottleResult, InvalidThrottleError>) -> [i64; 5] {
+        result.unwrap().into()
+    }
+
+    #[test]
+    fn throttle_matches_redis_cell() {
+        let mut state = GcraState::default();
+        let now = Instant::now();
+        let period = Duration::from_secs(60);
+
+        // Example from the redis-cell README, 30 per minute with a burst of 15 more
+        assert_eq!(
+            [0, 16, 15, -1, 2],
+            reply(state.throttle_at(15, 30, period, 1, now))
+        );
+        for remaining in (0..15).rev() {
+            let [limited, _, actual, _, _] = reply(state.throttle_at(15, 30, period, 1, now));
+            assert_eq!((0, remaining), (limited, actual));
+        }
+        assert_eq!(
+            [1, 16, 0, 2, 32],
+            reply(state.throttle_at(15, 30, period, 1, now))
+        );
+
+        // Seconds are truncated, so just before the retry it's still zero seconds away
+        let almost = now + Duration::from_millis(1_500);
+        assert_eq!(
+            [1, 16, 0, 0, 30],
+            reply(state.throttle_at(15, 30, period, 1, almost))
+        );
+        let retry_at = now + Duration::from_secs(2);
+        assert_eq!(
+            [0, 16, 0, -1, 32],
+            reply(state.throttle_at(15, 30, period, 1, retry_at))
+        );
+    }
+
+    #[test]
+    fn throttle_quantity() {
+        let mut state = GcraState::default();
+        let now = Instant::now();
+        let period = Duration::from_secs(10);
+
+        assert_eq!(
+            [0, 5, 1, -1, 8],
+            reply(state.throttle_at(4, 5, period, 4, now))
+        );
+        assert_eq!(
+            [1, 5, 1, 2, 8],
+            reply(state.throttle_at(4, 5, period, 2, now)),
+            "Only one left in the burst"
+        );
+        assert_eq!(
+            [1, 5, 1, -1, 8],
+            reply(state.throttle_at(4, 5, period, 6, now)),
+            "More than the whole burst never succeeds, but doesn't reset the key"
+        );
+        assert_eq!(
+            [0, 5, 0, -1, 10],
+            reply(state.throttle_at(4, 5, period, 1, now))
+        );
+    }
+
+    #[test]
+    fn throttle_invalid_rates() {
+        let mut state = GcraState::default();
+        for (max_burst, count, period) in [
+            (1, 0, Duration::from_secs(1)),
+            (1, 1, Duration::ZERO),
+            (u32::MAX, 1, Duration::from_secs(1)),
+        ] {
+            assert_eq!(
+                Err(InvalidThrottleError {
+                    max_burst,
+                    count,
+                    period
+                }),
+                state.throttle(max_burst, count, period, 1)
+            );
+        }
+        assert_eq!(GcraState::default(), state);
+    }
+}
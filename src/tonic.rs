@@ -0,0 +1,571 @@
+//! Middleware rate limiting [tonic](https://docs.rs/tonic) gRPC services, built on the
+//! [tower](crate::tower) integration.
+//!
+//! Calls are keyed by a [KeyBy], e.g. per caller and method, and denied with
+//! `RESOURCE_EXHAUSTED`. When retrying can succeed, the status says when in the
+//! `grpc-retry-pushback-ms` and `retry-after` metadata and in a `google.rpc.RetryInfo` detail,
+//! which clients can read back with [retry_delay].
+//!
+//! ```rust,no_run
+//! use std::sync::Arc;
+//! use gcra::{tonic::{GcraGrpcLayer, KeyBy}, RateLimit, RateLimiter};
+//! use tonic::{metadata::AsciiMetadataKey, transport::Server};
+//!
+//! let limiter: Arc<RateLimiter<String>> = Arc::new(RateLimiter::new(1024));
+//! let caller = KeyBy::Metadata(AsciiMetadataKey::from_static("x-api-key"));
+//! let server = Server::builder().layer(GcraGrpcLayer::new(
+//!     limiter,
+//!     RateLimit::per_sec(10),
+//!     KeyBy::PerMethod(Box::new(caller)),
+//! ));
+//! ```
+
+use std::{
+    fmt::Display,
+    future::Future,
+    hash::Hash,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use prost::Message;
+use tonic::{
+    codegen::http::{Request, Response},
+    metadata::{AsciiMetadataKey, MetadataMap, MetadataValue},
+    server::NamedService,
+    transport::server::TcpConnectInfo,
+    Code, Status,
+};
+use tower::{Layer, Service};
+
+use crate::{
+    clock::{Clock, InstantClock},
+    headers::{delta_seconds, RETRY_AFTER},
+    tower::{denied, KeyExtractor, Limited, MissingKey, RequestCost, UnitCost},
+    DashMapStore, GcraError, GcraStore, NoopObserver, Observer, RateLimit, RateLimiter,
+    RateLimiterError,
+};
+
+pub mod proto;
+
+use proto::{RetryInfo, RETRY_INFO_TYPE_URL};
+
+/// Metadata gRPC clients read the delay before retrying from, in milliseconds.
+pub const GRPC_RETRY_PUSHBACK_MS: &str = "grpc-retry-pushback-ms";
+
+/// Built-in ways of keying calls.
+///
+/// Calls without a key fail with `INVALID_ARGUMENT`, unless [MissingKey::Allow] is set with
+/// [GcraGrpcLayer::with_missing_key].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyBy {
+    /// The peer's IP address, from tonic's [TcpConnectInfo] or a [SocketAddr] request extension.
+    PeerIp,
+    /// The value of an ASCII metadata entry, e.g. an API key.
+    Metadata(AsciiMetadataKey),
+    /// The called method, e.g. `/helloworld.Greeter/SayHello`, shared by all callers.
+    Method,
+    /// The key of the inner [KeyBy], limited separately for every method.
+    PerMethod(Box<KeyBy>),
+}
+
+impl<B> KeyExtractor<Request<B>> for KeyBy {
+    type Key = Option<String>;
+
+    fn extract(&self, request: &Request<B>) -> Option<String> {
+        match self {
+            KeyBy::PeerIp => {
+                let extensions = request.extensions();
+                extensions
+                    .get::<TcpConnectInfo>()
+                    .and_then(TcpConnectInfo::remote_addr)
+                    .or_else(|| extensions.get::<SocketAddr>().copied())
+                    .map(|addr| addr.ip().to_string())
+            }
+            KeyBy::Metadata(key) => request
+                .headers()
+                .get(key.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            KeyBy::Method => Some(request.uri().path().to_string()),
+            KeyBy::PerMethod(caller) => {
+                let caller = caller.extract(request)?;
+                Some(format!("{} {caller}", request.uri().path()))
+            }
+        }
+    }
+}
+
+/// The [Status] a failed check stands for, made at `now`.
+///
+/// Denials are `RESOURCE_EXHAUSTED`, with the retry delay of [GcraError::DeniedUntil] in the
+/// metadata and a `google.rpc.RetryInfo` detail. Store errors are `UNAVAILABLE`, other errors
+/// `INTERNAL`.
+pub fn to_status(error: &RateLimiterError, now: Instant) -> Status {
+    match error {
+        RateLimiterError::Gcra(e @ GcraError::DeniedUntil { next_allowed_at }) => {
+            resource_exhausted(e, Some(next_allowed_at.saturating_duration_since(now)))
+        }
+        RateLimiterError::Gcra(e @ GcraError::DeniedIndefinitely { .. }) => {
+            resource_exhausted(e, None)
+        }
+        RateLimiterError::Store(_) => Status::unavailable(error.to_string()),
+        _ => Status::internal(error.to_string()),
+    }
+}
+
+fn resource_exhausted(error: &GcraError, retry_after: Option<Duration>) -> Status {
+    let Some(retry_after) = retry_after else {
+        return Status::resource_exhausted(error.to_string());
+    };
+    let millis = u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX);
+    let mut metadata = MetadataMap::new();
+    metadata.insert(GRPC_RETRY_PUSHBACK_MS, MetadataValue::from(millis));
+    metadata.insert(RETRY_AFTER, MetadataValue::from(delta_seconds(retry_after)));
+
+    let message = error.to_string();
+    let retry_info = RetryInfo {
+        retry_delay: retry_after.try_into().ok(),
+    };
+    let details = proto::Status {
+        code: Code::ResourceExhausted.into(),
+        message: message.clone(),
+        details: vec![prost_types::Any {
+            type_url: RETRY_INFO_TYPE_URL.to_string(),
+            value: retry_info.encode_to_vec(),
+        }],
+    };
+    Status::with_details_and_metadata(
+        Code::ResourceExhausted,
+        message,
+        details.encode_to_vec().into(),
+        metadata,
+    )
+}
+
+/// The delay of the `google.rpc.RetryInfo` detail of `status`, if it has one.
+pub fn retry_delay(status: &Status) -> Option<Duration> {
+    proto::Status::decode(status.details())
+        .ok()?
+        .details
+        .iter()
+        .filter(|detail| detail.type_url == RETRY_INFO_TYPE_URL)
+        .find_map(|detail| RetryInfo::decode(detail.value.as_slice()).ok()?.retry_delay)
+        .and_then(|delay| delay.try_into().ok())
+}
+
+/// Applies [GcraGrpcService] to services.
+pub struct GcraGrpcLayer<
+    KF,
+    CF = UnitCost,
+    Key: Eq + Hash = String,
+    C = InstantClock,
+    St = DashMapStore<Key>,
+    O = NoopObserver,
+> {
+    limited: Limited<Key, C, St, O>,
+    key: KF,
+    cost: CF,
+    missing_key: MissingKey,
+}
+
+impl<KF, CF, Key, C, St, O> Clone for GcraGrpcLayer<KF, CF, Key, C, St, O>
+where
+    KF: Clone,
+    CF: Clone,
+    Key: Eq + Hash,
+{
+    fn clone(&self) -> Self {
+        Self {
+            limited: self.limited.clone(),
+            key: self.key.clone(),
+            cost: self.cost.clone(),
+            missing_key: self.missing_key,
+        }
+    }
+}
+
+impl<KF, Key, C, St, O> GcraGrpcLayer<KF, UnitCost, Key, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    /// Limits calls by the key extracted by `key`, e.g. a [KeyBy], charging each a cost of 1.
+    pub fn new(limiter: Arc<RateLimiter<Key, C, St, O>>, rate_limit: RateLimit, key: KF) -> Self {
+        Self {
+            limited: Limited::rate_limit(limiter, rate_limit),
+            key,
+            cost: UnitCost,
+            missing_key: MissingKey::default(),
+        }
+    }
+
+    /// Limits calls by the key extracted by `key` against the registered `policy`, charging each
+    /// a cost of 1. Calls fail with `INTERNAL` while the policy isn't registered.
+    pub fn with_policy(
+        limiter: Arc<RateLimiter<Key, C, St, O>>,
+        policy: impl Into<String>,
+        key: KF,
+    ) -> Self {
+        Self {
+            limited: Limited::policy(limiter, policy.into()),
+            key,
+            cost: UnitCost,
+            missing_key: MissingKey::default(),
+        }
+    }
+}
+
+impl<KF, CF, Key, C, St, O> GcraGrpcLayer<KF, CF, Key, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    /// Charges calls the cost returned by `cost`.
+    pub fn with_cost<CF2>(self, cost: CF2) -> GcraGrpcLayer<KF, CF2, Key, C, St, O> {
+        GcraGrpcLayer {
+            limited: self.limited,
+            key: self.key,
+            cost,
+            missing_key: self.missing_key,
+        }
+    }
+
+    /// Sets what happens to calls without a key, failed with `INVALID_ARGUMENT` by default.
+    pub fn with_missing_key(mut self, missing_key: MissingKey) -> Self {
+        self.missing_key = missing_key;
+        self
+    }
+}
+
+impl<S, KF, CF, Key, C, St, O> Layer<S> for GcraGrpcLayer<KF, CF, Key, C, St, O>
+where
+    KF: Clone,
+    CF: Clone,
+    Key: Eq + Hash,
+{
+    type Service = GcraGrpcService<S, KF, CF, Key, C, St, O>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GcraGrpcService {
+            inner,
+            limited: self.limited.clone(),
+            key: self.key.clone(),
+            cost: self.cost.clone(),
+            missing_key: self.missing_key,
+        }
+    }
+}
+
+/// Rate limits calls before passing them on to the wrapped service, see [GcraGrpcLayer] and
+/// [to_status] for how failed checks are answered.
+pub struct GcraGrpcService<
+    S,
+    KF,
+    CF = UnitCost,
+    Key: Eq + Hash = String,
+    C = InstantClock,
+    St = DashMapStore<Key>,
+    O = NoopObserver,
+> {
+    inner: S,
+    limited: Limited<Key, C, St, O>,
+    key: KF,
+    cost: CF,
+    missing_key: MissingKey,
+}
+
+impl<S, KF, CF, Key, C, St, O> Clone for GcraGrpcService<S, KF, CF, Key, C, St, O>
+where
+    S: Clone,
+    KF: Clone,
+    CF: Clone,
+    Key: Eq + Hash,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            limited: self.limited.clone(),
+            key: self.key.clone(),
+            cost: self.cost.clone(),
+            missing_key: self.missing_key,
+        }
+    }
+}
+
+impl<S, KF, CF, Key, C, St, O> NamedService for GcraGrpcService<S, KF, CF, Key, C, St, O>
+where
+    S: NamedService,
+    Key: Eq + Hash,
+{
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, ReqBody, ResBody, KF, CF, Key, C, St, O> Service<Request<ReqBody>>
+    for GcraGrpcService<S, KF, CF, Key, C, St, O>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    ResBody: Default + Send + 'static,
+    KF: KeyExtractor<Request<ReqBody>, Key = Option<Key>>,
+    CF: RequestCost<Request<ReqBody>>,
+    Key: Send + Clone + Hash + Eq + Display + 'static,
+    C: Clock,
+    St: GcraStore<Key>,
+    O: Observer<Key>,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<ResBody>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let Some(key) = self.key.extract(&request) else {
+            if self.missing_key == MissingKey::Allow {
+                return Box::pin(self.inner.call(request));
+            }
+            let response = Status::invalid_argument("missing rate limit key").into_http();
+            return Box::pin(async { Ok(response) });
+        };
+        let cost = self.cost.cost(&request);
+        let now = self.limited.now();
+        let error = match self.limited.decide(key, cost, now) {
+            Ok(decision) if decision.allowed => return Box::pin(self.inner.call(request)),
+            Ok(decision) => denied(&decision, cost, now),
+            Err(e) => e,
+        };
+        let response = to_status(&error, now).into_http();
+        Box::pin(async { Ok(response) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::Ipv4Addr};
+
+    use futures::{channel::mpsc, StreamExt};
+    use hyper_util::rt::TokioIo;
+    use tonic::{
+        client::Grpc,
+        codec::ProstCodec,
+        codegen::{http::uri::PathAndQuery, Body, BoxFuture, StdError},
+        server::UnaryService,
+        transport::{Channel, Endpoint, Server},
+    };
+    use tower::{service_fn, util::MapRequestLayer};
+
+    use crate::clock::tests::FakeClock;
+
+    use super::*;
+
+    const PING: &str = "/test.Echo/Ping";
+    const PONG: &str = "/test.Echo/Pong";
+
+    /// Answers every method with an empty message.
+    #[derive(Clone)]
+    struct Echo;
+
+    impl NamedService for Echo {
+        const NAME: &'static str = "test.Echo";
+    }
+
+    impl UnaryService<()> for Echo {
+        type Response = ();
+        type Future = BoxFuture<tonic::Response<()>, Status>;
+
+        fn call(&mut self, _request: tonic::Request<()>) -> Self::Future {
+            Box::pin(async { Ok(tonic::Response::new(())) })
+        }
+    }
+
+    impl<B> Service<Request<B>> for Echo
+    where
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = Response<tonic::body::Body>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<B>) -> Self::Future {
+            Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(ProstCodec::default());
+                Ok(grpc.unary(Echo, request).await)
+            })
+        }
+    }
+
+    /// Serves [Echo] over in-memory connections from 127.0.0.1, behind `layer`.
+    async fn serve(layer: GcraGrpcLayer<KeyBy, UnitCost, String, FakeClock>) -> Channel {
+        let (connections, incoming) = mpsc::unbounded();
+        let peer = MapRequestLayer::new(|mut request: Request<tonic::body::Body>| {
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 50051));
+            request.extensions_mut().insert(addr);
+            request
+        });
+        tokio::spawn(
+            Server::builder()
+                .layer(peer)
+                .layer(layer)
+                .add_service(Echo)
+                .serve_with_incoming(incoming.map(Ok::<_, std::io::Error>)),
+        );
+        Endpoint::from_static("http://localhost")
+            .connect_with_connector(service_fn(move |_| {
+                let (client, server) = tokio::io::duplex(64 * 1024);
+                let sent = connections.unbounded_send(server);
+                async move {
+                    sent.map_err(|_| std::io::Error::other("server stopped"))?;
+                    Ok::<_, std::io::Error>(TokioIo::new(client))
+                }
+            }))
+            .await
+            .unwrap()
+    }
+
+    /// Serves [Echo] behind a layer limiting calls to 2 per second, keyed by `key`.
+    async fn start(key: KeyBy) -> (Channel, FakeClock) {
+        let clock = FakeClock::new();
+        let limiter: Arc<RateLimiter<String, _>> = Arc::new(RateLimiter::with_clock(clock.clone()));
+        let channel = serve(GcraGrpcLayer::new(limiter, RateLimit::per_sec(2), key)).await;
+        (channel, clock)
+    }
+
+    async fn call(
+        channel: &Channel,
+        path: &'static str,
+        api_key: Option<&str>,
+    ) -> Result<(), Status> {
+        let mut request = tonic::Request::new(());
+        if let Some(api_key) = api_key {
+            request
+                .metadata_mut()
+                .insert("x-api-key", api_key.parse().unwrap());
+        }
+        let mut grpc = Grpc::new(channel.clone());
+        grpc.ready().await.unwrap();
+        grpc.unary(
+            request,
+            PathAndQuery::from_static(path),
+            ProstCodec::<(), ()>::default(),
+        )
+        .await
+        .map(tonic::Response::into_inner)
+    }
+
+    fn metadata<'a>(status: &'a Status, key: &str) -> Option<&'a str> {
+        status
+            .metadata()
+            .get(key)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[tokio::test]
+    async fn limits_by_peer_ip() {
+        let (channel, clock) = start(KeyBy::PeerIp).await;
+
+        call(&channel, PING, None).await.unwrap();
+        call(&channel, PONG, None).await.unwrap();
+        let denied = call(&channel, PING, None).await.unwrap_err();
+        assert_eq!(Code::ResourceExhausted, denied.code());
+        assert_eq!(Some("500"), metadata(&denied, GRPC_RETRY_PUSHBACK_MS));
+        assert_eq!(Some("1"), metadata(&denied, RETRY_AFTER));
+        assert_eq!(Some(Duration::from_millis(500)), retry_delay(&denied));
+
+        clock.advance_by(Duration::from_millis(500));
+        call(&channel, PING, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn limits_per_method_and_caller() {
+        let caller = KeyBy::Metadata(AsciiMetadataKey::from_static("x-api-key"));
+        let (channel, _clock) = start(KeyBy::PerMethod(Box::new(caller))).await;
+
+        for _ in 0..2 {
+            call(&channel, PING, Some("alice")).await.unwrap();
+        }
+        let denied = call(&channel, PING, Some("alice")).await.unwrap_err();
+        assert_eq!(Code::ResourceExhausted, denied.code());
+
+        call(&channel, PONG, Some("alice"))
+            .await
+            .expect("Methods are limited separately");
+        call(&channel, PING, Some("bob"))
+            .await
+            .expect("Callers are limited separately");
+        let rejected = call(&channel, PING, None).await.unwrap_err();
+        assert_eq!(
+            Code::InvalidArgument,
+            rejected.code(),
+            "Leaving the metadata out must not get around the limit"
+        );
+    }
+
+    #[tokio::test]
+    async fn allows_unkeyed_calls_when_configured() {
+        let limiter: Arc<RateLimiter<String, _>> =
+            Arc::new(RateLimiter::with_clock(FakeClock::new()));
+        let caller = KeyBy::Metadata(AsciiMetadataKey::from_static("x-api-key"));
+        let layer = GcraGrpcLayer::new(limiter, RateLimit::per_sec(2), caller)
+            .with_missing_key(MissingKey::Allow);
+        let channel = serve(layer).await;
+
+        for _ in 0..3 {
+            call(&channel, PING, None)
+                .await
+                .expect("Allowed calls without the metadata are not limited");
+        }
+        call(&channel, PING, Some("alice")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn denies_indefinitely_without_retry_info() {
+        let clock = FakeClock::new();
+        let limiter: Arc<RateLimiter<String, _>> = Arc::new(RateLimiter::with_clock(clock));
+        let layer = GcraGrpcLayer::new(limiter, RateLimit::per_sec(2), KeyBy::Method)
+            .with_cost(|_: &Request<tonic::body::Body>| 3);
+        let mut service = layer.layer(Echo);
+
+        let request = Request::post(format!("http://localhost{PING}"))
+            .header("content-type", "application/grpc")
+            .body(tonic::body::Body::empty())
+            .unwrap();
+        let response = service.call(request).await.unwrap();
+        let status = Status::from_header_map(response.headers()).unwrap();
+        assert_eq!(Code::ResourceExhausted, status.code());
+        assert_eq!(None, metadata(&status, GRPC_RETRY_PUSHBACK_MS));
+        assert_eq!(None, retry_delay(&status));
+    }
+
+    #[test]
+    fn maps_errors_to_status() {
+        let now = Instant::now();
+        let status = to_status(
+            &GcraError::DeniedUntil {
+                next_allowed_at: now + Duration::from_millis(1500),
+            }
+            .into(),
+            now,
+        );
+        assert_eq!(Code::ResourceExhausted, status.code());
+        assert_eq!(Some("2"), metadata(&status, RETRY_AFTER));
+        assert_eq!(Some(Duration::from_millis(1500)), retry_delay(&status));
+
+        let status = to_status(
+            &RateLimiterError::UnknownPolicy {
+                policy: "missing".to_string(),
+            },
+            now,
+        );
+        assert_eq!(Code::Internal, status.code());
+        assert_eq!(None, retry_delay(&status));
+    }
+}
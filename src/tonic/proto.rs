@@ -0,0 +1,26 @@
+//! Messages of Google's [rich error model](https://cloud.google.com/apis/design/errors#error_model)
+//! carried in the `grpc-status-details-bin` trailer, limited to the ones used here. Field tags
+//! match the upstream protos.
+//!
+//! See <https://github.com/googleapis/googleapis/blob/master/google/rpc/error_details.proto>.
+
+/// Type URL of [RetryInfo] when packed into a [prost_types::Any].
+pub const RETRY_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.RetryInfo";
+
+/// `google.rpc.Status`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Status {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(message, repeated, tag = "3")]
+    pub details: Vec<prost_types::Any>,
+}
+
+/// `google.rpc.RetryInfo`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RetryInfo {
+    #[prost(message, optional, tag = "1")]
+    pub retry_delay: Option<prost_types::Duration>,
+}
@@ -0,0 +1,548 @@
+//! [tower] middleware rate limiting requests to any [Service] with a [RateLimiter].
+//!
+//! Requests are keyed by a [KeyExtractor] and charged by a [RequestCost], both of which are
+//! implemented by plain closures:
+//!
+//! ```rust
+//! use std::sync::Arc;
+//! use gcra::{tower::GcraLayer, RateLimit, RateLimiter};
+//!
+//! struct Request {
+//!     user: String,
+//!     items: u32,
+//! }
+//!
+//! let limiter: Arc<RateLimiter<String>> = Arc::new(RateLimiter::new(1024));
+//! let layer = GcraLayer::new(limiter, RateLimit::per_sec(10), |request: &Request| {
+//!     request.user.clone()
+//! })
+//! .with_cost(|request: &Request| request.items);
+//! ```
+//!
+//! # Readiness
+//! [GcraService::poll_ready] only reports the readiness of the wrapped service, as the limit
+//! depends on the request. The check happens in [GcraService::call], and denied requests leave
+//! the wrapped service ready for the next call. With [OnDeny::Wait], the ready service is moved
+//! into the returned future while it waits for the limit, which is why the wrapped service must
+//! be [Clone].
+
+use std::{
+    fmt::Display,
+    future::Future,
+    hash::Hash,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tower::{BoxError, Layer, Service};
+
+use crate::{
+    clock::{Clock, InstantClock},
+    Cost, DashMapStore, Decision, GcraError, GcraStore, IntoCost, NoopObserver, Observer,
+    RateLimit, RateLimiter, RateLimiterError,
+};
+
+/// Extracts the key a request is rate limited by.
+pub trait KeyExtractor<Req> {
+    type Key;
+
+    fn extract(&self, request: &Req) -> Self::Key;
+}
+
+impl<Req, Key, F> KeyExtractor<Req> for F
+where
+    F: Fn(&Req) -> Key,
+{
+    type Key = Key;
+
+    fn extract(&self, request: &Req) -> Key {
+        self(request)
+    }
+}
+
+/// How many resources a request uses up.
+///
+/// Implemented by closures returning any [IntoCost], i.e. a `u32` or a fractional [Cost].
+pub trait RequestCost<Req> {
+    fn cost(&self, request: &Req) -> Cost;
+}
+
+impl<Req, F, T> RequestCost<Req> for F
+where
+    F: Fn(&Req) -> T,
+    T: IntoCost,
+{
+    fn cost(&self, request: &Req) -> Cost {
+        self(request).into_cost()
+    }
+}
+
+/// Charges every request a cost of 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnitCost;
+
+impl<Req> RequestCost<Req> for UnitCost {
+    fn cost(&self, _request: &Req) -> Cost {
+        Cost::ONE
+    }
+}
+
+/// What to do with requests that are over the limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnDeny {
+    /// Fail the request with the [RateLimiterError] of the check.
+    #[default]
+    Error,
+    /// Hold on to the request until the limit allows it. Requests that can never be allowed
+    /// still fail with [GcraError::DeniedIndefinitely].
+    Wait,
+}
+
+/// What the http, tonic and actix middlewares do with requests their key extractor found no key
+/// for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingKey {
+    /// Reject the request, so clients can't get around the limit by leaving the key out.
+    #[default]
+    Reject,
+    /// Pass the request on without limiting it.
+    Allow,
+}
+
+#[derive(Debug, Clone)]
+enum Limit {
+    RateLimit(RateLimit),
+    Policy(String),
+}
+
+/// A [RateLimiter] along with the limit requests are checked against.
+pub(crate) struct Limited<Key: Eq + Hash, C, St, O> {
+    limiter: Arc<RateLimiter<Key, C, St, O>>,
+    limit: Limit,
+}
+
+impl<Key, C, St, O> Clone for Limited<Key, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    fn clone(&self) -> Self {
+        Self {
+            limiter: self.limiter.clone(),
+            limit: self.limit.clone(),
+        }
+    }
+}
+
+impl<Key, C, St, O> Limited<Key, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    pub(crate) fn rate_limit(
+        limiter: Arc<RateLimiter<Key, C, St, O>>,
+        rate_limit: RateLimit,
+    ) -> Self {
+        Self {
+            limiter,
+            limit: Limit::RateLimit(rate_limit),
+        }
+    }
+
+    pub(crate) fn policy(limiter: Arc<RateLimiter<Key, C, St, O>>, policy: String) -> Self {
+        Self {
+            limiter,
+            limit: Limit::Policy(policy),
+        }
+    }
+
+    /// Checks against the registered `policy` of the same rate limiter instead.
+    #[cfg(feature = "actix")]
+    pub(crate) fn with_policy(&self, policy: String) -> Self {
+        Self::policy(self.limiter.clone(), policy)
+    }
+}
+
+impl<Key, C, St, O> Limited<Key, C, St, O>
+where
+    Key: Send + Clone + Hash + Eq + Display + 'static,
+    C: Clock,
+    St: GcraStore<Key>,
+    O: Observer<Key>,
+{
+    pub(crate) fn now(&self) -> Instant {
+        self.limiter.clock().now()
+    }
+
+    pub(crate) fn decide(
+        &self,
+        key: Key,
+        cost: Cost,
+        now: Instant,
+    ) -> Result<Decision, RateLimiterError> {
+        match &self.limit {
+            Limit::RateLimit(rate_limit) => self.limiter.decide_sync_at(key, rate_limit, cost, now),
+            Limit::Policy(policy) => self.limiter.decide_policy_sync_at(key, policy, cost, now),
+        }
+    }
+}
+
+struct Config<Key, KF, CF, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    limited: Limited<Key, C, St, O>,
+    key: KF,
+    cost: CF,
+    on_deny: OnDeny,
+}
+
+/// The error a denied [Decision] made at `now` stands for.
+pub(crate) fn denied(decision: &Decision, cost: Cost, now: Instant) -> RateLimiterError {
+    let e = match decision.retry_after {
+        Some(retry_after) => GcraError::DeniedUntil {
+            next_allowed_at: now + retry_after,
+        },
+        None => GcraError::denied_indefinitely(cost, decision.rate_limit.clone()),
+    };
+    e.into()
+}
+
+/// Applies [GcraService] to services.
+pub struct GcraLayer<
+    Key: Eq + Hash,
+    KF,
+    CF = UnitCost,
+    C = InstantClock,
+    St = DashMapStore<Key>,
+    O = NoopObserver,
+> {
+    limited: Limited<Key, C, St, O>,
+    key: KF,
+    cost: CF,
+    on_deny: OnDeny,
+}
+
+impl<Key, KF, CF, C, St, O> Clone for GcraLayer<Key, KF, CF, C, St, O>
+where
+    Key: Eq + Hash,
+    KF: Clone,
+    CF: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            limited: self.limited.clone(),
+            key: self.key.clone(),
+            cost: self.cost.clone(),
+            on_deny: self.on_deny,
+        }
+    }
+}
+
+impl<Key, KF, C, St, O> GcraLayer<Key, KF, UnitCost, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    /// Limits requests by the key extracted by `key`, charging each a cost of 1.
+    pub fn new(limiter: Arc<RateLimiter<Key, C, St, O>>, rate_limit: RateLimit, key: KF) -> Self {
+        Self::with_limited(Limited::rate_limit(limiter, rate_limit), key)
+    }
+
+    /// Limits requests by the key extracted by `key` against the registered `policy`, charging
+    /// each a cost of 1. Requests fail with [RateLimiterError::UnknownPolicy] while the policy isn't
+    /// registered.
+    pub fn with_policy(
+        limiter: Arc<RateLimiter<Key, C, St, O>>,
+        policy: impl Into<String>,
+        key: KF,
+    ) -> Self {
+        Self::with_limited(Limited::policy(limiter, policy.into()), key)
+    }
+
+    fn with_limited(limited: Limited<Key, C, St, O>, key: KF) -> Self {
+        Self {
+            limited,
+            key,
+            cost: UnitCost,
+            on_deny: OnDeny::default(),
+        }
+    }
+}
+
+impl<Key, KF, CF, C, St, O> GcraLayer<Key, KF, CF, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    /// Charges requests the cost returned by `cost`.
+    pub fn with_cost<CF2>(self, cost: CF2) -> GcraLayer<Key, KF, CF2, C, St, O> {
+        GcraLayer {
+            limited: self.limited,
+            key: self.key,
+            cost,
+            on_deny: self.on_deny,
+        }
+    }
+
+    pub fn with_on_deny(mut self, on_deny: OnDeny) -> Self {
+        self.on_deny = on_deny;
+        self
+    }
+}
+
+impl<S, Key, KF, CF, C, St, O> Layer<S> for GcraLayer<Key, KF, CF, C, St, O>
+where
+    Key: Eq + Hash,
+    KF: Clone,
+    CF: Clone,
+{
+    type Service = GcraService<S, Key, KF, CF, C, St, O>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GcraService {
+            inner,
+            config: Arc::new(Config {
+                limited: self.limited.clone(),
+                key: self.key.clone(),
+                cost: self.cost.clone(),
+                on_deny: self.on_deny,
+            }),
+        }
+    }
+}
+
+/// Rate limits requests before passing them on to the wrapped service, see [GcraLayer].
+///
+/// Errors are boxed: either a [RateLimiterError] from the check, or the wrapped service's own.
+pub struct GcraService<
+    S,
+    Key: Eq + Hash,
+    KF,
+    CF = UnitCost,
+    C = InstantClock,
+    St = DashMapStore<Key>,
+    O = NoopObserver,
+> {
+    inner: S,
+    config: Arc<Config<Key, KF, CF, C, St, O>>,
+}
+
+impl<S, Key, KF, CF, C, St, O> Clone for GcraService<S, Key, KF, CF, C, St, O>
+where
+    S: Clone,
+    Key: Eq + Hash,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+impl<S, Key, KF, CF, C, St, O> GcraService<S, Key, KF, CF, C, St, O>
+where
+    Key: Eq + Hash,
+{
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, Req, Key, KF, CF, C, St, O> Service<Req> for GcraService<S, Key, KF, CF, C, St, O>
+where
+    S: Service<Req> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+    Req: Send + 'static,
+    Key: Send + Sync + Clone + Hash + Eq + Display + 'static,
+    KF: KeyExtractor<Req, Key = Key> + Send + Sync + 'static,
+    CF: RequestCost<Req> + Send + Sync + 'static,
+    C: Clock + Send + Sync + 'static,
+    St: GcraStore<Key> + Send + Sync + 'static,
+    O: Observer<Key> + Send + Sync + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        let key = self.config.key.extract(&request);
+        let cost = self.config.cost.cost(&request);
+        let now = self.config.limited.now();
+        let retry_after = match self.config.limited.decide(key.clone(), cost, now) {
+            Ok(decision) if decision.allowed => {
+                let response = self.inner.call(request);
+                return Box::pin(async move { response.await.map_err(Into::into) });
+            }
+            Ok(decision) => match (self.config.on_deny, decision.retry_after) {
+                (OnDeny::Wait, Some(retry_after)) => retry_after,
+                _ => {
+                    let e = denied(&decision, cost, now);
+                    return Box::pin(async move { Err(e.into()) });
+                }
+            },
+            Err(e) => return Box::pin(async move { Err(e.into()) }),
+        };
+
+        // Take the service that was driven to readiness, leaving a fresh clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let config = self.config.clone();
+        Box::pin(async move {
+            wait_until_allowed(&config, key, cost, retry_after).await?;
+            inner.call(request).await.map_err(Into::into)
+        })
+    }
+}
+
+async fn wait_until_allowed<Key, KF, CF, C, St, O>(
+    config: &Config<Key, KF, CF, C, St, O>,
+    key: Key,
+    cost: Cost,
+    mut retry_after: Duration,
+) -> Result<(), RateLimiterError>
+where
+    Key: Send + Clone + Hash + Eq + Display + 'static,
+    C: Clock,
+    St: GcraStore<Key>,
+    O: Observer<Key>,
+{
+    loop {
+        tokio::time::sleep(retry_after).await;
+        let now = config.limited.now();
+        let decision = config.limited.decide(key.clone(), cost, now)?;
+        if decision.allowed {
+            return Ok(());
+        }
+        // Someone else took the slot, wait for the next one
+        retry_after = decision
+            .retry_after
+            .ok_or_else(|| denied(&decision, cost, now))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+    use tower_test::mock;
+
+    use crate::clock::TokioClock;
+
+    use super::*;
+
+    type Request = (&'static str, u32);
+
+    type KeyFn = fn(&Request) -> String;
+    type CostFn = fn(&Request) -> u32;
+
+    fn layer() -> GcraLayer<String, KeyFn, CostFn, TokioClock> {
+        let limiter = Arc::new(RateLimiter::with_clock(TokioClock));
+        GcraLayer::new(
+            limiter,
+            RateLimit::per_sec(2),
+            (|(key, _)| key.to_string()) as KeyFn,
+        )
+        .with_cost((|(_, cost)| *cost) as CostFn)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn denies_with_error() {
+        let (service, mut handle) = mock::pair::<Request, &'static str>();
+        let mut service = layer().layer(service);
+
+        tokio::spawn(async move {
+            while let Some((request, response)) = handle.next_request().await {
+                response.send_response(request.0);
+            }
+        });
+
+        let response = service.ready().await.unwrap().call(("a", 2)).await;
+        assert_eq!("a", response.unwrap());
+
+        let error = service.ready().await.unwrap().call(("a", 1)).await;
+        assert!(matches!(
+            error.unwrap_err().downcast_ref(),
+            Some(RateLimiterError::Gcra(GcraError::DeniedUntil { .. }))
+        ));
+        let error = service.ready().await.unwrap().call(("b", 3)).await;
+        assert!(matches!(
+            error.unwrap_err().downcast_ref(),
+            Some(RateLimiterError::Gcra(GcraError::DeniedIndefinitely { .. }))
+        ));
+
+        let response = service.ready().await.unwrap().call(("b", 1)).await;
+        assert_eq!("b", response.unwrap(), "Keys are limited separately");
+        tokio::time::advance(Duration::from_millis(500)).await;
+        let response = service.ready().await.unwrap().call(("a", 1)).await;
+        assert_eq!("a", response.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_until_allowed() {
+        let (service, mut handle) = mock::pair::<Request, &'static str>();
+        let service = layer().with_on_deny(OnDeny::Wait).layer(service);
+
+        tokio::spawn(async move {
+            while let Some((request, response)) = handle.next_request().await {
+                response.send_response(request.0);
+            }
+        });
+
+        let start = tokio::time::Instant::now();
+        let responses =
+            futures::future::join_all((0..4).map(|_| service.clone().oneshot(("a", 1)))).await;
+        assert!(responses.iter().all(|response| response.is_ok()));
+        assert_eq!(Duration::from_secs(1), start.elapsed());
+
+        let error = service.oneshot(("a", 3)).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(RateLimiterError::Gcra(GcraError::DeniedIndefinitely { .. }))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn poll_ready_follows_inner_service() {
+        let (mut service, mut handle) = mock::spawn_layer(layer());
+
+        handle.allow(0);
+        assert!(service.poll_ready().is_pending());
+
+        handle.allow(1);
+        assert!(matches!(service.poll_ready(), Poll::Ready(Ok(()))));
+        let error = service.call(("a", 3)).await.unwrap_err();
+        assert!(error.is::<RateLimiterError>());
+
+        // The denied request didn't use up the readiness reserved for it
+        let response = service.call(("a", 1));
+        let (request, send_response) = handle.next_request().await.unwrap();
+        assert_eq!(("a", 1), request);
+        send_response.send_response("a");
+        assert_eq!("a", response.await.unwrap());
+
+        assert!(service.poll_ready().is_pending());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unknown_policy() {
+        let (service, _handle) = mock::pair::<Request, &'static str>();
+        let limiter: Arc<RateLimiter<String, _>> = Arc::new(RateLimiter::with_clock(TokioClock));
+        let mut service =
+            GcraLayer::with_policy(limiter, "api", |(key, _): &Request| key.to_string())
+                .layer(service);
+
+        let error = service.ready().await.unwrap().call(("a", 1)).await;
+        assert!(matches!(
+            error.unwrap_err().downcast_ref(),
+            Some(RateLimiterError::UnknownPolicy { .. })
+        ));
+    }
+}